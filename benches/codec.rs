@@ -0,0 +1,80 @@
+//! How fast clipboard objects are encoded and decoded, in bytes of contents per second.
+
+use std::borrow::Cow;
+
+use arboard::ImageData;
+use clipshare::clipboard::{ClipboardObject, File};
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use tokio::runtime::{Builder, Runtime};
+
+/// Larger than any of the payloads, so none of them is refused.
+const MAX_SIZE: u64 = 64 * 1024 * 1024;
+
+fn payloads() -> Vec<(&'static str, ClipboardObject)> {
+    let image = |width: usize, height: usize| {
+        ClipboardObject::Image(ImageData {
+            width,
+            height,
+            bytes: Cow::Owned((0..width * height * 4).map(|i| i as u8).collect()),
+        })
+    };
+    vec![
+        ("text 64 B", ClipboardObject::Text("x".repeat(64))),
+        ("text 1 MiB", ClipboardObject::Text("x".repeat(1 << 20))),
+        ("image 256x256", image(256, 256)),
+        ("image 1920x1080", image(1920, 1080)),
+        (
+            "files 4x1 MiB",
+            ClipboardObject::Files(
+                (0..4)
+                    .map(|i| File {
+                        name: format!("file-{i}.bin"),
+                        contents: vec![i; 1 << 20],
+                    })
+                    .collect(),
+            ),
+        ),
+    ]
+}
+
+fn runtime() -> Runtime {
+    Builder::new_current_thread().build().unwrap()
+}
+
+fn write(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("write");
+    for (name, obj) in payloads() {
+        group.throughput(Throughput::Bytes(obj.size() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &obj, |b, obj| {
+            b.to_async(&rt).iter(|| async {
+                let mut buf = Vec::with_capacity(obj.size() + 64);
+                obj.write(&mut buf).await.unwrap();
+                buf
+            })
+        });
+    }
+    group.finish();
+}
+
+fn read(c: &mut Criterion) {
+    let rt = runtime();
+    let mut group = c.benchmark_group("read");
+    for (name, obj) in payloads() {
+        let mut encoded = Vec::new();
+        rt.block_on(obj.write(&mut encoded)).unwrap();
+        group.throughput(Throughput::Bytes(obj.size() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(name), &encoded, |b, encoded| {
+            b.to_async(&rt).iter(|| async {
+                ClipboardObject::from_reader(&encoded[..], MAX_SIZE, false)
+                    .await
+                    .unwrap()
+                    .unwrap()
+            })
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, write, read);
+criterion_main!(benches);
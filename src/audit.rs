@@ -0,0 +1,81 @@
+//! A record of every clipboard object sent, received or declined, one JSON object per line
+//! appended to the file given with `--audit-log`.
+//!
+//! Every line has the `time` in RFC 3339, the `direction`, the `ip` and `peer` name of the other
+//! end, and the `kind` and `size` of the object. Its `content` is only there with
+//! `--audit-content`, as a one line preview. Like the events, fields are only ever added.
+
+use std::{
+    fs::{File, OpenOptions},
+    io::{self, Write},
+    net::IpAddr,
+    path::Path,
+    sync::{Mutex, PoisonError},
+};
+
+use clipshare::clipboard::ClipboardKind;
+use serde::Serialize;
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+use tracing::warn;
+
+static LOG: Mutex<Option<File>> = Mutex::new(None);
+
+#[derive(Debug, Serialize)]
+struct Entry<'a> {
+    time: String,
+    direction: &'a str,
+    ip: IpAddr,
+    peer: &'a str,
+    kind: ClipboardKind,
+    size: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    content: Option<&'a str>,
+}
+
+/// Appends the entries to `path` from then on, creating it if needed. It is only readable by
+/// the current user, as it tells what was copied and when.
+pub fn open(path: &Path) -> io::Result<()> {
+    let mut options = OpenOptions::new();
+    options.create(true).append(true);
+    #[cfg(unix)]
+    std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+    *LOG.lock().unwrap_or_else(PoisonError::into_inner) = Some(options.open(path)?);
+    Ok(())
+}
+
+/// Appends an entry for an object `direction` of `sent`, `received` or `declined`, when
+/// `--audit-log` is set.
+pub fn record(
+    direction: &str,
+    ip: IpAddr,
+    peer: &str,
+    kind: ClipboardKind,
+    size: usize,
+    content: Option<&str>,
+) {
+    let mut log = LOG.lock().unwrap_or_else(PoisonError::into_inner);
+    let Some(file) = log.as_mut() else {
+        return;
+    };
+    let entry = Entry {
+        time: OffsetDateTime::now_utc()
+            .format(&Rfc3339)
+            .unwrap_or_default(),
+        direction,
+        ip,
+        peer,
+        kind,
+        size,
+        content,
+    };
+    let line = match serde_json::to_string(&entry) {
+        Ok(line) => line,
+        Err(err) => {
+            warn!(error = %err, "Failed to serialize audit log entry");
+            return;
+        }
+    };
+    if let Err(err) = writeln!(file, "{line}") {
+        warn!(error = %err, "Failed to write to the audit log");
+    }
+}
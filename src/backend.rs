@@ -0,0 +1,554 @@
+//! Access to the clipboard the objects are read from and written to.
+
+use std::{
+    error::Error,
+    fmt,
+    io::{self, IsTerminal, Read, Write},
+    path::PathBuf,
+};
+
+use arboard::ImageData;
+use clipboard_master::{CallbackResult, ClipboardHandler, Master};
+use tracing::warn;
+
+/// A clipboard [`Clipboard`](crate::clipboard::Clipboard) reads and writes: the platform one, or
+/// anything else that stores text, images and file lists.
+///
+/// The `paste_*` methods return `None` when the clipboard doesn't currently hold that type.
+/// Errors are taken as transient and retried, except for [`Unavailable`]. [`OwnershipLost`] is
+/// an expected one, only logged at debug level.
+pub trait ClipboardBackend: Send + 'static {
+    fn paste_text(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>>;
+    fn paste_image(&mut self) -> Result<Option<ImageData<'static>>, Box<dyn Error + Send + Sync>>;
+    fn paste_files(&mut self) -> Result<Option<Vec<PathBuf>>, Box<dyn Error + Send + Sync>>;
+    fn copy_text(&mut self, text: &str) -> Result<(), Box<dyn Error + Send + Sync>>;
+    fn copy_image(&mut self, img: ImageData<'static>) -> Result<(), Box<dyn Error + Send + Sync>>;
+    fn copy_files(&mut self, paths: &[PathBuf]) -> Result<(), Box<dyn Error + Send + Sync>>;
+    /// Empties the clipboard, whatever it holds.
+    fn clear(&mut self) -> Result<(), Box<dyn Error + Send + Sync>>;
+    /// Whether the application that copied what the clipboard holds marked it as sensitive,
+    /// like a password manager does.
+    fn is_sensitive(&mut self) -> Result<bool, Box<dyn Error + Send + Sync>>;
+    /// The text held by the primary selection, `None` where there is no such thing.
+    fn paste_primary(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>>;
+    /// Sets the primary selection, doing nothing where there is no such thing.
+    fn copy_primary(&mut self, text: &str) -> Result<(), Box<dyn Error + Send + Sync>>;
+    /// The HTML the clipboard holds along with its text, like what is copied from a browser.
+    /// None by default.
+    fn paste_html(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        Ok(None)
+    }
+    /// Sets `html` with `text` as the alternative for applications that can't take HTML. Only
+    /// the text by default.
+    fn copy_html(&mut self, html: &str, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let _ = html;
+        self.copy_text(text)
+    }
+}
+
+/// A clipboard that can't be used at all, as opposed to one that failed this time.
+#[derive(Debug)]
+pub struct Unavailable(pub String);
+
+impl fmt::Display for Unavailable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "The clipboard is unavailable: {}", self.0)
+    }
+}
+
+impl Error for Unavailable {}
+
+/// Another application took the clipboard over while it was read or written, which happens all
+/// the time on X11. Reading it again, or writing it again to take it back, is all it takes.
+#[derive(Debug)]
+pub struct OwnershipLost(pub String);
+
+impl fmt::Display for OwnershipLost {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Another application took the clipboard over: {}", self.0)
+    }
+}
+
+impl Error for OwnershipLost {}
+
+/// The platform clipboard (X11, Wayland, Windows or macOS).
+impl ClipboardBackend for arboard::Clipboard {
+    fn paste_text(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        pasted(self.get_text())
+    }
+
+    fn paste_image(&mut self) -> Result<Option<ImageData<'static>>, Box<dyn Error + Send + Sync>> {
+        pasted(self.get_image())
+    }
+
+    fn paste_files(&mut self) -> Result<Option<Vec<PathBuf>>, Box<dyn Error + Send + Sync>> {
+        match self.get().file_list() {
+            // Not every platform has file lists, that shouldn't stop text and images
+            Err(arboard::Error::ClipboardNotSupported) => Ok(None),
+            result => pasted(result),
+        }
+    }
+
+    fn copy_text(&mut self, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        copied(self.set_text(text))
+    }
+
+    fn copy_image(&mut self, img: ImageData<'static>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        copied(self.set_image(img))
+    }
+
+    fn copy_files(&mut self, paths: &[PathBuf]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        copied(self.set().file_list(paths))
+    }
+
+    fn clear(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        copied(arboard::Clipboard::clear(self))
+    }
+
+    fn is_sensitive(&mut self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        sensitive()
+    }
+
+    fn paste_primary(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        paste_primary(self)
+    }
+
+    fn copy_primary(&mut self, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        copy_primary(self, text)
+    }
+
+    fn paste_html(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        match self.get().html() {
+            Err(arboard::Error::ClipboardNotSupported) => Ok(None),
+            result => pasted(result),
+        }
+    }
+
+    fn copy_html(&mut self, html: &str, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        copied(self.set().html(html, Some(text)))
+    }
+}
+
+/// A clipboard holding another type, or something that doesn't convert to the one asked for,
+/// just doesn't hold that type.
+fn pasted<T>(result: Result<T, arboard::Error>) -> Result<Option<T>, Box<dyn Error + Send + Sync>> {
+    match result {
+        Ok(val) => Ok(Some(val)),
+        Err(arboard::Error::ContentNotAvailable | arboard::Error::ConversionFailure) => Ok(None),
+        Err(err) => Err(classify(err)),
+    }
+}
+
+fn copied(result: Result<(), arboard::Error>) -> Result<(), Box<dyn Error + Send + Sync>> {
+    result.map_err(classify)
+}
+
+fn classify(err: arboard::Error) -> Box<dyn Error + Send + Sync> {
+    match err {
+        arboard::Error::ClipboardNotSupported => Box::new(Unavailable(err.to_string())),
+        arboard::Error::ClipboardOccupied => Box::new(OwnershipLost(err.to_string())),
+        err => err.into(),
+    }
+}
+
+/// Any backend, picked at runtime.
+impl ClipboardBackend for Box<dyn ClipboardBackend> {
+    fn paste_text(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        (**self).paste_text()
+    }
+
+    fn paste_image(&mut self) -> Result<Option<ImageData<'static>>, Box<dyn Error + Send + Sync>> {
+        (**self).paste_image()
+    }
+
+    fn paste_files(&mut self) -> Result<Option<Vec<PathBuf>>, Box<dyn Error + Send + Sync>> {
+        (**self).paste_files()
+    }
+
+    fn copy_text(&mut self, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        (**self).copy_text(text)
+    }
+
+    fn copy_image(&mut self, img: ImageData<'static>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        (**self).copy_image(img)
+    }
+
+    fn copy_files(&mut self, paths: &[PathBuf]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        (**self).copy_files(paths)
+    }
+
+    fn clear(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        (**self).clear()
+    }
+
+    fn is_sensitive(&mut self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        (**self).is_sensitive()
+    }
+
+    fn paste_primary(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        (**self).paste_primary()
+    }
+
+    fn copy_primary(&mut self, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        (**self).copy_primary(text)
+    }
+
+    fn paste_html(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        (**self).paste_html()
+    }
+
+    fn copy_html(&mut self, html: &str, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        (**self).copy_html(html, text)
+    }
+}
+
+/// The platform clipboard, emptied first so whatever was copied before isn't synced on start.
+pub fn cleared() -> Result<arboard::Clipboard, arboard::Error> {
+    let mut clipboard = arboard::Clipboard::new()?;
+    clipboard.set_image(ImageData {
+        width: 1,
+        height: 1,
+        bytes: vec![0, 0, 0, 0].into(),
+    })?;
+    clipboard.set_text("")?;
+    Ok(clipboard)
+}
+
+/// Target offered next to a copied password by KeePassXC, KWallet and other password managers
+/// on Linux, see the KDE clipboard documentation.
+#[cfg(all(unix, not(target_os = "macos")))]
+const PASSWORD_HINT: &str = "x-kde-passwordManagerHint";
+
+/// The primary selection of X11 and Wayland. A compositor without one just never holds anything
+/// there.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn paste_primary(
+    clipboard: &mut arboard::Clipboard,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    use arboard::{GetExtLinux, LinuxClipboardKind};
+
+    match clipboard
+        .get()
+        .clipboard(LinuxClipboardKind::Primary)
+        .text()
+    {
+        Err(arboard::Error::ClipboardNotSupported) => Ok(None),
+        result => pasted(result),
+    }
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn paste_primary(
+    _clipboard: &mut arboard::Clipboard,
+) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+    Ok(None)
+}
+
+#[cfg(all(unix, not(target_os = "macos")))]
+fn copy_primary(
+    clipboard: &mut arboard::Clipboard,
+    text: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    use arboard::{LinuxClipboardKind, SetExtLinux};
+
+    match clipboard
+        .set()
+        .clipboard(LinuxClipboardKind::Primary)
+        .text(text)
+    {
+        Err(arboard::Error::ClipboardNotSupported) => Ok(()),
+        result => copied(result),
+    }
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn copy_primary(
+    _clipboard: &mut arboard::Clipboard,
+    _text: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    Ok(())
+}
+
+/// Whether what the platform clipboard holds was copied by a password manager, going by the
+/// hint it offers along with it. Only X11 and Wayland have one, elsewhere nothing is taken as
+/// sensitive.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn sensitive() -> Result<bool, Box<dyn Error + Send + Sync>> {
+    use wl_clipboard_rs::paste::{self, ClipboardType, Seat};
+
+    if std::env::var_os("WAYLAND_DISPLAY").is_none() {
+        return x11_offers(PASSWORD_HINT);
+    }
+    match paste::get_mime_types(ClipboardType::Regular, Seat::Unspecified) {
+        Ok(types) => Ok(types.contains(PASSWORD_HINT)),
+        Err(paste::Error::ClipboardEmpty | paste::Error::NoSeats) => Ok(false),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(not(all(unix, not(target_os = "macos"))))]
+fn sensitive() -> Result<bool, Box<dyn Error + Send + Sync>> {
+    Ok(false)
+}
+
+/// Whether the owner of the X11 clipboard offers it as `target`, asking it for the list of
+/// targets through a window of our own.
+#[cfg(all(unix, not(target_os = "macos")))]
+fn x11_offers(target: &str) -> Result<bool, Box<dyn Error + Send + Sync>> {
+    use std::time::{Duration, Instant};
+
+    use x11rb::{
+        connection::Connection,
+        protocol::{
+            xproto::{AtomEnum, ConnectionExt, CreateWindowAux, WindowClass},
+            Event,
+        },
+        COPY_DEPTH_FROM_PARENT, COPY_FROM_PARENT, CURRENT_TIME, NONE,
+    };
+
+    // An owner that doesn't answer in time just isn't a password manager we know of
+    const ANSWER_TIMEOUT: Duration = Duration::from_millis(200);
+
+    let (conn, screen) = x11rb::connect(None)?;
+    // Never interned means nobody ever offered it
+    let hint = conn.intern_atom(true, target.as_bytes())?.reply()?.atom;
+    if hint == NONE {
+        return Ok(false);
+    }
+    let clipboard = conn.intern_atom(false, b"CLIPBOARD")?.reply()?.atom;
+    let targets = conn.intern_atom(false, b"TARGETS")?.reply()?.atom;
+    let property = conn.intern_atom(false, b"CLIPSHARE_TARGETS")?.reply()?.atom;
+
+    let window = conn.generate_id()?;
+    conn.create_window(
+        COPY_DEPTH_FROM_PARENT,
+        window,
+        conn.setup().roots[screen].root,
+        0,
+        0,
+        1,
+        1,
+        0,
+        WindowClass::INPUT_ONLY,
+        COPY_FROM_PARENT,
+        &CreateWindowAux::new(),
+    )?;
+    conn.convert_selection(window, clipboard, targets, property, CURRENT_TIME)?;
+    conn.flush()?;
+
+    let deadline = Instant::now() + ANSWER_TIMEOUT;
+    loop {
+        match conn.poll_for_event()? {
+            Some(Event::SelectionNotify(event)) if event.requestor == window => {
+                if event.property == NONE {
+                    return Ok(false);
+                }
+                let offered = conn
+                    .get_property(true, window, property, AtomEnum::ATOM, 0, u32::MAX / 4)?
+                    .reply()?;
+                return Ok(offered
+                    .value32()
+                    .is_some_and(|mut atoms| atoms.any(|atom| atom == hint)));
+            }
+            Some(_) => {}
+            None if Instant::now() >= deadline => return Ok(false),
+            None => std::thread::sleep(Duration::from_millis(5)),
+        }
+    }
+}
+
+/// Calls `on_change` every time the platform clipboard changes, blocking for as long as it can
+/// listen. Fails right away where there are no change events to listen to, like on Wayland.
+pub fn listen(on_change: impl FnMut()) -> io::Result<()> {
+    struct Handler<F>(F);
+
+    impl<F: FnMut()> ClipboardHandler for Handler<F> {
+        fn on_clipboard_change(&mut self) -> CallbackResult {
+            (self.0)();
+            CallbackResult::Next
+        }
+    }
+
+    // Listening through XWayland would miss what native Wayland applications copy
+    #[cfg(all(unix, not(target_os = "macos")))]
+    if std::env::var_os("WAYLAND_DISPLAY").is_some() {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "no clipboard change events on Wayland",
+        ));
+    }
+
+    Master::new(Handler(on_change))?.run()
+}
+
+/// A clipboard holding whatever was piped on stdin, and writing what is copied to stdout.
+///
+/// Stdin is read to the end when it is created, unless it is a terminal. Received images can't
+/// be written out and are skipped, files are written as their paths, one per line.
+#[derive(Debug, Clone, Default)]
+pub struct StdioClipboard {
+    input: Option<String>,
+}
+
+impl StdioClipboard {
+    pub fn new() -> io::Result<Self> {
+        let mut stdin = io::stdin();
+        if stdin.is_terminal() {
+            return Ok(Self::default());
+        }
+        let mut input = String::new();
+        stdin.read_to_string(&mut input)?;
+        Ok(Self {
+            input: Some(input).filter(|input| !input.is_empty()),
+        })
+    }
+}
+
+impl ClipboardBackend for StdioClipboard {
+    fn paste_text(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        Ok(self.input.clone())
+    }
+
+    fn paste_image(&mut self) -> Result<Option<ImageData<'static>>, Box<dyn Error + Send + Sync>> {
+        Ok(None)
+    }
+
+    fn paste_files(&mut self) -> Result<Option<Vec<PathBuf>>, Box<dyn Error + Send + Sync>> {
+        Ok(None)
+    }
+
+    fn copy_text(&mut self, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut stdout = io::stdout().lock();
+        stdout.write_all(text.as_bytes())?;
+        stdout.flush()?;
+        Ok(())
+    }
+
+    fn copy_image(&mut self, img: ImageData<'static>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        warn!(
+            width = img.width,
+            height = img.height,
+            "Can't write a received image to stdout, skipping it"
+        );
+        Ok(())
+    }
+
+    fn copy_files(&mut self, paths: &[PathBuf]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let mut stdout = io::stdout().lock();
+        for path in paths {
+            writeln!(stdout, "{}", path.display())?;
+        }
+        stdout.flush()?;
+        Ok(())
+    }
+
+    /// Nothing to take back from stdout.
+    fn clear(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+
+    /// Whatever is piped in is meant to be sent.
+    fn is_sensitive(&mut self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(false)
+    }
+
+    /// Nothing to select in a pipe.
+    fn paste_primary(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        Ok(None)
+    }
+
+    fn copy_primary(&mut self, _text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        Ok(())
+    }
+}
+
+/// A clipboard kept in memory, holding a single object at a time like the platform ones do,
+/// along with a primary selection of its own.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryClipboard {
+    contents: Option<Contents>,
+    primary: Option<String>,
+}
+
+#[derive(Debug, Clone)]
+enum Contents {
+    Text(String),
+    /// HTML and its text alternative.
+    Html(String, String),
+    Image(ImageData<'static>),
+    Files(Vec<PathBuf>),
+}
+
+impl MemoryClipboard {
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+impl ClipboardBackend for MemoryClipboard {
+    fn paste_text(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        match self.contents {
+            Some(Contents::Text(ref text) | Contents::Html(_, ref text)) => Ok(Some(text.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    fn paste_image(&mut self) -> Result<Option<ImageData<'static>>, Box<dyn Error + Send + Sync>> {
+        match self.contents {
+            Some(Contents::Image(ref img)) => Ok(Some(img.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    fn paste_files(&mut self) -> Result<Option<Vec<PathBuf>>, Box<dyn Error + Send + Sync>> {
+        match self.contents {
+            Some(Contents::Files(ref paths)) => Ok(Some(paths.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    fn copy_text(&mut self, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.contents = Some(Contents::Text(text.to_string()));
+        Ok(())
+    }
+
+    fn copy_image(&mut self, img: ImageData<'static>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.contents = Some(Contents::Image(img));
+        Ok(())
+    }
+
+    fn copy_files(&mut self, paths: &[PathBuf]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.contents = Some(Contents::Files(paths.to_vec()));
+        Ok(())
+    }
+
+    fn clear(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.contents = None;
+        Ok(())
+    }
+
+    fn is_sensitive(&mut self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(false)
+    }
+
+    fn paste_primary(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        Ok(self.primary.clone())
+    }
+
+    fn copy_primary(&mut self, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.primary = Some(text.to_string());
+        Ok(())
+    }
+
+    fn paste_html(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        match self.contents {
+            Some(Contents::Html(ref html, _)) => Ok(Some(html.clone())),
+            _ => Ok(None),
+        }
+    }
+
+    fn copy_html(&mut self, html: &str, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.contents = Some(Contents::Html(html.to_string(), text.to_string()));
+        Ok(())
+    }
+}
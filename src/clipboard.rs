@@ -5,186 +5,1219 @@ use std::{
     fmt,
     hash::{Hash, Hasher},
     mem,
-    sync::atomic::{AtomicU64, Ordering},
-    time::Duration,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
 };
 
 use arboard::ImageData;
+use serde::{Deserialize, Serialize};
 use tokio::{
     io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    sync::Mutex,
-    time::sleep,
+    sync::{broadcast, Mutex, Notify},
+    task,
+    time::{sleep, timeout},
 };
-use tracing::trace;
+use tracing::{debug, info, trace, warn};
 
-pub struct Clipboard {
-    clipboard: Mutex<arboard::Clipboard>,
+use crate::{
+    backend::{self, ClipboardBackend, OwnershipLost, Unavailable},
+    codec, crypto,
+    error::ClipshareError,
+    history::History,
+    image::{self, ImageFormat, ImageLimit},
+};
+
+/// How often the local clipboard is looked at for changes, by default.
+pub const POLL_INTERVAL: Duration = Duration::from_secs(1);
+/// How long the wait between two looks at a local clipboard that isn't changing grows to, by
+/// default.
+pub const IDLE_POLL_INTERVAL: Duration = Duration::from_secs(5);
+/// Longest wait between two attempts at reading a clipboard that keeps failing.
+const MAX_POLL_BACKOFF: Duration = Duration::from_secs(30);
+/// How long a read of the local clipboard may take, by default, before it is given up on until
+/// the next poll.
+pub const PASTE_TIMEOUT: Duration = Duration::from_secs(2);
+/// Times a received object is written to the clipboard before giving up on it.
+const WRITE_ATTEMPTS: u32 = 3;
+
+/// Where a clipboard change came from.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Origin {
+    /// Copied on this machine.
+    Local,
+    /// Received from the connection with this id.
+    Peer(u64),
+}
+
+/// Which of the selections of the local clipboard are synced. Only X11 and Wayland have a
+/// primary selection, holding the text last selected and pasted with a middle click, elsewhere
+/// there is nothing to sync there.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Selection {
+    /// What is copied with Ctrl-C.
+    #[default]
+    Clipboard,
+    /// What is selected.
+    Primary,
+    /// Both of them, each on its own.
+    Both,
+}
+
+impl Selection {
+    /// Whether objects of `selection` are synced.
+    pub fn includes(self, selection: Selection) -> bool {
+        self == Self::Both || self == selection
+    }
+}
+
+/// A clipboard change, fanned out to every connection except the one it came from.
+#[derive(Debug, Clone)]
+pub struct Update {
+    pub origin: Origin,
+    /// Sequence number of the change, see [`Clipboard::receive`].
+    pub seq: u64,
+    /// The [`Clipboard::node`] the change was made on, wherever it was received from since.
+    pub node: u64,
+    pub obj: Arc<ClipboardObject>,
+    /// Whether the application that copied it marked it as sensitive, like a password manager.
+    pub sensitive: bool,
+}
+
+/// Shared clipboard state: the backend it syncs, what was last synced and the updates fanned out
+/// to every connection.
+pub struct Clipboard<B = arboard::Clipboard> {
+    clipboard: Arc<Mutex<B>>,
     current_text: AtomicU64,
     current_image: AtomicU64,
+    current_files: AtomicU64,
+    current_primary: AtomicU64,
+    /// Digest of the last files received and the hash of the paths they were written to.
+    received_files: std::sync::Mutex<Option<(u64, u64)>>,
+    /// Directory the files on the clipboard were received into, removed once others come in or
+    /// the clipboard is dropped.
+    received_dir: std::sync::Mutex<Option<PathBuf>>,
+    /// Largest total size of copied files that will be synced, `None` when file sync is off.
+    max_file_size: Option<u64>,
+    /// What happens to large copied images before they are synced.
+    image_limit: Option<ImageLimit>,
+    updates: broadcast::Sender<Update>,
+    next_peer: AtomicU64,
+    /// Random id of this end, see [`Clipboard::node`].
+    node: u64,
+    /// How often the clipboard is read when there are no change events, right after a change.
+    poll_interval: Duration,
+    /// How often it is read at most once it stayed the same for a while.
+    idle_poll_interval: Duration,
+    /// How long a read of the clipboard may take while polling it.
+    paste_timeout: Duration,
+    events: Option<Arc<ChangeEvents>>,
+    /// Selections synced, each with its own changes so one never takes over the other.
+    selection: Selection,
+    changes: Changes,
+    primary_changes: Changes,
+    history: Option<std::sync::Mutex<History>>,
+    /// Whether received objects are only passed on to the other connections, see
+    /// [`Clipboard::forwarding`].
+    forwarding: bool,
+}
+
+impl<B> Drop for Clipboard<B> {
+    fn drop(&mut self) {
+        if let Some(dir) = self.received_dir.get_mut().ok().and_then(Option::take) {
+            remove_received(&dir);
+        }
+    }
 }
 
-impl fmt::Debug for Clipboard {
+impl<B> fmt::Debug for Clipboard<B> {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Clipboard")
             .field("current_text", &self.current_text)
             .field("current_image", &self.current_image)
+            .field("current_files", &self.current_files)
+            .field("current_primary", &self.current_primary)
+            .field("node", &self.node)
+            .field("max_file_size", &self.max_file_size)
+            .field("image_limit", &self.image_limit)
+            .field("poll_interval", &self.poll_interval)
+            .field("idle_poll_interval", &self.idle_poll_interval)
+            .field("paste_timeout", &self.paste_timeout)
+            .field("events", &self.events)
+            .field("selection", &self.selection)
+            .field("changes", &self.changes)
+            .field("primary_changes", &self.primary_changes)
+            .field("history", &self.history.is_some())
+            .field("forwarding", &self.forwarding)
             .finish()
     }
 }
 
 impl Clipboard {
-    pub fn new() -> Self {
-        Self::new_with_clipboard(arboard::Clipboard::new().unwrap())
+    /// The platform clipboard, failing where there is none, like on a headless system.
+    pub fn new() -> Result<Self, ClipshareError> {
+        let clipboard =
+            arboard::Clipboard::new().map_err(|err| ClipshareError::Backend(err.into()))?;
+        Ok(Self::with_backend(clipboard))
     }
 
-    pub fn cleared() -> Self {
-        let mut clipboard = arboard::Clipboard::new().unwrap();
-
-        clipboard
-            .set_image(ImageData {
-                width: 1,
-                height: 1,
-                bytes: Cow::from(vec![0, 0, 0, 0]),
-            })
-            .unwrap();
-
-        clipboard.set_text("").unwrap();
-
-        Self::new_with_clipboard(clipboard)
+    /// Like [`Clipboard::new`], emptying the clipboard first so what was copied before isn't
+    /// synced.
+    pub fn cleared() -> Result<Self, ClipshareError> {
+        let clipboard = backend::cleared().map_err(|err| ClipshareError::Backend(err.into()))?;
+        Ok(Self::with_backend(clipboard))
     }
+}
 
-    fn new_with_clipboard(mut clipboard: arboard::Clipboard) -> Self {
-        let current_text = AtomicU64::new(clipboard.get_text().map(hash).unwrap_or_default());
+impl<B: ClipboardBackend> Clipboard<B> {
+    pub fn with_backend(mut clipboard: B) -> Self {
+        // A clipboard failing right now is read again by `watch` anyway
+        let current_text = AtomicU64::new(
+            clipboard
+                .paste_text()
+                .ok()
+                .flatten()
+                .map(hash)
+                .unwrap_or_default(),
+        );
         let current_image = AtomicU64::new(
             clipboard
-                .get_image()
+                .paste_image()
+                .ok()
+                .flatten()
                 .map(|img| hash(img.bytes))
                 .unwrap_or_default(),
         );
+        let current_files = AtomicU64::new(
+            clipboard
+                .paste_files()
+                .ok()
+                .flatten()
+                .map(|paths| hash_paths(&paths))
+                .unwrap_or_default(),
+        );
+        let current_primary = AtomicU64::new(
+            clipboard
+                .paste_primary()
+                .ok()
+                .flatten()
+                .map(hash)
+                .unwrap_or_default(),
+        );
         Self {
-            clipboard: Mutex::new(clipboard),
+            clipboard: Arc::new(Mutex::new(clipboard)),
             current_text,
             current_image,
+            current_files,
+            current_primary,
+            received_files: std::sync::Mutex::new(None),
+            received_dir: std::sync::Mutex::new(None),
+            max_file_size: None,
+            image_limit: None,
+            updates: broadcast::channel(16).0,
+            next_peer: AtomicU64::new(0),
+            node: crypto::random_id(),
+            poll_interval: POLL_INTERVAL,
+            idle_poll_interval: IDLE_POLL_INTERVAL,
+            paste_timeout: PASTE_TIMEOUT,
+            events: None,
+            selection: Selection::Clipboard,
+            changes: Changes::default(),
+            primary_changes: Changes::default(),
+            history: None,
+            forwarding: false,
         }
     }
 
+    /// Enables syncing copied files, as long as they add up to at most `max_size` bytes.
+    pub fn with_files(mut self, max_size: u64) -> Self {
+        self.max_file_size = Some(max_size);
+        self
+    }
+
+    /// Downscales, or leaves out, copied images larger than `limit` before they are synced.
+    pub fn with_image_limit(mut self, limit: ImageLimit) -> Self {
+        self.image_limit = Some(limit);
+        self
+    }
+
+    /// Reads the clipboard for changes every `interval` instead of every [`POLL_INTERVAL`].
+    pub fn with_poll_interval(mut self, interval: Duration) -> Self {
+        self.poll_interval = interval;
+        self
+    }
+
+    /// Lets the wait between two reads of a clipboard that doesn't change grow up to `interval`
+    /// instead of [`IDLE_POLL_INTERVAL`], going back to the poll interval on the next change.
+    pub fn with_idle_poll_interval(mut self, interval: Duration) -> Self {
+        self.idle_poll_interval = interval;
+        self
+    }
+
+    /// Syncs `selection` instead of only the clipboard.
+    pub fn with_selection(mut self, selection: Selection) -> Self {
+        self.selection = selection;
+        self
+    }
+
+    /// Gives up on a read of the clipboard taking longer than `timeout` instead of
+    /// [`PASTE_TIMEOUT`], as an application holding the selection may never answer.
+    pub fn with_paste_timeout(mut self, timeout: Duration) -> Self {
+        self.paste_timeout = timeout;
+        self
+    }
+
+    /// Only reads the clipboard when the platform says it changed, falling back to polling where
+    /// it can't (Wayland, or no display at all). Meant for the platform clipboard backend.
+    pub fn with_change_events(mut self) -> Self {
+        let events = Arc::new(ChangeEvents {
+            changed: Notify::new(),
+            live: AtomicBool::new(true),
+        });
+        let listener = events.clone();
+        let spawned = std::thread::Builder::new()
+            .name("clipboard-events".to_string())
+            .spawn(move || {
+                if let Err(err) = backend::listen(|| listener.changed.notify_one()) {
+                    debug!(error = %err, "No clipboard change events, polling instead");
+                }
+                listener.live.store(false, Ordering::SeqCst);
+                // Wake up a `watch` waiting for an event that will never come
+                listener.changed.notify_one();
+            });
+        match spawned {
+            Ok(_) => self.events = Some(events),
+            Err(err) => warn!(error = %err, "Failed to listen for clipboard changes"),
+        }
+        self
+    }
+
+    /// Passes the objects received on to the other connections without ever writing them to the
+    /// backend, for a relay that has no clipboard of its own. Received files are forwarded as
+    /// they are, whatever [`Self::with_files`] says.
+    pub fn forwarding(mut self) -> Self {
+        self.forwarding = true;
+        self
+    }
+
+    /// Keeps the last `len` distinct objects copied here or received, up to `max_size` bytes.
+    pub fn with_history(mut self, len: usize, max_size: usize) -> Self {
+        self.history = Some(std::sync::Mutex::new(History::new(len, max_size)));
+        self
+    }
+
+    /// Past clipboard objects, the most recent first. Empty when history is disabled.
+    pub fn history(&self) -> Vec<Arc<ClipboardObject>> {
+        match self.history {
+            Some(ref history) => history.lock().unwrap().entries().cloned().collect(),
+            None => Vec::new(),
+        }
+    }
+
+    /// Puts the `index`th history entry back on the clipboard, syncing it to every peer.
+    pub async fn recall(&self, index: usize) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let obj = self
+            .history()
+            .get(index)
+            .cloned()
+            .ok_or_else(|| format!("No history entry {index}"))?;
+        self.copy(Origin::Local, (*obj).clone()).await
+    }
+
+    /// Random id tagging the changes made here, so that one coming back through other peers,
+    /// like around a ring of servers and clients, is recognized and dropped instead of going
+    /// around forever.
+    pub fn node(&self) -> u64 {
+        self.node
+    }
+
+    /// Allocates the origin used by a new connection.
+    pub fn peer(&self) -> Origin {
+        Origin::Peer(self.next_peer.fetch_add(1, Ordering::SeqCst))
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Update> {
+        self.updates.subscribe()
+    }
+
+    /// The last change synced of each selection, made here or received, oldest first.
+    pub fn latest(&self) -> Vec<Update> {
+        let mut latest = [&self.changes, &self.primary_changes]
+            .into_iter()
+            .filter_map(|changes| changes.latest.lock().unwrap().clone())
+            .collect::<Vec<_>>();
+        latest.sort_by_key(|update| update.seq);
+        latest
+    }
+
+    /// What the clipboard holds right now, looking at files first when file sync is on.
+    pub async fn current(&self) -> Result<Option<ClipboardObject>, Box<dyn Error + Send + Sync>> {
+        let mut clip = self.clipboard.lock().await;
+        if let Some(max_size) = self.max_file_size {
+            if let Some(paths) = clip.paste_files()?.filter(|paths| !paths.is_empty()) {
+                drop(clip);
+                let files = read_files(&paths, max_size).await?;
+                return Ok(files.map(ClipboardObject::Files));
+            }
+        }
+        if let Some(text) = clip.paste_text()?.filter(|text| !text.is_empty()) {
+            let html = clip.paste_html()?;
+            return Ok(Some(with_html(text, html)));
+        }
+        Ok(clip
+            .paste_image()?
+            .filter(|img| !img.bytes.is_empty())
+            .map(ClipboardObject::Image))
+    }
+
+    /// Polls the local clipboard forever, publishing every change.
+    pub async fn watch(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        loop {
+            let (obj, sensitive) = self.paste().await?;
+            let seq = self.next_seq(obj.selection());
+            self.publish(Origin::Local, seq, self.node, obj, sensitive);
+        }
+    }
+
+    /// Looks at the local clipboard right away rather than at the next poll, publishing what
+    /// was copied since it was last looked at. Changes made while a connection was down are then
+    /// part of [`Self::latest`] as soon as it is back.
+    pub async fn refresh(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.forwarding {
+            return Ok(());
+        }
+        if let Some((obj, sensitive)) = self.poll().await? {
+            let seq = self.next_seq(obj.selection());
+            self.publish(Origin::Local, seq, self.node, obj, sensitive);
+        }
+        Ok(())
+    }
+
+    /// Applies `obj` as a new change, publishing it to the connections other than `origin` when
+    /// it actually changed the clipboard.
     pub async fn copy(
         &self,
+        origin: Origin,
+        obj: impl Into<ClipboardObject>,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let obj = obj.into();
+        let seq = self.next_seq(obj.selection());
+        self.apply(origin, seq, self.node, obj).await
+    }
+
+    /// Applies an object received from `origin`, unless it is older than the latest change so a
+    /// late update doesn't clobber newer content, or it was made on this [`Self::node`] and came
+    /// back around.
+    ///
+    /// Every change gets a sequence number higher than the previous one, made here or received,
+    /// and at least the current time in milliseconds, so the last change made on any of the
+    /// peers wins, even one that just started.
+    ///
+    /// Something copied here that [`Self::watch`] didn't pick up yet is newer than the object,
+    /// so it is published instead of being overwritten.
+    pub async fn receive(
+        &self,
+        origin: Origin,
+        seq: u64,
+        node: u64,
         obj: impl Into<ClipboardObject>,
     ) -> Result<(), Box<dyn Error + Send + Sync>> {
         let obj = obj.into();
-        let hashed = hash(&obj);
+        let selection = obj.selection();
+        if node == self.node && origin != Origin::Local {
+            debug!(seq, "Ignoring clipboard copied here that came back around");
+            return Ok(());
+        }
+        if !self.forwarding && !self.selection.includes(selection) {
+            debug!(
+                seq,
+                ?selection,
+                "Ignoring clipboard of a selection not synced"
+            );
+            return Ok(());
+        }
 
-        match obj {
-            ClipboardObject::Text(text) => {
-                if self.current_text.load(Ordering::SeqCst) != hashed {
-                    self.clipboard.lock().await.set_text(text)?;
+        let latest = self.changes(selection).seq.fetch_max(seq, Ordering::SeqCst);
+        if seq <= latest {
+            debug!(
+                seq,
+                latest, "Ignoring clipboard older than the latest change"
+            );
+            return Ok(());
+        }
+
+        if !self.forwarding {
+            let polled = match selection {
+                Selection::Primary => self.poll_primary().await,
+                _ => self.poll_clipboard().await,
+            };
+            // A clipboard that can't be read right now is left to `watch`
+            if let Ok(Some((local, sensitive))) = polled {
+                debug!(
+                    seq,
+                    "Keeping the clipboard copied here over the received one"
+                );
+                let local_seq = self.next_seq(selection);
+                self.publish(Origin::Local, local_seq, self.node, local, sensitive);
+                return Ok(());
+            }
+        }
+
+        self.apply(origin, seq, node, obj).await
+    }
+
+    /// Publishes what the clipboard holds right now as a new change, for a backend whose
+    /// contents are already there when it is created.
+    pub async fn publish_current(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if let Some(obj) = self.current().await? {
+            let seq = self.next_seq(Selection::Clipboard);
+            self.publish(Origin::Local, seq, self.node, obj, false);
+        }
+        Ok(())
+    }
+
+    /// Allocates the sequence number of a new change of `selection` made here.
+    pub fn next_seq(&self, selection: Selection) -> u64 {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |now| now.as_millis() as u64);
+        let latest = self
+            .changes(selection)
+            .seq
+            .fetch_update(Ordering::SeqCst, Ordering::SeqCst, |latest| {
+                Some(now.max(latest + 1))
+            })
+            .unwrap_or_else(|latest| latest);
+        now.max(latest + 1)
+    }
+
+    fn changes(&self, selection: Selection) -> &Changes {
+        match selection {
+            Selection::Primary => &self.primary_changes,
+            _ => &self.changes,
+        }
+    }
+
+    /// Writes `obj` to the backend unless it already holds the same content, so a repeated
+    /// object doesn't take over the clipboard and notify other applications again.
+    async fn apply(
+        &self,
+        origin: Origin,
+        seq: u64,
+        node: u64,
+        obj: ClipboardObject,
+    ) -> Result<(), Box<dyn Error + Send + Sync>> {
+        if self.forwarding {
+            self.publish(origin, seq, node, obj, false);
+            return Ok(());
+        }
+
+        let changed = match obj {
+            ClipboardObject::Text(ref text) => {
+                let hashed = hash(text);
+                let changed = self.current_text.load(Ordering::SeqCst) != hashed
+                    && self.write(|clip| clip.copy_text(text)).await?;
+                if changed {
                     self.current_text.store(hashed, Ordering::SeqCst);
                 }
+                changed
             }
-            ClipboardObject::Image(img) => {
-                if self.current_image.load(Ordering::SeqCst) != hashed {
-                    self.clipboard.lock().await.set_image(img)?;
+            ClipboardObject::Html { ref html, ref text } => {
+                // Both are copied together, so the text tells them apart as well
+                let hashed = hash(text);
+                let changed = self.current_text.load(Ordering::SeqCst) != hashed
+                    && self.write(|clip| clip.copy_html(html, text)).await?;
+                if changed {
+                    self.current_text.store(hashed, Ordering::SeqCst);
+                }
+                changed
+            }
+            ClipboardObject::Image(ref img) => {
+                let hashed = hash(&img.bytes);
+                let changed = self.current_image.load(Ordering::SeqCst) != hashed
+                    && self.write(|clip| clip.copy_image(img.clone())).await?;
+                if changed {
                     self.current_image.store(hashed, Ordering::SeqCst);
                 }
+                changed
+            }
+            ClipboardObject::Files(ref files) => {
+                let size = files
+                    .iter()
+                    .map(|file| file.contents.len() as u64)
+                    .sum::<u64>();
+                let digest = obj.digest();
+                // The files land in a new directory every time, so they are compared by content
+                let unchanged = *self.received_files.lock().unwrap()
+                    == Some((digest, self.current_files.load(Ordering::SeqCst)));
+                match self.max_file_size {
+                    Some(_) if unchanged => false,
+                    Some(max_size) if size <= max_size => {
+                        let (dir, paths) = materialize(files).await?;
+                        let written = self.write(|clip| clip.copy_files(&paths)).await;
+                        // Only the files on the clipboard are kept around
+                        let stale = match written {
+                            Ok(_) => self.received_dir.lock().unwrap().replace(dir),
+                            Err(_) => Some(dir),
+                        };
+                        if let Some(stale) = stale {
+                            remove_received(&stale);
+                        }
+                        let changed = written?;
+                        if changed {
+                            let hashed = hash_paths(&paths);
+                            self.current_files.store(hashed, Ordering::SeqCst);
+                            *self.received_files.lock().unwrap() = Some((digest, hashed));
+                        }
+                        changed
+                    }
+                    Some(_) => {
+                        warn!(size, "Ignoring received files over --max-file-size");
+                        false
+                    }
+                    None => {
+                        warn!(size, "Ignoring received files, file sync is disabled");
+                        false
+                    }
+                }
+            }
+            ClipboardObject::Clear => {
+                let changed = self.write(|clip| clip.clear()).await?;
+                if changed {
+                    self.current_text.store(0, Ordering::SeqCst);
+                    self.current_image.store(0, Ordering::SeqCst);
+                    self.current_files.store(0, Ordering::SeqCst);
+                    *self.received_files.lock().unwrap() = None;
+                    let stale = self.received_dir.lock().unwrap().take();
+                    if let Some(stale) = stale {
+                        remove_received(&stale);
+                    }
+                }
+                changed
+            }
+            ClipboardObject::Primary(ref text) => {
+                let hashed = hash(text);
+                let changed = self.current_primary.load(Ordering::SeqCst) != hashed
+                    && self.write(|clip| clip.copy_primary(text)).await?;
+                if changed {
+                    self.current_primary.store(hashed, Ordering::SeqCst);
+                }
+                changed
             }
         };
+
+        if changed {
+            self.publish(origin, seq, node, obj, false);
+        }
         Ok(())
     }
 
-    pub async fn paste(&self) -> Result<ClipboardObject, Box<dyn Error + Send + Sync>> {
+    fn publish(&self, origin: Origin, seq: u64, node: u64, obj: ClipboardObject, sensitive: bool) {
+        trace!(?origin, seq, node, sensitive, "Publishing clipboard update");
+        let obj = Arc::new(obj);
+        // Nothing worth recalling in an empty clipboard or in every bit of text selected, and a
+        // recalled password would be synced
+        let selection = obj.selection();
+        if let Some(history) = self
+            .history
+            .as_ref()
+            .filter(|_| !obj.is_clear() && selection == Selection::Clipboard && !sensitive)
+        {
+            history.lock().unwrap().push(obj.clone());
+        }
+        let update = Update {
+            origin,
+            seq,
+            node,
+            obj,
+            sensitive,
+        };
+        *self.changes(selection).latest.lock().unwrap() = Some(update.clone());
+        // Nobody listening just means there are no connections right now
+        let _ = self.updates.send(update);
+    }
+
+    /// Waits for the next local change and whether it is sensitive, retrying with a growing
+    /// delay when the clipboard can't be read, until it turns out to be [`Unavailable`].
+    ///
+    /// When polling, the wait between two reads grows by half every time the clipboard stayed
+    /// the same, up to the idle poll interval, so an idle clipboard is barely read.
+    async fn paste(&self) -> Result<(ClipboardObject, bool), Box<dyn Error + Send + Sync>> {
+        let mut backoff = self.poll_interval;
+        let mut interval = self.poll_interval;
         loop {
-            let mut clip = self.clipboard.lock().await;
+            match self.poll().await {
+                Ok(Some(pasted)) => return Ok(pasted),
+                Ok(None) => {
+                    backoff = self.poll_interval;
+                    self.changed(interval).await;
+                    interval = (interval + interval / 2)
+                        .min(self.idle_poll_interval)
+                        .max(self.poll_interval);
+                }
+                Err(err) if err.is::<Unavailable>() => return Err(err),
+                Err(err) if err.is::<OwnershipLost>() => {
+                    debug!(error = %err, "Clipboard taken over while reading it, reading it again");
+                    self.changed(interval).await;
+                }
+                Err(err) => {
+                    warn!(error = %err, retry_in = ?backoff, "Failed to read the clipboard");
+                    sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_POLL_BACKOFF);
+                }
+            }
+        }
+    }
 
-            if let Ok(paste) = clip.get_text() {
+    /// Waits until the clipboard may have changed, for `interval` when polling.
+    async fn changed(&self, interval: Duration) {
+        // Change events only cover the clipboard, selecting text goes unnoticed
+        let polling = self.selection.includes(Selection::Primary);
+        match self.events {
+            Some(ref events) if events.live.load(Ordering::SeqCst) && !polling => {
+                events.changed.notified().await
+            }
+            _ => sleep(interval).await,
+        }
+    }
+
+    /// Looks at the selections synced once, returning what changed since the last time, if
+    /// anything, and whether it is sensitive.
+    async fn poll(&self) -> Result<Option<(ClipboardObject, bool)>, Box<dyn Error + Send + Sync>> {
+        if self.selection.includes(Selection::Clipboard) {
+            if let Some(pasted) = self.poll_clipboard().await? {
+                return Ok(Some(pasted));
+            }
+        }
+        if self.selection.includes(Selection::Primary) {
+            return self.poll_primary().await;
+        }
+        Ok(None)
+    }
+
+    /// Looks at the clipboard once, like [`Self::poll`].
+    async fn poll_clipboard(
+        &self,
+    ) -> Result<Option<(ClipboardObject, bool)>, Box<dyn Error + Send + Sync>> {
+        // The current_* hashes hold whatever was last synced, in either direction, so an
+        // object applied by `copy` isn't sent back. Once the clipboard stops holding a type
+        // its hash is forgotten, so copying the same content again later still syncs.
+
+        // Copied files usually come with their paths as text too, so look at them first
+        if let Some(max_size) = self.max_file_size {
+            let paths = self.read(|clip| clip.paste_files()).await?;
+            let paths = paths.unwrap_or_default();
+            if paths.is_empty() {
+                self.current_files.store(0, Ordering::SeqCst);
+            } else {
+                let hashed = hash_paths(&paths);
+                if hashed != self.current_files.swap(hashed, Ordering::SeqCst) {
+                    match read_files(&paths, max_size).await {
+                        Ok(Some(files)) => return Ok(Some((ClipboardObject::Files(files), false))),
+                        Ok(None) => {}
+                        Err(err) => warn!(error = %err, "Failed to read copied files"),
+                    }
+                }
+                return Ok(None);
+            }
+        }
+
+        match self.read(|clip| clip.paste_text()).await? {
+            Some(paste) if !paste.is_empty() => {
                 let hashed = hash(&paste);
-                if !paste.is_empty() && hashed != self.current_text.load(Ordering::SeqCst) {
-                    self.current_text.store(hashed, Ordering::SeqCst);
-                    break Ok(ClipboardObject::Text(paste));
+                if hashed != self.current_text.swap(hashed, Ordering::SeqCst) {
+                    let html = self.read(|clip| clip.paste_html()).await?;
+                    let sensitive = self.read(|clip| Ok(is_sensitive(clip))).await?;
+                    return Ok(Some((with_html(paste, html), sensitive)));
                 }
             }
+            _ => self.current_text.store(0, Ordering::SeqCst),
+        }
 
-            if let Ok(paste) = clip.get_image() {
+        match self.read(|clip| clip.paste_image()).await? {
+            Some(paste) if !paste.bytes.is_empty() => {
                 let hashed = hash(&paste.bytes);
-                if !paste.bytes.is_empty() && hashed != self.current_image.load(Ordering::SeqCst) {
-                    self.current_image.store(hashed, Ordering::SeqCst);
-                    break Ok(ClipboardObject::Image(paste));
+                if hashed != self.current_image.swap(hashed, Ordering::SeqCst) {
+                    let Some(paste) = self.limit_image(paste) else {
+                        return Ok(None);
+                    };
+                    let sensitive = self.read(|clip| Ok(is_sensitive(clip))).await?;
+                    return Ok(Some((ClipboardObject::Image(paste), sensitive)));
                 }
             }
+            _ => self.current_image.store(0, Ordering::SeqCst),
+        }
+
+        Ok(None)
+    }
 
-            sleep(Duration::from_secs(1)).await;
+    /// A copied image made to fit [`Self::with_image_limit`], or `None` when it is left out.
+    fn limit_image(&self, img: ImageData<'static>) -> Option<ImageData<'static>> {
+        let Some(limit) = self.image_limit else {
+            return Some(img);
+        };
+        let (width, height) = (img.width, img.height);
+        let Some(img) = limit.apply(img) else {
+            info!(width, height, "Not syncing image larger than the limit");
+            return None;
+        };
+        if (img.width, img.height) != (width, height) {
+            debug!(
+                width,
+                height,
+                to_width = img.width,
+                to_height = img.height,
+                "Downscaled copied image"
+            );
         }
+        Some(img)
     }
+
+    /// Looks at the primary selection once, like [`Self::poll`]. What is merely selected is
+    /// never taken as sensitive, password managers only mark what they copy.
+    async fn poll_primary(
+        &self,
+    ) -> Result<Option<(ClipboardObject, bool)>, Box<dyn Error + Send + Sync>> {
+        match self.read(|clip| clip.paste_primary()).await? {
+            Some(paste) if !paste.is_empty() => {
+                let hashed = hash(&paste);
+                if hashed != self.current_primary.swap(hashed, Ordering::SeqCst) {
+                    return Ok(Some((ClipboardObject::Primary(paste), false)));
+                }
+            }
+            _ => self.current_primary.store(0, Ordering::SeqCst),
+        }
+        Ok(None)
+    }
+
+    /// Runs `read` on the backend in a thread of its own, as it blocks until the application
+    /// holding the clipboard answers, giving up after the paste timeout. A read that never
+    /// finishes keeps the backend locked, so the next ones time out too until it does.
+    async fn read<T: Send + 'static>(
+        &self,
+        read: impl FnOnce(&mut B) -> Result<T, Box<dyn Error + Send + Sync>> + Send + 'static,
+    ) -> Result<T, Box<dyn Error + Send + Sync>> {
+        let read = async {
+            let mut clip = self.clipboard.clone().lock_owned().await;
+            task::spawn_blocking(move || read(&mut clip)).await?
+        };
+        timeout(self.paste_timeout, read).await.map_err(|_| {
+            format!(
+                "Reading the clipboard took longer than {}ms",
+                self.paste_timeout.as_millis()
+            )
+        })?
+    }
+
+    /// Runs `write` on the backend, retrying a few times when it fails, which also takes back a
+    /// clipboard another application took over. Returns whether it eventually succeeded, only an
+    /// [`Unavailable`] clipboard being an error.
+    async fn write(
+        &self,
+        mut write: impl FnMut(&mut B) -> Result<(), Box<dyn Error + Send + Sync>>,
+    ) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        let mut backoff = Duration::from_millis(100);
+        let mut attempt = 1;
+        loop {
+            let result = write(&mut *self.clipboard.lock().await);
+            match result {
+                Ok(()) => return Ok(true),
+                Err(err) if err.is::<Unavailable>() => return Err(err),
+                Err(err) if attempt >= WRITE_ATTEMPTS && err.is::<OwnershipLost>() => {
+                    debug!(error = %err, "Clipboard kept being taken over, skipping this object");
+                    return Ok(false);
+                }
+                Err(err) if attempt >= WRITE_ATTEMPTS => {
+                    warn!(error = %err, "Failed to write the clipboard, skipping this object");
+                    return Ok(false);
+                }
+                Err(err) => {
+                    debug!(error = %err, attempt, "Failed to write the clipboard, retrying");
+                    sleep(backoff).await;
+                    backoff *= 2;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+/// Whether `clip` holds something sensitive, taking it as not when that can't be told.
+fn is_sensitive(clip: &mut impl ClipboardBackend) -> bool {
+    clip.is_sensitive().unwrap_or_else(|err| {
+        debug!(error = %err, "Failed to tell whether the clipboard is sensitive");
+        false
+    })
 }
 
+/// What is tracked for each selection on its own.
+#[derive(Debug, Default)]
+struct Changes {
+    /// Sequence number of the latest change, made here or received.
+    seq: AtomicU64,
+    /// The last update published, sent to every new connection.
+    latest: std::sync::Mutex<Option<Update>>,
+}
+
+/// Change notifications from the platform clipboard, for as long as the listener runs.
 #[derive(Debug)]
+struct ChangeEvents {
+    changed: Notify,
+    live: AtomicBool,
+}
+
+#[derive(Clone)]
 pub enum ClipboardObject {
     Text(String),
     Image(ImageData<'static>),
+    Files(Vec<File>),
+    /// Nothing, emptying the clipboard it is applied to.
+    Clear,
+    /// Text selected, held by the primary selection rather than the clipboard.
+    Primary(String),
+    /// Formatted text, like what is copied from a browser, along with its plain text for the
+    /// applications that can't take HTML.
+    Html {
+        html: String,
+        text: String,
+    },
+}
+
+/// What a [`ClipboardObject`] holds, as reported in the logs, `--json-events` and the audit log.
+///
+/// It serializes to its lowercase name, like `text`, which never changes once released. Kinds
+/// may be added, but not removed or renamed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+#[non_exhaustive]
+pub enum ClipboardKind {
+    /// [`ClipboardObject::Text`]
+    Text,
+    /// [`ClipboardObject::Image`], whether it is sent as raw pixels or a PNG.
+    Image,
+    /// [`ClipboardObject::Files`]
+    Files,
+    /// [`ClipboardObject::Clear`]
+    Clear,
+    /// [`ClipboardObject::Primary`]
+    Primary,
+    /// [`ClipboardObject::Html`]
+    Html,
 }
 
-impl AsRef<[u8]> for ClipboardObject {
-    fn as_ref(&self) -> &[u8] {
+impl ClipboardKind {
+    /// Every kind, the position of each one being its bit in the kinds an end accepts, see
+    /// [`Protocol::accepting`](crate::protocol::Protocol::accepting).
+    pub const ALL: [Self; 6] = [
+        Self::Text,
+        Self::Image,
+        Self::Files,
+        Self::Clear,
+        Self::Primary,
+        Self::Html,
+    ];
+
+    /// Its bit in the kinds an end accepts.
+    pub fn bit(self) -> u16 {
+        1 << self as u16
+    }
+
+    /// The lowercase name it serializes to.
+    pub fn as_str(self) -> &'static str {
         match self {
-            Self::Text(txt) => txt.as_ref(),
-            Self::Image(img) => img.bytes.as_ref(),
+            Self::Text => "text",
+            Self::Image => "image",
+            Self::Files => "files",
+            Self::Clear => "clear",
+            Self::Primary => "primary",
+            Self::Html => "html",
         }
     }
 }
 
+impl fmt::Display for ClipboardKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for ClipboardKind {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        Self::ALL
+            .into_iter()
+            .find(|kind| kind.as_str() == name)
+            .ok_or_else(|| {
+                format!(
+                    "unknown kind `{name}`, expected text, image, files, clear, primary or html"
+                )
+            })
+    }
+}
+
+// Only the type and size, so clipboard contents never end up in logs by accident
+impl fmt::Debug for ClipboardObject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClipboardObject")
+            .field("kind", &self.kind())
+            .field("size", &self.size())
+            .finish()
+    }
+}
+
+/// A copied file, sent with its contents so the peer can recreate it.
+#[derive(Clone)]
+pub struct File {
+    pub name: String,
+    pub contents: Vec<u8>,
+}
+
+impl fmt::Debug for File {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("File")
+            .field("name", &self.name)
+            .field("size", &self.contents.len())
+            .finish()
+    }
+}
+
+/// Content type tag of a clipboard object on the wire, a [`ClipboardKind`] along with how it is
+/// encoded.
+///
+/// Every object is encoded as a [`codec`] message tagged with its type, so a peer can skip types
+/// it doesn't know about.
 #[repr(u8)]
 enum ClipboardObjectType {
+    /// UTF-8 text.
     Text = 1,
+    /// Width and height as big endian `u64`s followed by the RGBA pixels.
     Image = 2,
+    /// File count as a big endian `u64` followed by each length prefixed name and contents.
+    Files = 3,
+    /// No payload at all.
+    Clear = 4,
+    /// UTF-8 text, of the primary selection.
+    Primary = 5,
+    /// An image as a PNG, decoded back to RGBA pixels when read.
+    Png = 6,
+    /// Length prefixed UTF-8 HTML followed by its plain text.
+    Html = 7,
+}
+
+impl TryFrom<u8> for ClipboardObjectType {
+    type Error = u8;
+
+    fn try_from(kind: u8) -> Result<Self, Self::Error> {
+        match kind {
+            1 => Ok(Self::Text),
+            2 => Ok(Self::Image),
+            3 => Ok(Self::Files),
+            4 => Ok(Self::Clear),
+            5 => Ok(Self::Primary),
+            6 => Ok(Self::Png),
+            7 => Ok(Self::Html),
+            n => Err(n),
+        }
+    }
+}
+
+impl ClipboardObjectType {
+    fn kind(&self) -> ClipboardKind {
+        match *self {
+            Self::Text => ClipboardKind::Text,
+            Self::Image | Self::Png => ClipboardKind::Image,
+            Self::Files => ClipboardKind::Files,
+            Self::Clear => ClipboardKind::Clear,
+            Self::Primary => ClipboardKind::Primary,
+            Self::Html => ClipboardKind::Html,
+        }
+    }
 }
 
 impl ClipboardObject {
+    /// What the object holds.
+    pub fn kind(&self) -> ClipboardKind {
+        match *self {
+            Self::Text(_) => ClipboardKind::Text,
+            Self::Image(_) => ClipboardKind::Image,
+            Self::Files(_) => ClipboardKind::Files,
+            Self::Clear => ClipboardKind::Clear,
+            Self::Primary(_) => ClipboardKind::Primary,
+            Self::Html { .. } => ClipboardKind::Html,
+        }
+    }
+
+    /// The richest form of the object a peer taking the kinds `accepts` tells it does can use:
+    /// the object itself, its plain text for HTML, or nothing at all.
+    pub fn accepted_by(self, accepts: impl Fn(ClipboardKind) -> bool) -> Option<Self> {
+        match self {
+            obj if accepts(obj.kind()) => Some(obj),
+            Self::Html { text, .. } if accepts(ClipboardKind::Text) => Some(Self::Text(text)),
+            _ => None,
+        }
+    }
+
+    /// Size of the object contents in bytes.
+    pub fn size(&self) -> usize {
+        match *self {
+            Self::Text(ref text) | Self::Primary(ref text) => text.len(),
+            Self::Image(ref img) => img.bytes.len(),
+            Self::Files(ref files) => files
+                .iter()
+                .map(|file| file.name.len() + file.contents.len())
+                .sum(),
+            Self::Clear => 0,
+            Self::Html { ref html, ref text } => html.len() + text.len(),
+        }
+    }
+
+    pub fn is_clear(&self) -> bool {
+        matches!(*self, Self::Clear)
+    }
+
+    /// Whether the object is text with nothing in it, which some applications set for a moment.
+    pub fn is_empty_text(&self) -> bool {
+        matches!(self, Self::Text(text) | Self::Primary(text) if text.is_empty())
+    }
+
+    /// The selection the object is applied to, never [`Selection::Both`].
+    pub fn selection(&self) -> Selection {
+        match *self {
+            Self::Primary(_) => Selection::Primary,
+            _ => Selection::Clipboard,
+        }
+    }
+
+    /// Hash of the object contents, equal for objects holding the same content.
+    pub fn digest(&self) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        match *self {
+            Self::Text(ref text) => {
+                (ClipboardObjectType::Text as u8).hash(&mut hasher);
+                text.hash(&mut hasher);
+            }
+            Self::Image(ref img) => {
+                (ClipboardObjectType::Image as u8).hash(&mut hasher);
+                (img.width, img.height).hash(&mut hasher);
+                img.bytes.hash(&mut hasher);
+            }
+            Self::Files(ref files) => {
+                (ClipboardObjectType::Files as u8).hash(&mut hasher);
+                for file in files {
+                    file.name.hash(&mut hasher);
+                    file.contents.hash(&mut hasher);
+                }
+            }
+            Self::Clear => (ClipboardObjectType::Clear as u8).hash(&mut hasher),
+            Self::Primary(ref text) => {
+                (ClipboardObjectType::Primary as u8).hash(&mut hasher);
+                text.hash(&mut hasher);
+            }
+            Self::Html { ref html, ref text } => {
+                (ClipboardObjectType::Html as u8).hash(&mut hasher);
+                html.hash(&mut hasher);
+                text.hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Reads the next object, refusing payloads declared larger than `max_size` before
+    /// allocating anything for them.
+    ///
+    /// Objects that can't be used but don't break the stream are skipped, returning `None`: those
+    /// of an unknown type and text that isn't valid UTF-8, unless `lossy` is set, in which case
+    /// the invalid bytes are replaced.
     pub async fn from_reader(
         mut reader: impl AsyncRead + Send + Unpin,
-    ) -> Result<Self, Box<dyn Error + Send + Sync>> {
-        let mut buf = [0; 1];
-        reader.read_exact(&mut buf).await?;
-
-        trace!("Read kind {buf:?}");
-        let kind = match buf[0] {
-            1 => ClipboardObjectType::Text,
-            2 => ClipboardObjectType::Image,
-            n => return Err(format!("Invalid clipboard object type {n}").into()),
+        max_size: u64,
+        lossy: bool,
+    ) -> Result<Option<Self>, ClipshareError> {
+        let (tag, len) = codec::read_header(&mut reader, max_size, "Clipboard object").await?;
+
+        let kind = match ClipboardObjectType::try_from(tag) {
+            Ok(kind) => kind,
+            Err(kind) => {
+                warn!(kind, len, "Skipping clipboard object of unknown type");
+                tokio::io::copy(&mut (&mut reader).take(len), &mut tokio::io::sink()).await?;
+                return Ok(None);
+            }
         };
 
+        trace!(kind = %kind.kind(), len, "Reading clipboard object");
+        let mut payload = (&mut reader).take(len);
+        let obj = Self::read_payload(kind, len, max_size, lossy, &mut payload).await?;
+
+        if payload.limit() != 0 {
+            return Err(ClipshareError::protocol(
+                "Clipboard object is shorter than its declared length",
+            ));
+        }
+
+        Ok(obj)
+    }
+
+    async fn read_payload(
+        kind: ClipboardObjectType,
+        len: u64,
+        max_size: u64,
+        lossy: bool,
+        mut reader: impl AsyncRead + Send + Unpin,
+    ) -> Result<Option<Self>, ClipshareError> {
         match kind {
-            ClipboardObjectType::Text => {
-                let mut buf = [0; mem::size_of::<u64>()];
-                reader.read_exact(&mut buf).await?;
-                let len = u64::from_be_bytes(buf).try_into()?;
-                trace!(len, "Read text len");
-
-                let mut buf = vec![0; len];
-                reader.read_exact(&mut buf).await?;
+            ClipboardObjectType::Text | ClipboardObjectType::Primary => {
+                let buf = codec::read_bytes(&mut reader, len).await?;
                 trace!(len, "Read text");
 
-                let text = std::str::from_utf8(&buf)?;
-                Ok(Self::Text(text.to_string()))
+                let text = match kind {
+                    ClipboardObjectType::Primary => Self::Primary,
+                    _ => Self::Text,
+                };
+                Ok(utf8(buf, lossy).map(text))
+            }
+
+            ClipboardObjectType::Html => {
+                let html_len = codec::read_u64(&mut reader).await?;
+                let text_len = len
+                    .checked_sub(mem::size_of::<u64>() as u64)
+                    .and_then(|rest| rest.checked_sub(html_len))
+                    .ok_or(ClipshareError::protocol(
+                        "HTML is longer than the whole payload",
+                    ))?;
+                let html = codec::read_bytes(&mut reader, html_len).await?;
+                let text = codec::read_bytes(&mut reader, text_len).await?;
+                trace!(html_len, text_len, "Read HTML");
+
+                Ok(utf8(html, lossy)
+                    .zip(utf8(text, lossy))
+                    .map(|(html, text)| Self::Html { html, text }))
             }
 
             ClipboardObjectType::Image => {
-                let mut buf = [0; mem::size_of::<u64>()];
-                reader.read_exact(&mut buf).await?;
-                let width = u64::from_be_bytes(buf).try_into()?;
+                let width = dimension(codec::read_u64(&mut reader).await?)?;
                 trace!(width, "Read image width");
 
-                let mut buf = [0; mem::size_of::<u64>()];
-                reader.read_exact(&mut buf).await?;
-                let height = u64::from_be_bytes(buf).try_into()?;
+                let height = dimension(codec::read_u64(&mut reader).await?)?;
                 trace!(height, "Read image height");
 
-                let mut buf = [0; mem::size_of::<u64>()];
-                reader.read_exact(&mut buf).await?;
-                let len = u64::from_be_bytes(buf).try_into()?;
-                trace!(width, height, len, "Read image metadata");
-
-                let mut buf = vec![0; len];
-                reader.read_exact(&mut buf).await?;
+                let len = len
+                    .checked_sub(2 * mem::size_of::<u64>() as u64)
+                    .ok_or(ClipshareError::protocol("Image payload is too short"))?;
+                // RGBA, 4 bytes a pixel, which the backend counts on
+                let expected = width
+                    .checked_mul(height)
+                    .and_then(|pixels| pixels.checked_mul(4));
+                if expected.is_none_or(|expected| expected as u64 != len) {
+                    return Err(ClipshareError::protocol(format!(
+                        "Image of {width}x{height} pixels doesn't take {len} bytes"
+                    )));
+                }
+                let buf = codec::read_bytes(&mut reader, len).await?;
                 trace!(width, height, len, "Read image");
 
                 let img = ImageData {
@@ -193,24 +1226,97 @@ impl ClipboardObject {
                     bytes: Cow::from(buf),
                 };
 
-                Ok(Self::Image(img))
+                Ok(Some(Self::Image(img)))
             }
+
+            ClipboardObjectType::Png => {
+                let buf = codec::read_bytes(&mut reader, len).await?;
+                // Decoded pixels are held to the same limit as raw ones
+                let img = image::decode_png(&buf, max_size)?;
+                trace!(
+                    width = img.width,
+                    height = img.height,
+                    len,
+                    "Read PNG image"
+                );
+
+                Ok(Some(Self::Image(img)))
+            }
+
+            ClipboardObjectType::Files => {
+                let count = codec::read_u64(&mut reader).await?;
+                trace!(count, "Read file count");
+
+                // The whole payload is already capped, so that also bounds every field in it
+                let field_len = |field: u64| {
+                    if field > len {
+                        Err(ClipshareError::protocol(
+                            "File field is longer than the whole payload",
+                        ))
+                    } else {
+                        Ok(field)
+                    }
+                };
+
+                let mut files = Vec::new();
+                for _ in 0..count {
+                    let name_len = field_len(codec::read_u64(&mut reader).await?)?;
+                    let name = String::from_utf8(codec::read_bytes(&mut reader, name_len).await?)
+                        .map_err(|err| {
+                        ClipshareError::protocol(format!("Invalid file name: {err}"))
+                    })?;
+
+                    let len = field_len(codec::read_u64(&mut reader).await?)?;
+                    trace!(len, "Read file len");
+
+                    let contents = codec::read_bytes(&mut reader, len).await?;
+                    trace!(len, "Read file");
+
+                    files.push(File { name, contents });
+                }
+
+                Ok(Some(Self::Files(files)))
+            }
+
+            ClipboardObjectType::Clear => Ok(Some(Self::Clear)),
         }
     }
 
     pub async fn write(
-        self,
+        &self,
+        writer: impl AsyncWrite + Send + Unpin,
+    ) -> Result<(), ClipshareError> {
+        self.write_as(writer, ImageFormat::Raw).await
+    }
+
+    /// Like [`Self::write`], encoding an image as `format`.
+    pub async fn write_as(
+        &self,
         mut writer: impl AsyncWrite + Send + Unpin,
-    ) -> Result<(), Box<dyn Error + Send + Sync>> {
-        let buf = match self {
+        format: ImageFormat,
+    ) -> Result<(), ClipshareError> {
+        let u64_len = mem::size_of::<u64>();
+
+        match *self {
+            Self::Image(ref img) if format == ImageFormat::Png => {
+                let png = image::encode_png(img)?;
+                trace!(
+                    width = img.width,
+                    height = img.height,
+                    len = png.len(),
+                    "Sending PNG image"
+                );
+
+                codec::write_header(&mut writer, ClipboardObjectType::Png as u8, png.len()).await?;
+                writer.write_all(&png).await?;
+            }
+
             Self::Text(ref text) => {
-                trace!(len = text.as_bytes().len(), "Sending text");
+                trace!(len = text.len(), "Sending text");
 
-                [
-                    &[ClipboardObjectType::Text as u8][..],
-                    &u64::try_from(text.as_bytes().len())?.to_be_bytes()[..],
-                ]
-                .concat()
+                codec::write_header(&mut writer, ClipboardObjectType::Text as u8, text.len())
+                    .await?;
+                writer.write_all(text.as_bytes()).await?;
             }
 
             Self::Image(ref img) => {
@@ -221,32 +1327,212 @@ impl ClipboardObject {
                     "Sending image"
                 );
 
-                [
-                    &[ClipboardObjectType::Image as u8][..],
-                    &u64::try_from(img.width)?.to_be_bytes()[..],
-                    &u64::try_from(img.height)?.to_be_bytes()[..],
-                    &u64::try_from(img.bytes.len())?.to_be_bytes()[..],
-                ]
-                .concat()
+                let len = 2 * u64_len + img.bytes.len();
+                codec::write_header(&mut writer, ClipboardObjectType::Image as u8, len).await?;
+                codec::write_u64(&mut writer, img.width).await?;
+                codec::write_u64(&mut writer, img.height).await?;
+                writer.write_all(&img.bytes).await?;
             }
-        };
 
-        writer.write_all(&buf).await?;
+            Self::Files(ref files) => {
+                trace!(count = files.len(), "Sending files");
 
-        let buf = match self {
-            Self::Text(ref text) => text.as_bytes(),
-            Self::Image(ref img) => &img.bytes,
-        };
+                let len = u64_len
+                    + files
+                        .iter()
+                        .map(|file| 2 * u64_len + file.name.len() + file.contents.len())
+                        .sum::<usize>();
+                codec::write_header(&mut writer, ClipboardObjectType::Files as u8, len).await?;
+                codec::write_u64(&mut writer, files.len()).await?;
+                for file in files {
+                    codec::write_u64(&mut writer, file.name.len()).await?;
+                    writer.write_all(file.name.as_bytes()).await?;
+                    codec::write_u64(&mut writer, file.contents.len()).await?;
+                    writer.write_all(&file.contents).await?;
+                }
+            }
+
+            Self::Clear => {
+                trace!("Sending clear");
+
+                codec::write_header(&mut writer, ClipboardObjectType::Clear as u8, 0).await?;
+            }
+
+            Self::Primary(ref text) => {
+                trace!(len = text.len(), "Sending primary selection");
+
+                codec::write_header(&mut writer, ClipboardObjectType::Primary as u8, text.len())
+                    .await?;
+                writer.write_all(text.as_bytes()).await?;
+            }
+
+            Self::Html { ref html, ref text } => {
+                trace!(html_len = html.len(), text_len = text.len(), "Sending HTML");
 
-        writer.write_all(buf).await?;
-        trace!(len = buf.len(), "Clipboard sent");
+                let len = u64_len + html.len() + text.len();
+                codec::write_header(&mut writer, ClipboardObjectType::Html as u8, len).await?;
+                codec::write_u64(&mut writer, html.len()).await?;
+                writer.write_all(html.as_bytes()).await?;
+                writer.write_all(text.as_bytes()).await?;
+            }
+        }
 
+        trace!("Clipboard sent");
         Ok(())
     }
 }
 
+/// Copied `text`, along with the HTML it was copied as if there is any.
+fn with_html(text: String, html: Option<String>) -> ClipboardObject {
+    match html.filter(|html| !html.is_empty()) {
+        Some(html) => ClipboardObject::Html { html, text },
+        None => ClipboardObject::Text(text),
+    }
+}
+
+/// Received text, `None` when it isn't valid UTF-8 unless `lossy` is set, in which case the
+/// invalid bytes are replaced.
+fn utf8(buf: Vec<u8>, lossy: bool) -> Option<String> {
+    match String::from_utf8(buf) {
+        Ok(valid) => Some(valid),
+        Err(err) if lossy => {
+            warn!("Received text is not valid UTF-8, replacing the invalid bytes");
+            Some(String::from_utf8_lossy(err.as_bytes()).into_owned())
+        }
+        Err(err) => {
+            warn!(error = %err, "Skipping received text that is not valid UTF-8");
+            None
+        }
+    }
+}
+
+/// An image dimension as sent, refusing one this platform can't hold.
+fn dimension(val: u64) -> Result<usize, ClipshareError> {
+    val.try_into()
+        .map_err(|_| ClipshareError::protocol(format!("Image dimension {val} is too large")))
+}
+
 fn hash(val: impl AsRef<[u8]>) -> u64 {
     let mut hasher = DefaultHasher::new();
     val.as_ref().hash(&mut hasher);
     hasher.finish()
 }
+
+fn hash_paths(paths: &[PathBuf]) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    paths.hash(&mut hasher);
+    hasher.finish()
+}
+
+/// Reads the copied files, or returns `None` when they are not worth syncing.
+async fn read_files(
+    paths: &[PathBuf],
+    max_size: u64,
+) -> Result<Option<Vec<File>>, Box<dyn Error + Send + Sync>> {
+    let mut size = 0;
+    let mut regular = Vec::new();
+    for path in paths {
+        let metadata = tokio::fs::metadata(path).await?;
+        if metadata.is_file() {
+            size += metadata.len();
+            regular.push(path);
+        } else {
+            warn!(path = %path.display(), "Only regular files are synced, skipping");
+        }
+    }
+
+    if regular.is_empty() {
+        return Ok(None);
+    }
+
+    if size > max_size {
+        warn!(
+            size,
+            max_size, "Not syncing copied files over --max-file-size"
+        );
+        return Ok(None);
+    }
+
+    let mut files = Vec::new();
+    for path in regular {
+        let Some(name) = path.file_name() else {
+            continue;
+        };
+        files.push(File {
+            name: name.to_string_lossy().into_owned(),
+            contents: tokio::fs::read(path).await?,
+        });
+    }
+    Ok(Some(files))
+}
+
+/// Writes received files into a new directory only the current user can get to, returning the
+/// directory along with their paths.
+async fn materialize(
+    files: &[File],
+) -> Result<(PathBuf, Vec<PathBuf>), Box<dyn Error + Send + Sync>> {
+    // One of the user's own, unlike the temporary directory anyone can make things in
+    let parent = dirs::runtime_dir()
+        .or_else(dirs::cache_dir)
+        .ok_or("No runtime or cache directory to write received files to")?
+        .join("clipshare");
+    let mut builder = tokio::fs::DirBuilder::new();
+    builder.recursive(true);
+    #[cfg(unix)]
+    builder.mode(0o700);
+    builder.create(&parent).await?;
+    let dir = parent.join(format!("{:016x}", crypto::random_id()));
+    builder.recursive(false);
+    builder.create(&dir).await?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{MetadataExt, PermissionsExt};
+
+        // The directory just made is ours, the one it's in has to be too, or whoever owns it
+        // could swap ours for a link to somewhere else
+        let user = tokio::fs::metadata(&dir).await?.uid();
+        let metadata = tokio::fs::symlink_metadata(&parent).await?;
+        if !metadata.is_dir() || metadata.uid() != user {
+            let _ = tokio::fs::remove_dir(&dir).await;
+            return Err(format!("{} belongs to another user", parent.display()).into());
+        }
+        if metadata.mode() & 0o077 != 0 {
+            tokio::fs::set_permissions(&parent, std::fs::Permissions::from_mode(0o700)).await?;
+        }
+    }
+
+    let mut paths = Vec::new();
+    for file in files {
+        // Only accept plain names, so a peer can't write outside of our directory
+        let mut components = Path::new(&file.name).components();
+        let (Some(Component::Normal(name)), None) = (components.next(), components.next()) else {
+            return Err(format!("Invalid file name {:?}", file.name).into());
+        };
+        let mut path = dir.join(name);
+        // Number files going by the same name, rather than have one overwrite the other
+        let mut copy = 1;
+        while tokio::fs::try_exists(&path).await? {
+            copy += 1;
+            let name = Path::new(name);
+            let mut numbered = name.file_stem().unwrap_or_default().to_os_string();
+            numbered.push(format!(" ({copy})"));
+            if let Some(extension) = name.extension() {
+                numbered.push(".");
+                numbered.push(extension);
+            }
+            path = dir.join(numbered);
+        }
+        tokio::fs::write(&path, &file.contents).await?;
+        trace!(path = %path.display(), "Wrote received file");
+        paths.push(path);
+    }
+    Ok((dir, paths))
+}
+
+/// Removes a directory received files were written to, once they aren't on the clipboard.
+fn remove_received(dir: &Path) {
+    match std::fs::remove_dir_all(dir) {
+        Ok(()) => trace!(dir = %dir.display(), "Removed received files"),
+        Err(err) => warn!(dir = %dir.display(), error = %err, "Failed to remove received files"),
+    }
+}
@@ -0,0 +1,144 @@
+//! The message encoding shared by the handshake and clipboard objects.
+//!
+//! A message is a tag byte telling what it is, the length of its body as a big endian `u64` and
+//! the body itself. The length is checked against a maximum before anything is allocated for
+//! the body, and a reader can skip a message it doesn't know the tag of. Clipboard objects are
+//! encoded as messages, then split into the frames of [`Protocol`](crate::protocol::Protocol).
+
+use std::{io, mem};
+
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+use tracing::trace;
+
+use crate::error::ClipshareError;
+
+/// Bytes in front of every message body.
+pub const HEADER_LEN: usize = 1 + mem::size_of::<u64>();
+
+/// Writes the header of a message whose `len` bytes of body follow.
+pub async fn write_header(
+    mut writer: impl AsyncWrite + Send + Unpin,
+    tag: u8,
+    len: usize,
+) -> Result<(), ClipshareError> {
+    let buf = [&[tag][..], &(len as u64).to_be_bytes()[..]].concat();
+    writer.write_all(&buf).await?;
+    Ok(())
+}
+
+/// Reads the header of a message, returning its tag and body length, refusing a body longer
+/// than `max_len`. `what` names the message in the error.
+pub async fn read_header(
+    mut reader: impl AsyncRead + Send + Unpin,
+    max_len: u64,
+    what: &str,
+) -> Result<(u8, u64), ClipshareError> {
+    let mut tag = [0; 1];
+    read_exact(&mut reader, &mut tag).await?;
+    read_len(reader, tag[0], max_len, what).await
+}
+
+/// Reads the length following the `tag` of a message, refusing one longer than `max_len`.
+async fn read_len(
+    reader: impl AsyncRead + Send + Unpin,
+    tag: u8,
+    max_len: u64,
+    what: &str,
+) -> Result<(u8, u64), ClipshareError> {
+    let len = read_u64(reader).await?;
+    trace!(tag, len, "Read message header");
+
+    if len > max_len {
+        return Err(ClipshareError::too_large(what, len, max_len));
+    }
+    Ok((tag, len))
+}
+
+/// Writes a whole message.
+pub async fn write_message(
+    mut writer: impl AsyncWrite + Send + Unpin,
+    tag: u8,
+    body: &[u8],
+) -> Result<(), ClipshareError> {
+    write_header(&mut writer, tag, body.len()).await?;
+    writer.write_all(body).await?;
+    Ok(())
+}
+
+/// Reads a whole message from a connection, returning its tag and body, refusing a body longer
+/// than `max_len`. The peer closing the connection before it is [`ClipshareError::Closed`].
+pub async fn read_message(
+    mut reader: impl AsyncRead + Send + Unpin,
+    max_len: u64,
+    what: &str,
+) -> Result<(u8, Vec<u8>), ClipshareError> {
+    let mut tag = [0; 1];
+    read_start(&mut reader, &mut tag).await?;
+    let (tag, len) = read_len(&mut reader, tag[0], max_len, what).await?;
+    let body = read_bytes(reader, len).await?;
+    Ok((tag, body))
+}
+
+/// Reads `len` bytes, which the caller already checked against a maximum.
+pub async fn read_bytes(
+    reader: impl AsyncRead + Send + Unpin,
+    len: u64,
+) -> Result<Vec<u8>, ClipshareError> {
+    let len = usize::try_from(len)
+        .map_err(|_| ClipshareError::too_large("Message", len, usize::MAX as u64))?;
+    let mut buf = vec![0; len];
+    read_exact(reader, &mut buf).await?;
+    Ok(buf)
+}
+
+pub async fn read_u64(mut reader: impl AsyncRead + Send + Unpin) -> Result<u64, ClipshareError> {
+    let mut buf = [0; mem::size_of::<u64>()];
+    read_exact(&mut reader, &mut buf).await?;
+    Ok(u64::from_be_bytes(buf))
+}
+
+/// Fills `buf` with the start of the next message on a connection. The peer closing it before
+/// the first byte is an ordinary [`ClipshareError::Closed`], unlike it closing it partway.
+pub async fn read_start(
+    mut reader: impl AsyncRead + Send + Unpin,
+    buf: &mut [u8],
+) -> Result<(), ClipshareError> {
+    let mut read = 0;
+    while read < buf.len() {
+        match reader.read(&mut buf[read..]).await {
+            Ok(0) if read == 0 => return Err(ClipshareError::Closed),
+            // A TLS peer closing the connection without saying so first
+            Err(err) if read == 0 && err.kind() == io::ErrorKind::UnexpectedEof => {
+                return Err(ClipshareError::Closed)
+            }
+            Ok(0) => return Err(truncated()),
+            Ok(len) => read += len,
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(())
+}
+
+/// Fills `buf` with more of a message, which ending first is a protocol error.
+pub async fn read_exact(
+    mut reader: impl AsyncRead + Send + Unpin,
+    buf: &mut [u8],
+) -> Result<(), ClipshareError> {
+    match reader.read_exact(buf).await {
+        Ok(_) => Ok(()),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Err(truncated()),
+        Err(err) => Err(err.into()),
+    }
+}
+
+fn truncated() -> ClipshareError {
+    ClipshareError::protocol("Message cut short, the peer closed the connection partway through")
+}
+
+pub async fn write_u64(
+    mut writer: impl AsyncWrite + Send + Unpin,
+    val: usize,
+) -> Result<(), ClipshareError> {
+    writer.write_all(&(val as u64).to_be_bytes()).await?;
+    Ok(())
+}
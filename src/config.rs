@@ -0,0 +1,146 @@
+use std::{
+    error::Error,
+    fs, io,
+    net::{IpAddr, SocketAddr},
+    path::PathBuf,
+};
+
+use serde::Deserialize;
+use tracing::Level;
+
+use clipshare::clipboard::ClipboardKind;
+
+use crate::{BackendKind, ClearOnStart, ImageFormatKind, LogFormat, SelectionKind, TransportKind};
+
+/// Settings read from the config file, mirroring the command line options. Anything given on the
+/// command line takes precedence.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub port: Option<u16>,
+    pub port_fallback: Option<bool>,
+    pub bind: Option<IpAddr>,
+    pub socket: Option<PathBuf>,
+    pub url: Option<Urls>,
+    pub allow: Option<Vec<String>>,
+    pub max_connections: Option<usize>,
+    pub discover_timeout: Option<u64>,
+    pub no_advertise: Option<bool>,
+    pub broadcast: Option<bool>,
+    pub qr: Option<bool>,
+    pub clear_on_start: Option<ClearOnStart>,
+    pub no_clear: Option<bool>,
+    pub clear_remote: Option<bool>,
+    pub sync_files: Option<bool>,
+    pub selection: Option<SelectionKind>,
+    pub sync_sensitive: Option<bool>,
+    pub sync_empty: Option<bool>,
+    pub include: Option<Vec<String>>,
+    pub exclude: Option<Vec<String>>,
+    pub max_file_size: Option<u64>,
+    pub max_image_dimension: Option<usize>,
+    pub reject_large_images: Option<bool>,
+    pub image_format: Option<ImageFormatKind>,
+    pub max_size: Option<u64>,
+    pub stream_threshold: Option<usize>,
+    pub accept: Option<Vec<ClipboardKind>>,
+    pub confirm_over: Option<u64>,
+    pub auto_accept: Option<bool>,
+    pub key: Option<String>,
+    pub key_file: Option<PathBuf>,
+    pub keys_file: Option<PathBuf>,
+    pub room: Option<String>,
+    pub name: Option<String>,
+    pub tls: Option<bool>,
+    pub transport: Option<TransportKind>,
+    pub tls_cert: Option<PathBuf>,
+    pub tls_key: Option<PathBuf>,
+    pub tls_pin: Option<String>,
+    pub encrypt: Option<bool>,
+    pub require_encryption: Option<bool>,
+    pub compress: Option<bool>,
+    pub lossy: Option<bool>,
+    pub send_only: Option<bool>,
+    pub recv_only: Option<bool>,
+    pub normalize_eol: Option<bool>,
+    pub trim: Option<bool>,
+    pub min_interval_ms: Option<u64>,
+    pub poll_interval_ms: Option<u64>,
+    pub idle_poll_interval_ms: Option<u64>,
+    pub paste_timeout_ms: Option<u64>,
+    pub recv_queue: Option<usize>,
+    pub notify: Option<bool>,
+    pub history: Option<usize>,
+    pub state_file: Option<PathBuf>,
+    pub backend: Option<BackendKind>,
+    pub relay: Option<bool>,
+    pub keepalive_secs: Option<u64>,
+    pub heartbeat_secs: Option<u64>,
+    pub handshake_timeout: Option<u64>,
+    pub max_retries: Option<u32>,
+    pub log_content: Option<bool>,
+    pub audit_log: Option<PathBuf>,
+    pub audit_content: Option<bool>,
+    pub json_events: Option<bool>,
+    pub metrics_addr: Option<SocketAddr>,
+    pub progress: Option<bool>,
+    pub log_level: Option<String>,
+    pub log_format: Option<LogFormat>,
+    pub log_file: Option<PathBuf>,
+    pub no_console_log: Option<bool>,
+}
+
+/// Server urls, a single one or a list of them.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum Urls {
+    One(String),
+    Many(Vec<String>),
+}
+
+impl Urls {
+    pub fn into_vec(self) -> Vec<String> {
+        match self {
+            Self::One(url) => vec![url],
+            Self::Many(urls) => urls,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the config file at `path`, or the default one in the config dir when omitted. A
+    /// missing default config file is the same as an empty one.
+    pub fn load(path: Option<PathBuf>) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let (path, required) = match path {
+            Some(path) => (path, true),
+            None => match dirs::config_dir() {
+                Some(dir) => (dir.join("clipshare").join("config.toml"), false),
+                None => return Ok(Self::default()),
+            },
+        };
+
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound && !required => {
+                return Ok(Self::default())
+            }
+            Err(err) => {
+                return Err(format!("Could not read config file {}: {err}", path.display()).into())
+            }
+        };
+
+        toml::from_str(&contents)
+            .map_err(|err| format!("Invalid config file {}: {err}", path.display()).into())
+    }
+
+    pub fn log_level(&self) -> Result<Option<Level>, Box<dyn Error + Send + Sync>> {
+        self.log_level
+            .as_deref()
+            .map(|level| {
+                level
+                    .parse()
+                    .map_err(|_| format!("Invalid log-level `{level}` in the config file").into())
+            })
+            .transpose()
+    }
+}
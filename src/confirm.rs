@@ -0,0 +1,52 @@
+//! Asking before applying large or file objects received from the peers.
+
+use std::{fmt, sync::Arc};
+
+use crate::clipboard::{ClipboardKind, ClipboardObject};
+
+/// Asks whether a received object may be applied, when it holds files or is larger than a
+/// threshold. Anything else is applied right away.
+#[derive(Clone)]
+pub struct Confirm {
+    over: u64,
+    ask: Arc<dyn Fn(ClipboardKind, usize) -> bool + Send + Sync>,
+}
+
+impl fmt::Debug for Confirm {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Confirm")
+            .field("over", &self.over)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Confirm {
+    /// Calls `ask` with the kind and size of the files and objects over `over` bytes, which are
+    /// discarded when it answers `false`. It runs off the runtime, so it may block on the user.
+    pub fn new(
+        over: u64,
+        ask: impl Fn(ClipboardKind, usize) -> bool + Send + Sync + 'static,
+    ) -> Self {
+        Self {
+            over,
+            ask: Arc::new(ask),
+        }
+    }
+
+    /// Whether `obj` needs asking about.
+    pub fn needed(&self, obj: &ClipboardObject) -> bool {
+        matches!(obj, ClipboardObject::Files(_)) || obj.size() as u64 > self.over
+    }
+
+    /// Whether `obj` may be applied, asking about it when needed.
+    pub async fn allows(&self, obj: &ClipboardObject) -> bool {
+        if !self.needed(obj) {
+            return true;
+        }
+        let ask = self.ask.clone();
+        let (kind, size) = (obj.kind(), obj.size());
+        tokio::task::spawn_blocking(move || ask(kind, size))
+            .await
+            .unwrap_or_default()
+    }
+}
@@ -0,0 +1,185 @@
+use std::fmt;
+
+use aes_gcm::aead::rand_core::RngCore;
+use aes_gcm::{
+    aead::{Aead, AeadCore, KeyInit, OsRng},
+    Aes256Gcm, Nonce,
+};
+use curve25519_dalek::MontgomeryPoint;
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+
+use crate::error::ClipshareError;
+
+const SALT: &[u8] = b"clipshare";
+const PAYLOAD_INFO: &[u8] = b"clipshare payload key";
+const SESSION_INFO: &[u8] = b"clipshare session key";
+const NONCE_LEN: usize = 12;
+
+/// Length of the challenge a server sends in the handshake, and of the response to it.
+pub const CHALLENGE_LEN: usize = 32;
+/// Length of the ephemeral public key each end sends in the handshake.
+pub const PUBLIC_KEY_LEN: usize = 32;
+
+/// AES-256-GCM cipher derived from the shared key, or from it and a key exchange for a single
+/// connection.
+pub struct Cipher {
+    aead: Aes256Gcm,
+}
+
+impl fmt::Debug for Cipher {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Cipher").finish_non_exhaustive()
+    }
+}
+
+impl Cipher {
+    pub fn new(key: &str) -> Self {
+        let mut derived = [0; 32];
+        Hkdf::<Sha256>::new(Some(SALT), key.as_bytes())
+            .expand(PAYLOAD_INFO, &mut derived)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self {
+            aead: Aes256Gcm::new(&derived.into()),
+        }
+    }
+
+    /// The cipher of a single connection, derived from the shared `key` along with the `shared`
+    /// secret of its key exchange and both public keys. The exchange gives forward secrecy, the
+    /// key that only the two ends can derive the same cipher.
+    pub fn session(
+        key: &str,
+        shared: &[u8; 32],
+        client_public: &[u8; PUBLIC_KEY_LEN],
+        server_public: &[u8; PUBLIC_KEY_LEN],
+    ) -> Self {
+        let mut derived = [0; 32];
+        let secret = [key.as_bytes(), &shared[..]].concat();
+        let info = [SESSION_INFO, &client_public[..], &server_public[..]].concat();
+        Hkdf::<Sha256>::new(Some(SALT), &secret)
+            .expand(&info, &mut derived)
+            .expect("32 bytes is a valid HKDF-SHA256 output length");
+        Self {
+            aead: Aes256Gcm::new(&derived.into()),
+        }
+    }
+
+    /// Encrypts `plaintext` with a fresh random nonce, which is prepended to the ciphertext.
+    pub fn encrypt(&self, plaintext: &[u8]) -> Result<Vec<u8>, ClipshareError> {
+        let nonce = Aes256Gcm::generate_nonce(&mut OsRng);
+        let ciphertext = self
+            .aead
+            .encrypt(&nonce, plaintext)
+            .map_err(|_| ClipshareError::protocol("Failed to encrypt clipboard"))?;
+        Ok([&nonce[..], &ciphertext[..]].concat())
+    }
+
+    pub fn decrypt(&self, data: &[u8]) -> Result<Vec<u8>, ClipshareError> {
+        if data.len() < NONCE_LEN {
+            return Err(ClipshareError::protocol("Encrypted clipboard is too short"));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        self.aead
+            .decrypt(Nonce::from_slice(nonce), ciphertext)
+            .map_err(|_| {
+                ClipshareError::auth(
+                    "Failed to decrypt clipboard, are both ends using the same key?",
+                )
+            })
+    }
+}
+
+/// A fresh random challenge for a client to prove it knows the key.
+pub fn challenge() -> [u8; CHALLENGE_LEN] {
+    let mut challenge = [0; CHALLENGE_LEN];
+    OsRng.fill_bytes(&mut challenge);
+    challenge
+}
+
+/// An X25519 key pair made for a single connection and thrown away once the key exchange is
+/// done, so recording a session and learning the shared key later isn't enough to decrypt it.
+pub struct Ephemeral {
+    secret: [u8; 32],
+    public: [u8; PUBLIC_KEY_LEN],
+}
+
+impl fmt::Debug for Ephemeral {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Ephemeral").finish_non_exhaustive()
+    }
+}
+
+impl Ephemeral {
+    pub fn new() -> Self {
+        let mut secret = [0; 32];
+        OsRng.fill_bytes(&mut secret);
+        let public = MontgomeryPoint::mul_base_clamped(secret).to_bytes();
+        Self { secret, public }
+    }
+
+    pub fn public(&self) -> [u8; PUBLIC_KEY_LEN] {
+        self.public
+    }
+
+    /// The secret shared with the peer that sent `public`, refusing a public key that would make
+    /// it the same whatever this end picked.
+    pub fn agree(self, public: &[u8; PUBLIC_KEY_LEN]) -> Result<[u8; 32], ClipshareError> {
+        let shared = MontgomeryPoint(*public).mul_clamped(self.secret).to_bytes();
+        if shared == [0; 32] {
+            return Err(ClipshareError::protocol("Invalid key exchange public key"));
+        }
+        Ok(shared)
+    }
+}
+
+impl Default for Ephemeral {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// A random id, telling apart the ends syncing with each other.
+pub fn random_id() -> u64 {
+    OsRng.next_u64()
+}
+
+/// What a client tells the server along with its answer to the challenge, covered by it.
+#[derive(Debug, Clone, Copy)]
+pub struct Claims<'a> {
+    pub flags: u8,
+    /// Its ephemeral public key.
+    pub public: &'a [u8],
+    /// The id of its device.
+    pub device: u64,
+    /// The name it goes by, as sent, at most `u8::MAX` bytes long.
+    pub name: &'a [u8],
+    /// The room it joins.
+    pub room: &'a str,
+}
+
+/// The answer to `challenge` of a client knowing `key` and making `claims`, so a captured
+/// response is of no use for another connection and none of the claims can be tampered with.
+pub fn respond(key: &str, challenge: &[u8], claims: &Claims<'_>) -> [u8; CHALLENGE_LEN] {
+    auth(key, challenge, claims).finalize().into_bytes().into()
+}
+
+/// Whether `response` is the answer to `challenge` of a client knowing `key` and making
+/// `claims`, compared in constant time.
+pub fn verify(key: &str, challenge: &[u8], claims: &Claims<'_>, response: &[u8]) -> bool {
+    auth(key, challenge, claims).verify_slice(response).is_ok()
+}
+
+fn auth(key: &str, challenge: &[u8], claims: &Claims<'_>) -> Hmac<Sha256> {
+    let mut mac = <Hmac<Sha256> as Mac>::new_from_slice(key.as_bytes())
+        .expect("HMAC accepts keys of any length");
+    mac.update(challenge);
+    mac.update(&[claims.flags]);
+    mac.update(claims.public);
+    mac.update(&claims.device.to_be_bytes());
+    // Length prefixed, so no part of the name can pass for the room or the other way around
+    mac.update(&[claims.name.len() as u8]);
+    mac.update(claims.name);
+    mac.update(claims.room.as_bytes());
+    mac
+}
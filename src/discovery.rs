@@ -0,0 +1,224 @@
+use std::{
+    error::Error,
+    net::{Ipv4Addr, SocketAddr},
+    time::Duration,
+};
+
+use mdns_sd::{ServiceDaemon, ServiceEvent, ServiceInfo};
+use tokio::{
+    net::UdpSocket,
+    time::{interval, timeout},
+};
+use tracing::{debug, trace, warn};
+
+use crate::protocol;
+
+const SERVICE_TYPE: &str = "_clipshare._tcp.local.";
+
+/// UDP port servers listen on for broadcast probes.
+const BROADCAST_PORT: u16 = 11338;
+/// Broadcast by clients, followed by their protocol version.
+const PROBE: &[u8] = b"CLIPSHARE?";
+/// Sent back by servers, followed by their protocol version, flags and TCP port.
+const PROBE_REPLY: &[u8] = b"CLIPSHARE!";
+/// Probe reply flag telling a key other than the default one is needed.
+const KEY_REQUIRED: u8 = 0b01;
+/// Probe reply flag telling the server expects TLS.
+const TLS: u8 = 0b10;
+
+/// Keeps the server registered on mDNS for as long as it is alive.
+pub struct Advertisement {
+    daemon: ServiceDaemon,
+}
+
+impl Drop for Advertisement {
+    fn drop(&mut self) {
+        let _ = self.daemon.shutdown();
+    }
+}
+
+/// What a server advertises about itself besides its port.
+#[derive(Debug, Clone, Copy)]
+pub struct Service {
+    /// Whether a key other than the default one is needed to connect.
+    pub key_required: bool,
+    pub tls: bool,
+    /// Whether to advertise through mDNS.
+    pub mdns: bool,
+    /// Whether to answer UDP broadcast probes.
+    pub broadcast: bool,
+}
+
+impl Service {
+    /// Registers this server as a `_clipshare._tcp` service, advertising its port, protocol
+    /// version and whether a key is required to connect.
+    pub fn advertise(self, port: u16) -> Result<Advertisement, Box<dyn Error + Send + Sync>> {
+        let daemon = ServiceDaemon::new()?;
+        let hostname = gethostname::gethostname().to_string_lossy().into_owned();
+
+        let properties = [
+            ("version", protocol::VERSION.to_string()),
+            ("key", u8::from(self.key_required).to_string()),
+            ("tls", u8::from(self.tls).to_string()),
+        ];
+
+        let service = ServiceInfo::new(
+            SERVICE_TYPE,
+            &hostname,
+            &format!("{hostname}.local."),
+            "",
+            port,
+            &properties[..],
+        )?
+        .enable_addr_auto();
+
+        trace!(
+            fullname = service.get_fullname(),
+            "Registering mDNS service"
+        );
+        daemon.register(service)?;
+
+        Ok(Advertisement { daemon })
+    }
+
+    /// Answers UDP broadcast probes with this server's TCP `port`, forever.
+    pub async fn answer_probes(self, port: u16) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, BROADCAST_PORT)).await?;
+        debug!(port = BROADCAST_PORT, "Answering UDP broadcast probes");
+
+        let mut flags = 0;
+        if self.key_required {
+            flags |= KEY_REQUIRED;
+        }
+        if self.tls {
+            flags |= TLS;
+        }
+        let reply = [
+            PROBE_REPLY,
+            &[protocol::VERSION, flags][..],
+            &port.to_be_bytes()[..],
+        ]
+        .concat();
+
+        let mut buf = [0; 64];
+        loop {
+            let (len, peer) = socket.recv_from(&mut buf).await?;
+            let Some(version) = buf[..len].strip_prefix(PROBE) else {
+                continue;
+            };
+            trace!(%peer, ?version, "Received broadcast probe");
+            socket.send_to(&reply, peer).await?;
+        }
+    }
+}
+
+/// Broadcasts probes on the local network, returning the address of the first compatible
+/// server that answers.
+pub async fn discover_broadcast(wait: Duration) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let socket = UdpSocket::bind((Ipv4Addr::UNSPECIFIED, 0)).await?;
+    socket.set_broadcast(true)?;
+    let probe = [PROBE, &[protocol::VERSION][..]].concat();
+
+    let found = timeout(wait, async {
+        // Probes may get lost, so keep sending them until someone answers
+        let mut resend = interval(Duration::from_secs(1));
+        let mut buf = [0; 64];
+        loop {
+            tokio::select! {
+                _ = resend.tick() => {
+                    trace!("Broadcasting probe");
+                    socket.send_to(&probe, (Ipv4Addr::BROADCAST, BROADCAST_PORT)).await?;
+                }
+                received = socket.recv_from(&mut buf) => {
+                    let (len, peer) = received?;
+                    let Some(&[version, flags, high, low]) = buf[..len].strip_prefix(PROBE_REPLY) else {
+                        continue;
+                    };
+                    if version != protocol::VERSION {
+                        warn!(%peer, version, "Ignoring server with an incompatible protocol version");
+                        continue;
+                    }
+                    if flags & KEY_REQUIRED != 0 {
+                        debug!(%peer, "Server requires a key");
+                    }
+                    if flags & TLS != 0 {
+                        debug!(%peer, "Server expects TLS");
+                    }
+                    let port = u16::from_be_bytes([high, low]);
+                    return Ok::<_, std::io::Error>(SocketAddr::new(peer.ip(), port).to_string());
+                }
+            }
+        }
+    })
+    .await;
+
+    match found {
+        Ok(found) => Ok(found?),
+        Err(_) => Err(format!(
+            "No clipshare server answered the broadcast within {}s",
+            wait.as_secs()
+        )
+        .into()),
+    }
+}
+
+/// Browses for a clipshare server, returning the address of the first one that resolves (or the
+/// one whose instance name matches `name`).
+pub async fn discover(
+    name: Option<&str>,
+    wait: Duration,
+) -> Result<String, Box<dyn Error + Send + Sync>> {
+    let daemon = ServiceDaemon::new()?;
+    let events = daemon.browse(SERVICE_TYPE)?;
+
+    let found = timeout(wait, async {
+        while let Ok(event) = events.recv_async().await {
+            let ServiceEvent::ServiceResolved(service) = event else {
+                continue;
+            };
+            trace!(fullname = service.get_fullname(), "Resolved mDNS service");
+
+            if let Some(name) = name {
+                let instance = service.get_fullname().trim_end_matches(SERVICE_TYPE);
+                if instance.trim_end_matches('.') != name {
+                    continue;
+                }
+            }
+
+            let version = service.get_property_val_str("version");
+            if version != Some(&protocol::VERSION.to_string()) {
+                warn!(
+                    fullname = service.get_fullname(),
+                    version, "Ignoring server with an incompatible protocol version"
+                );
+                continue;
+            }
+
+            if service.get_property_val_str("key") == Some("1") {
+                debug!(fullname = service.get_fullname(), "Server requires a key");
+            }
+
+            let addresses = service.get_addresses();
+            let ip = addresses
+                .iter()
+                .map(|ip| ip.to_ip_addr())
+                .filter(|ip| !ip.is_loopback())
+                .min_by_key(|ip| ip.is_ipv6())
+                .or_else(|| addresses.iter().next().map(|ip| ip.to_ip_addr()));
+
+            if let Some(ip) = ip {
+                return Some(SocketAddr::new(ip, service.get_port()).to_string());
+            }
+        }
+        None
+    })
+    .await;
+
+    let _ = daemon.shutdown();
+
+    match found {
+        Ok(Some(addr)) => Ok(addr),
+        Ok(None) => Err("mDNS discovery stopped before finding a server".into()),
+        Err(_) => Err(format!("No clipshare server found within {}s", wait.as_secs()).into()),
+    }
+}
@@ -0,0 +1,98 @@
+//! What can go wrong on a connection, for callers to tell a wrong key from a dropped connection.
+
+use std::{error::Error, fmt, io};
+
+use crate::clipboard::ClipboardKind;
+
+/// Why a connection, or the handshake starting it, failed.
+#[derive(Debug)]
+#[non_exhaustive]
+pub enum ClipshareError {
+    /// Reading from or writing to the connection failed.
+    Io(io::Error),
+    /// The peer closed the connection between two messages, an ordinary disconnect. Closing it
+    /// partway through one is a [`Self::Protocol`] error.
+    Closed,
+    /// The peer isn't speaking the clipshare protocol, the same version of it, or sent something
+    /// malformed.
+    Protocol(String),
+    /// The handshake didn't go through for a reason other than the key, like the room or
+    /// `--encrypt` not matching, or the peer not completing it in time.
+    Handshake(String),
+    /// The peer doesn't know the key, or the pairing code, or can't decrypt what is sent with it.
+    Auth(String),
+    /// Something the peer sent is larger than allowed.
+    TooLarge {
+        /// What was too large.
+        what: String,
+        size: u64,
+        max: u64,
+    },
+    /// The peer doesn't accept objects of this kind, nor a simpler form of it.
+    NotAccepted(ClipboardKind),
+    /// The local clipboard couldn't be read or written.
+    Backend(Box<dyn Error + Send + Sync>),
+}
+
+impl fmt::Display for ClipshareError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(err) => err.fmt(f),
+            Self::Closed => f.write_str("The peer closed the connection"),
+            Self::Protocol(reason) | Self::Handshake(reason) | Self::Auth(reason) => {
+                f.write_str(reason)
+            }
+            Self::TooLarge { what, size, max } => {
+                write!(
+                    f,
+                    "{what} of {size} bytes exceeds the maximum of {max} bytes"
+                )
+            }
+            Self::NotAccepted(kind) => write!(f, "The peer doesn't accept {kind}"),
+            Self::Backend(err) => err.fmt(f),
+        }
+    }
+}
+
+impl Error for ClipshareError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            Self::Io(err) => err.source(),
+            Self::Backend(err) => err.source(),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for ClipshareError {
+    fn from(err: io::Error) -> Self {
+        Self::Io(err)
+    }
+}
+
+impl ClipshareError {
+    pub(crate) fn protocol(reason: impl Into<String>) -> Self {
+        Self::Protocol(reason.into())
+    }
+
+    pub(crate) fn handshake(reason: impl Into<String>) -> Self {
+        Self::Handshake(reason.into())
+    }
+
+    pub(crate) fn auth(reason: impl Into<String>) -> Self {
+        Self::Auth(reason.into())
+    }
+
+    pub(crate) fn too_large(what: impl Into<String>, size: u64, max: u64) -> Self {
+        Self::TooLarge {
+            what: what.into(),
+            size,
+            max,
+        }
+    }
+
+    /// Whether trying again later can't help, as the peer will refuse this end the same way.
+    pub fn is_auth(&self) -> bool {
+        matches!(self, Self::Auth(_))
+    }
+}
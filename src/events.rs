@@ -0,0 +1,187 @@
+//! Machine readable lifecycle events, one JSON object per line on stdout with `--json-events`.
+//!
+//! Every event has an `event` field naming it, the other fields depend on it. Fields are only
+//! ever added, so consumers should ignore the ones they don't know.
+
+use std::{
+    net::IpAddr,
+    path::Path,
+    sync::atomic::{AtomicBool, Ordering},
+    time::Instant,
+};
+
+use clipshare::{clipboard::ClipboardKind, session::SessionEvent};
+use serde::Serialize;
+use tokio::sync::mpsc;
+use tracing::{info, warn};
+
+use crate::{audit, metrics, progress};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+#[derive(Debug, Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub enum Event<'a> {
+    Listening {
+        port: u16,
+    },
+    ListeningSocket {
+        path: &'a Path,
+    },
+    Connected {
+        ip: IpAddr,
+    },
+    HandshakeOk {
+        ip: IpAddr,
+        peer: &'a str,
+    },
+    HandshakeFailed {
+        ip: IpAddr,
+        error: &'a str,
+    },
+    Sent {
+        ip: IpAddr,
+        kind: ClipboardKind,
+        size: usize,
+    },
+    Received {
+        ip: IpAddr,
+        kind: ClipboardKind,
+        size: usize,
+    },
+    Declined {
+        ip: IpAddr,
+        kind: ClipboardKind,
+        size: usize,
+    },
+    Sending {
+        ip: IpAddr,
+        done: u64,
+        total: u64,
+    },
+    Receiving {
+        ip: IpAddr,
+        done: u64,
+        total: u64,
+    },
+    Disconnected {
+        ip: IpAddr,
+    },
+    Stats {
+        ip: IpAddr,
+        #[serde(flatten)]
+        stats: Stats,
+    },
+    Error {
+        error: &'a str,
+    },
+}
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+pub fn enabled() -> bool {
+    ENABLED.load(Ordering::Relaxed)
+}
+
+/// Prints `event` when `--json-events` is set, and counts it for `--metrics-addr`.
+pub fn emit(event: Event<'_>) {
+    metrics::record(&event);
+    if !enabled() {
+        return;
+    }
+    match serde_json::to_string(&event) {
+        Ok(line) => println!("{line}"),
+        Err(err) => warn!(error = %err, "Failed to serialize event"),
+    }
+}
+
+/// What went through a connection, from the handshake to its end.
+#[derive(Debug, Default, Clone, Copy, Serialize)]
+pub struct Stats {
+    /// Clipboard objects sent.
+    pub sent: u64,
+    /// Clipboard objects received.
+    pub received: u64,
+    /// Size of the contents of the objects sent, before compression and encryption.
+    pub bytes_sent: u64,
+    /// Size of the contents of the objects received.
+    pub bytes_received: u64,
+    /// Size of the largest object sent or received.
+    pub largest: usize,
+    pub duration_secs: f64,
+}
+
+impl Stats {
+    /// Logs a summary of the connection to `ip`, and prints it with `--json-events`.
+    pub fn show(self, ip: IpAddr) {
+        info!(
+            %ip,
+            sent = self.sent,
+            received = self.received,
+            bytes_sent = self.bytes_sent,
+            bytes_received = self.bytes_received,
+            largest = self.largest,
+            "Connection lasted {:.0}s",
+            self.duration_secs
+        );
+        emit(Event::Stats { ip, stats: self });
+    }
+}
+
+/// Prints the objects sent and received on the connection to `ip` and how far along large ones
+/// are, until the connection is gone, returning what went through it. The objects are written to
+/// the audit log too, as coming from or going to `peer`.
+pub async fn report(
+    ip: IpAddr,
+    peer: &str,
+    mut events: mpsc::UnboundedReceiver<SessionEvent>,
+) -> Stats {
+    let start = Instant::now();
+    let mut stats = Stats::default();
+    while let Some(event) = events.recv().await {
+        match event {
+            SessionEvent::Sent {
+                kind,
+                size,
+                preview,
+            } => {
+                stats.sent += 1;
+                stats.bytes_sent += size as u64;
+                stats.largest = stats.largest.max(size);
+                audit::record("sent", ip, peer, kind, size, preview.as_deref());
+                emit(Event::Sent { ip, kind, size });
+            }
+            SessionEvent::Received {
+                kind,
+                size,
+                preview,
+            } => {
+                stats.received += 1;
+                stats.bytes_received += size as u64;
+                stats.largest = stats.largest.max(size);
+                audit::record("received", ip, peer, kind, size, preview.as_deref());
+                emit(Event::Received { ip, kind, size });
+            }
+            SessionEvent::Declined {
+                kind,
+                size,
+                preview,
+            } => {
+                audit::record("declined", ip, peer, kind, size, preview.as_deref());
+                emit(Event::Declined { ip, kind, size });
+            }
+            SessionEvent::Sending { done, total } => {
+                progress::show("Sending", done, total);
+                emit(Event::Sending { ip, done, total });
+            }
+            SessionEvent::Receiving { done, total } => {
+                progress::show("Receiving", done, total);
+                emit(Event::Receiving { ip, done, total });
+            }
+        }
+    }
+    stats.duration_secs = start.elapsed().as_secs_f64();
+    stats
+}
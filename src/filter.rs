@@ -0,0 +1,37 @@
+//! Choosing which copied text is sent to the peers.
+
+use regex::Regex;
+
+use crate::clipboard::ClipboardObject;
+
+/// Patterns text has to match, or must not match, to be sent. Images, files and cleared
+/// clipboards are always sent.
+///
+/// A pattern matches anywhere in the text unless it is anchored with `^` or `$`. Text matching
+/// an exclude pattern is never sent, even when it matches an include pattern too.
+#[derive(Debug, Clone, Default)]
+pub struct Filter {
+    include: Vec<Regex>,
+    exclude: Vec<Regex>,
+}
+
+impl Filter {
+    /// Only sends text matching at least one of `include`, unless it is empty.
+    pub fn new(include: Vec<Regex>, exclude: Vec<Regex>) -> Self {
+        Self { include, exclude }
+    }
+
+    /// Whether `obj` may be sent.
+    pub fn allows(&self, obj: &ClipboardObject) -> bool {
+        let (ClipboardObject::Text(ref text)
+        | ClipboardObject::Primary(ref text)
+        | ClipboardObject::Html { ref text, .. }) = *obj
+        else {
+            return true;
+        };
+        if self.exclude.iter().any(|exclude| exclude.is_match(text)) {
+            return false;
+        }
+        self.include.is_empty() || self.include.iter().any(|include| include.is_match(text))
+    }
+}
@@ -0,0 +1,639 @@
+//! Connection handshake.
+//!
+//! Both ends first exchange the magic prefix and their protocol version. The rest are
+//! [`codec`] messages: the server sends a random challenge, the client answers with its flags,
+//! an HMAC of the challenge, flags, public key, device id, name and room keyed with the key, the
+//! largest payload it accepts, the largest object it wants sent in a single frame, the kinds of
+//! objects it accepts, an ephemeral X25519 public key, the id of its device, its length
+//! prefixed name and the room it wants to join. The key never crosses the wire and a captured
+//! answer can't be replayed. The server then accepts the client with the flags
+//! both ends agreed on, its own limits and kinds, its own ephemeral public key and its name, or
+//! rejects it. Both ends go by the smaller of each limit from then on, and only send the kinds
+//! the other one accepts.
+//!
+//! With `--encrypt`, the payloads of the connection are encrypted with a cipher derived from the
+//! key along with the secret of that key exchange, which is different for every connection and
+//! forgotten once it is established: recording a session and learning the key later isn't
+//! enough to decrypt it. A peer without the key, replacing the server public key, derives
+//! another cipher and can't decrypt anything.
+//!
+//! A client without a key can instead answer the challenge with a request to pair. When the
+//! server has a pairing code to offer, both ends run [`pairing`] with it, the server adds the
+//! key they agreed on to the ones it accepts and sends a fresh challenge, answered with that key.
+//!
+//! The HMAC covers the device id and name of the client too, which tell it connecting again
+//! apart from a new client: a peer rewriting them can't pass for another one to close its
+//! connection.
+
+use std::{
+    fmt, mem,
+    sync::{Arc, PoisonError, RwLock, RwLockReadGuard},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, trace, warn, Span};
+
+use crate::{
+    codec,
+    crypto::{self, Cipher, Claims, Ephemeral, CHALLENGE_LEN, PUBLIC_KEY_LEN},
+    error::ClipshareError,
+    pairing::{self, Paired, Pairing},
+    protocol::{self, Protocol},
+};
+
+/// Tag of the random challenge the server sends.
+const CHALLENGE: u8 = 1;
+/// Tag of the client's flags, answer to the challenge and room.
+const RESPONSE: u8 = 2;
+/// Tag of the flags both ends agreed on and the server's name, sent when it accepts the client.
+const ACCEPTED: u8 = 3;
+/// Tag of the empty message sent by the server when it refuses the client.
+const REJECTED: u8 = 4;
+/// Tag of the empty message a client answers the challenge with to pair, and of the server's
+/// go-ahead.
+const PAIR: u8 = 5;
+
+/// Room of the peers that didn't ask for one.
+pub const DEFAULT_ROOM: &str = "";
+/// Longest room name, in bytes.
+pub const MAX_ROOM_LEN: usize = u8::MAX as usize;
+/// Longest peer name, in bytes.
+pub const MAX_NAME_LEN: usize = u8::MAX as usize;
+/// Bytes of the largest payload an end accepts, a big endian `u64`, followed by the largest
+/// object it sends in a single frame, a big endian `u32`, and the bits of the kinds of objects
+/// it accepts, a big endian `u16`.
+const LIMITS_LEN: usize = mem::size_of::<u64>() + mem::size_of::<u32>() + mem::size_of::<u16>();
+/// Bytes of the device id of a client, a big endian `u64`.
+const DEVICE_LEN: usize = mem::size_of::<u64>();
+/// Longest response the server reads, before the client is authenticated.
+const MAX_RESPONSE_LEN: usize =
+    1 + CHALLENGE_LEN + LIMITS_LEN + PUBLIC_KEY_LEN + DEVICE_LEN + 1 + MAX_NAME_LEN + MAX_ROOM_LEN;
+
+/// Who a client is: the name it goes by and a random id of the session it runs, the same every
+/// time it connects, or `0` for none.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Identity {
+    pub name: String,
+    pub device: u64,
+}
+
+/// What the server side of the handshake agreed on with a client.
+#[derive(Debug, Clone)]
+pub struct Accepted {
+    pub protocol: Protocol,
+    /// Name of the key the client authenticated with.
+    pub key: String,
+    /// Room the client joined.
+    pub room: String,
+    /// Who the client is.
+    pub peer: Identity,
+}
+
+/// What the client side of the handshake agreed on with the server.
+#[derive(Debug, Clone)]
+pub struct Joined {
+    pub protocol: Protocol,
+    /// Name the server goes by.
+    pub name: String,
+}
+
+/// The name this machine goes by when none is given: its hostname.
+pub fn default_name() -> String {
+    gethostname::gethostname().to_string_lossy().into_owned()
+}
+
+/// Keys a server accepts, each under a name recorded on the connection span when a client
+/// authenticates with it.
+///
+/// Clones share the keys, so one added by pairing is accepted right away by all of them.
+#[derive(Clone, Default)]
+pub struct Keys {
+    keys: Arc<RwLock<Vec<(String, String)>>>,
+}
+
+impl fmt::Debug for Keys {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_list()
+            .entries(self.read().iter().map(|(name, _)| name))
+            .finish()
+    }
+}
+
+impl Keys {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// A single key, named `default`.
+    pub fn single(key: impl Into<String>) -> Self {
+        let keys = Self::new();
+        keys.add("default", key);
+        keys
+    }
+
+    pub fn add(&self, name: impl Into<String>, key: impl Into<String>) {
+        self.keys
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .push((name.into(), key.into()));
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.read().is_empty()
+    }
+
+    /// Whether any of the keys is `key`.
+    pub fn contains(&self, key: &str) -> bool {
+        self.read().iter().any(|(_, k)| k == key)
+    }
+
+    /// The name and key of the one a client answered `challenge` with.
+    fn find(
+        &self,
+        challenge: &[u8],
+        claims: &Claims<'_>,
+        response: &[u8],
+    ) -> Option<(String, String)> {
+        self.read()
+            .iter()
+            .find(|(_, key)| crypto::verify(key, challenge, claims, response))
+            .cloned()
+    }
+
+    fn read(&self) -> RwLockReadGuard<'_, Vec<(String, String)>> {
+        self.keys.read().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+/// Runs the server side of the handshake as `name`, returning what was agreed on with the
+/// client. Only clients joining `room` are accepted, or any room when it is `None`. Clients
+/// asking to pair are offered the code of `pairing`, when there is one left.
+///
+/// Any failure rejects the client, the caller must drop the connection without syncing.
+pub async fn server(
+    mut reader: impl AsyncRead + Send + Unpin,
+    mut writer: impl AsyncWrite + Send + Unpin,
+    keys: &Keys,
+    room: Option<&str>,
+    name: &str,
+    protocol: &Protocol,
+    pairing: Option<&Pairing>,
+) -> Result<Accepted, ClipshareError> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(ClipshareError::handshake(format!(
+            "Names are at most {MAX_NAME_LEN} bytes long"
+        )));
+    }
+
+    let version = protocol::read_hello(&mut reader).await?;
+    Span::current().record("version", version);
+    // Always answer with our version, so an older or newer client can tell the user what's wrong
+    protocol::write_hello(&mut writer).await?;
+    if version != protocol::VERSION {
+        warn!(
+            version,
+            expected = protocol::VERSION,
+            "A client using another protocol version tried to connect"
+        );
+        return reject(
+            writer,
+            ClipshareError::protocol(format!(
+                "Unsupported protocol version {version}, this server speaks version {}, please upgrade the older one",
+                protocol::VERSION
+            )),
+        )
+        .await;
+    }
+
+    let (mut challenge, mut tag, mut body) = challenge(&mut reader, &mut writer).await?;
+    if tag == PAIR {
+        // The code can only be tried once, whatever comes of it
+        let Some((pairing, code)) = pairing.and_then(|pairing| Some((pairing, pairing.take()?)))
+        else {
+            return reject(
+                writer,
+                ClipshareError::handshake(
+                    "The client asked to pair, but there is no code to offer",
+                ),
+            )
+            .await;
+        };
+        codec::write_message(&mut writer, PAIR, &[]).await?;
+        writer.flush().await?;
+        let paired = pairing::host(&mut reader, &mut writer, &code, name).await?;
+        keys.add(&paired.name, &paired.key);
+        pairing.paired(&paired);
+        (challenge, tag, body) = self::challenge(&mut reader, &mut writer).await?;
+    }
+
+    if tag != RESPONSE
+        || body.len() < 1 + CHALLENGE_LEN + LIMITS_LEN + PUBLIC_KEY_LEN + DEVICE_LEN + 1
+    {
+        return reject(
+            writer,
+            ClipshareError::protocol("Malformed handshake response"),
+        )
+        .await;
+    }
+    let (&flags, rest) = body.split_first().expect("the body isn't empty");
+    let (response, rest) = rest.split_at(CHALLENGE_LEN);
+    let (max_size, stream_threshold, accepts, rest) = read_limits(rest);
+    let (client_public, rest) = read_public(rest);
+    let (device, rest) = rest.split_at(DEVICE_LEN);
+    let device = u64::from_be_bytes(device.try_into().expect("the length was checked"));
+    let (&name_len, rest) = rest.split_first().expect("the name length is there");
+    let Some((peer, joined)) = rest.split_at_checked(name_len.into()) else {
+        return reject(
+            writer,
+            ClipshareError::protocol("Malformed handshake response"),
+        )
+        .await;
+    };
+    let raw_name = peer;
+    let peer = peer_name(peer);
+    Span::current().record("peer", &peer);
+    let Ok(joined) = String::from_utf8(joined.to_vec()) else {
+        return reject(
+            writer,
+            ClipshareError::protocol("Room name is not valid UTF-8"),
+        )
+        .await;
+    };
+    Span::current().record("room", &joined);
+
+    if flags & !protocol::FLAGS != 0 {
+        return reject(
+            writer,
+            ClipshareError::protocol(format!("Unknown handshake flags {flags:#04x}")),
+        )
+        .await;
+    }
+
+    if flags & protocol::ENCRYPTED != protocol.flags() & protocol::ENCRYPTED {
+        let reason = if flags & protocol::ENCRYPTED == 0 {
+            "Encryption mismatch, the client doesn't encrypt, refusing to sync in the clear"
+        } else {
+            "Encryption mismatch, the client encrypts but this end doesn't use --encrypt"
+        };
+        return reject(writer, ClipshareError::handshake(reason)).await;
+    }
+
+    let claims = Claims {
+        flags,
+        public: &client_public,
+        device,
+        name: raw_name,
+        room: &joined,
+    };
+    let Some((key_name, key)) = keys.find(&challenge, &claims, response) else {
+        return reject(writer, ClipshareError::auth("Key mismatch")).await;
+    };
+    Span::current().record("key", &key_name);
+
+    if room.is_some_and(|room| room != joined) {
+        return reject(
+            writer,
+            ClipshareError::handshake(format!("Room mismatch, the client asked for `{joined}`")),
+        )
+        .await;
+    }
+
+    let own_limits = limits(protocol);
+    let protocol = protocol.negotiate(flags, max_size, stream_threshold, accepts);
+    let ephemeral = Ephemeral::new();
+    let server_public = ephemeral.public();
+    // Every connection encrypts with its own cipher
+    let protocol = if flags & protocol::ENCRYPTED != 0 {
+        let shared = match ephemeral.agree(&client_public) {
+            Ok(shared) => shared,
+            Err(err) => return reject(writer, err).await,
+        };
+        protocol.encrypted(Cipher::session(
+            &key,
+            &shared,
+            &client_public,
+            &server_public,
+        ))
+    } else {
+        protocol
+    };
+    debug!(
+        flags = protocol.flags(),
+        max_size = protocol.max_size(),
+        stream_threshold = protocol.stream_threshold(),
+        "Negotiated session"
+    );
+    let body = [
+        &[protocol.flags()][..],
+        &own_limits,
+        &server_public,
+        name.as_bytes(),
+    ]
+    .concat();
+    codec::write_message(&mut writer, ACCEPTED, &body).await?;
+    writer.flush().await?;
+
+    Ok(Accepted {
+        protocol,
+        key: key_name,
+        room: joined,
+        peer: Identity { name: peer, device },
+    })
+}
+
+/// Sends a fresh challenge, returning it along with the tag and body of the client's answer.
+async fn challenge(
+    mut reader: impl AsyncRead + Send + Unpin,
+    mut writer: impl AsyncWrite + Send + Unpin,
+) -> Result<([u8; CHALLENGE_LEN], u8, Vec<u8>), ClipshareError> {
+    let challenge = crypto::challenge();
+    codec::write_message(&mut writer, CHALLENGE, &challenge).await?;
+    writer.flush().await?;
+
+    // The key itself is never sent, the name and room are the only lengths a client picks
+    let (tag, body) =
+        codec::read_message(&mut reader, MAX_RESPONSE_LEN as u64, "Handshake response").await?;
+    trace!("Read challenge response");
+    Ok((challenge, tag, body))
+}
+
+/// Runs the client side of the handshake as `identity`, joining `room`, returning what was
+/// agreed on with the server.
+pub async fn client(
+    mut reader: impl AsyncRead + Send + Unpin,
+    mut writer: impl AsyncWrite + Send + Unpin,
+    key: &str,
+    room: &str,
+    identity: &Identity,
+    protocol: &Protocol,
+) -> Result<Joined, ClipshareError> {
+    hello(&mut reader, &mut writer, room, &identity.name).await?;
+    let challenge = read_challenge(&mut reader).await?;
+    answer(reader, writer, &challenge, key, room, identity, protocol).await
+}
+
+/// Runs the client side of the handshake as `identity` like [`client`], pairing with the server
+/// through `code` first, and joining with the key pairing agreed on.
+pub async fn pair(
+    mut reader: impl AsyncRead + Send + Unpin,
+    mut writer: impl AsyncWrite + Send + Unpin,
+    code: &str,
+    room: &str,
+    identity: &Identity,
+    protocol: &Protocol,
+) -> Result<(Joined, Paired), ClipshareError> {
+    hello(&mut reader, &mut writer, room, &identity.name).await?;
+    read_challenge(&mut reader).await?;
+    codec::write_message(&mut writer, PAIR, &[]).await?;
+    writer.flush().await?;
+
+    let (tag, _) = codec::read_message(&mut reader, 0, "Handshake answer").await?;
+    match tag {
+        PAIR => {}
+        REJECTED => {
+            return Err(ClipshareError::handshake(
+                "The server isn't pairing, start it with --pair, or its code was already tried",
+            ))
+        }
+        _ => return Err(ClipshareError::protocol("Malformed handshake answer")),
+    }
+    let paired = pairing::join(&mut reader, &mut writer, code, &identity.name).await?;
+
+    let challenge = read_challenge(&mut reader).await?;
+    let joined = answer(
+        reader,
+        writer,
+        &challenge,
+        &paired.key,
+        room,
+        identity,
+        protocol,
+    )
+    .await?;
+    Ok((joined, paired))
+}
+
+/// Checks the lengths the client picks, then exchanges the magic prefix and versions.
+async fn hello(
+    mut reader: impl AsyncRead + Send + Unpin,
+    mut writer: impl AsyncWrite + Send + Unpin,
+    room: &str,
+    name: &str,
+) -> Result<(), ClipshareError> {
+    if room.len() > MAX_ROOM_LEN {
+        return Err(ClipshareError::handshake(format!(
+            "Room names are at most {MAX_ROOM_LEN} bytes long"
+        )));
+    }
+    if name.len() > MAX_NAME_LEN {
+        return Err(ClipshareError::handshake(format!(
+            "Names are at most {MAX_NAME_LEN} bytes long"
+        )));
+    }
+
+    protocol::write_hello(&mut writer).await?;
+    let version = protocol::read_hello(&mut reader).await?;
+    Span::current().record("version", version);
+    if version != protocol::VERSION {
+        return Err(ClipshareError::protocol(format!(
+            "The server speaks protocol version {version} but this client speaks version {}, please upgrade the older one",
+            protocol::VERSION
+        )));
+    }
+    Ok(())
+}
+
+async fn read_challenge(
+    mut reader: impl AsyncRead + Send + Unpin,
+) -> Result<Vec<u8>, ClipshareError> {
+    let (tag, challenge) =
+        codec::read_message(&mut reader, CHALLENGE_LEN as u64, "Handshake challenge").await?;
+    if tag != CHALLENGE || challenge.len() != CHALLENGE_LEN {
+        return Err(ClipshareError::protocol("Malformed handshake challenge"));
+    }
+    trace!("Read challenge");
+    Ok(challenge)
+}
+
+/// Answers `challenge` with `key`, returning what the server agreed on.
+async fn answer(
+    mut reader: impl AsyncRead + Send + Unpin,
+    mut writer: impl AsyncWrite + Send + Unpin,
+    challenge: &[u8],
+    key: &str,
+    room: &str,
+    identity: &Identity,
+    protocol: &Protocol,
+) -> Result<Joined, ClipshareError> {
+    // Prove the key is known without sending it
+    let flags = protocol.flags();
+    let ephemeral = Ephemeral::new();
+    let client_public = ephemeral.public();
+    let claims = Claims {
+        flags,
+        public: &client_public,
+        device: identity.device,
+        name: identity.name.as_bytes(),
+        room,
+    };
+    let response = crypto::respond(key, challenge, &claims);
+    let body = [
+        &[flags][..],
+        &response[..],
+        &limits(protocol),
+        &client_public,
+        &identity.device.to_be_bytes(),
+        &[identity.name.len() as u8][..],
+        identity.name.as_bytes(),
+        room.as_bytes(),
+    ]
+    .concat();
+    codec::write_message(&mut writer, RESPONSE, &body).await?;
+    writer.flush().await?;
+
+    // The server answers with the flags both ends agreed on, its limits, public key and name
+    let (tag, answer) = codec::read_message(
+        &mut reader,
+        (1 + LIMITS_LEN + PUBLIC_KEY_LEN + MAX_NAME_LEN) as u64,
+        "Handshake answer",
+    )
+    .await?;
+    let (agreed, limits, server_public, peer) = match (tag, &answer[..]) {
+        (ACCEPTED, [agreed, rest @ ..]) if rest.len() >= LIMITS_LEN + PUBLIC_KEY_LEN => {
+            let (max_size, stream_threshold, accepts, rest) = read_limits(rest);
+            let (server_public, peer) = read_public(rest);
+            (
+                *agreed,
+                (max_size, stream_threshold, accepts),
+                server_public,
+                peer_name(peer),
+            )
+        }
+        (REJECTED, _) => {
+            // It doesn't say why, and any of them will be refused the same way next time
+            return Err(ClipshareError::auth(
+                "The server rejected the connection, check the key, the room and --encrypt",
+            ));
+        }
+        _ => return Err(ClipshareError::protocol("Malformed handshake answer")),
+    };
+    if agreed & protocol::ENCRYPTED != protocol.flags() & protocol::ENCRYPTED {
+        return Err(ClipshareError::handshake(
+            "Encryption mismatch, enable --encrypt on both ends",
+        ));
+    }
+    let (max_size, stream_threshold, accepts) = limits;
+    let protocol = protocol.negotiate(agreed, max_size, stream_threshold, accepts);
+    let protocol = if agreed & protocol::ENCRYPTED != 0 {
+        let shared = ephemeral.agree(&server_public)?;
+        protocol.encrypted(Cipher::session(
+            key,
+            &shared,
+            &client_public,
+            &server_public,
+        ))
+    } else {
+        protocol
+    };
+    debug!(
+        flags = protocol.flags(),
+        max_size = protocol.max_size(),
+        stream_threshold = protocol.stream_threshold(),
+        "Negotiated session"
+    );
+    Span::current().record("peer", &peer);
+
+    Ok(Joined {
+        protocol,
+        name: peer,
+    })
+}
+
+/// The largest payload `protocol` accepts, the largest object it sends in a single frame and
+/// the kinds of objects it accepts.
+fn limits(protocol: &Protocol) -> [u8; LIMITS_LEN] {
+    // Never more than `MAX_STREAM_THRESHOLD`, which fits
+    let stream_threshold = protocol.stream_threshold() as u32;
+    [
+        &protocol.max_size().to_be_bytes()[..],
+        &stream_threshold.to_be_bytes(),
+        &protocol.accepted_kinds().to_be_bytes(),
+    ]
+    .concat()
+    .try_into()
+    .expect("the limits are LIMITS_LEN long")
+}
+
+/// Splits the largest payload a peer accepts, the largest object it sends in a single frame and
+/// the kinds of objects it accepts off the start of `body`, which is long enough.
+fn read_limits(body: &[u8]) -> (u64, usize, u16, &[u8]) {
+    let (max_size, rest) = body.split_at(mem::size_of::<u64>());
+    let (stream_threshold, rest) = rest.split_at(mem::size_of::<u32>());
+    let (accepts, rest) = rest.split_at(mem::size_of::<u16>());
+    let max_size = u64::from_be_bytes(max_size.try_into().expect("the length was checked"));
+    let stream_threshold =
+        u32::from_be_bytes(stream_threshold.try_into().expect("the length was checked"));
+    let accepts = u16::from_be_bytes(accepts.try_into().expect("the length was checked"));
+    (max_size, stream_threshold as usize, accepts, rest)
+}
+
+/// Splits an ephemeral public key off the start of `body`, which is long enough.
+fn read_public(body: &[u8]) -> ([u8; PUBLIC_KEY_LEN], &[u8]) {
+    let (public, rest) = body.split_at(PUBLIC_KEY_LEN);
+    (public.try_into().expect("the length was checked"), rest)
+}
+
+/// The name a peer sent, without anything that would garble the logs or a terminal.
+fn peer_name(name: &[u8]) -> String {
+    String::from_utf8_lossy(name)
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect()
+}
+
+async fn reject<T>(
+    mut writer: impl AsyncWrite + Send + Unpin,
+    reason: ClipshareError,
+) -> Result<T, ClipshareError> {
+    codec::write_message(&mut writer, REJECTED, &[]).await?;
+    writer.shutdown().await?;
+    Err(reason)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use tokio::{
+        io::{duplex, split},
+        time::timeout,
+    };
+
+    use super::*;
+
+    #[tokio::test]
+    async fn absurd_response_length_is_refused() {
+        let (server_stream, mut client) = duplex(1024);
+        let (reader, writer) = split(server_stream);
+        let (keys, protocol) = (Keys::single("key"), Protocol::new(1 << 20));
+        let serving = server(reader, writer, &keys, None, "server", &protocol, None);
+        let answering = async {
+            protocol::write_hello(&mut client).await.unwrap();
+            protocol::read_hello(&mut client).await.unwrap();
+            codec::read_message(&mut client, CHALLENGE_LEN as u64, "Handshake challenge")
+                .await
+                .unwrap();
+            // Far more than any answer takes, with nothing following it
+            codec::write_header(&mut client, RESPONSE, usize::MAX)
+                .await
+                .unwrap();
+        };
+        let (result, ()) = tokio::join!(timeout(Duration::from_secs(5), serving), answering);
+        let result = result.expect("the length alone was refused");
+        assert!(matches!(
+            result,
+            Err(ClipshareError::TooLarge { ref what, size: u64::MAX, .. })
+                if what == "Handshake response"
+        ));
+    }
+}
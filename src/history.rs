@@ -0,0 +1,127 @@
+use std::{collections::VecDeque, sync::Arc};
+
+use tokio::io::{stdin, AsyncBufReadExt, BufReader};
+use tracing::{trace, warn};
+
+use crate::{
+    backend::ClipboardBackend,
+    clipboard::{Clipboard, ClipboardObject},
+};
+
+/// Ring buffer of the last distinct clipboard objects, capped by count and total size.
+#[derive(Debug)]
+pub struct History {
+    entries: VecDeque<(u64, Arc<ClipboardObject>)>,
+    len: usize,
+    max_size: usize,
+    size: usize,
+}
+
+impl History {
+    pub fn new(len: usize, max_size: usize) -> Self {
+        Self {
+            entries: VecDeque::new(),
+            len,
+            max_size,
+            size: 0,
+        }
+    }
+
+    /// Records `obj` as the most recent entry, moving it to the front if it was already there
+    /// and evicting the oldest entries past the limits.
+    pub fn push(&mut self, obj: Arc<ClipboardObject>) {
+        let digest = obj.digest();
+        if let Some(index) = self.entries.iter().position(|&(d, _)| d == digest) {
+            if let Some((_, old)) = self.entries.remove(index) {
+                self.size -= old.size();
+            }
+        }
+
+        if obj.size() > self.max_size {
+            trace!(
+                size = obj.size(),
+                "Not keeping clipboard object over the history size"
+            );
+            return;
+        }
+
+        self.size += obj.size();
+        self.entries.push_front((digest, obj));
+
+        while self.entries.len() > self.len || self.size > self.max_size {
+            match self.entries.pop_back() {
+                Some((_, old)) => self.size -= old.size(),
+                None => break,
+            }
+        }
+    }
+
+    /// Entries from the most recent to the oldest.
+    pub fn entries(&self) -> impl Iterator<Item = &Arc<ClipboardObject>> {
+        self.entries.iter().map(|(_, obj)| obj)
+    }
+}
+
+/// Reads commands from stdin to list and recall history entries, until stdin is closed.
+pub async fn prompt<B: ClipboardBackend>(clipboard: Arc<Clipboard<B>>) {
+    eprintln!("Type `h` to list the clipboard history, or an entry number to copy it again");
+
+    let mut lines = BufReader::new(stdin()).lines();
+    loop {
+        let line = match lines.next_line().await {
+            Ok(Some(line)) => line,
+            Ok(None) => break,
+            Err(err) => {
+                warn!(error = %err, "Failed to read from stdin, disabling the history prompt");
+                break;
+            }
+        };
+
+        match line.trim() {
+            "" => {}
+            "h" | "history" => {
+                let history = clipboard.history();
+                if history.is_empty() {
+                    eprintln!("The clipboard history is empty");
+                }
+                for (index, obj) in history.iter().enumerate() {
+                    eprintln!("{index:>3}: {}", summary(obj));
+                }
+            }
+            command => match command.parse() {
+                Ok(index) => match clipboard.recall(index).await {
+                    Ok(()) => eprintln!("Copied history entry {index}"),
+                    Err(err) => eprintln!("{err}"),
+                },
+                Err(_) => eprintln!("Unknown command `{command}`, type `h` to list the history"),
+            },
+        }
+    }
+}
+
+/// One line description of a clipboard object.
+pub fn summary(obj: &ClipboardObject) -> String {
+    const MAX_CHARS: usize = 60;
+
+    match *obj {
+        ClipboardObject::Text(ref text)
+        | ClipboardObject::Primary(ref text)
+        | ClipboardObject::Html { ref text, .. } => {
+            let line = text.split_whitespace().collect::<Vec<_>>().join(" ");
+            if line.chars().count() > MAX_CHARS {
+                format!("{}…", line.chars().take(MAX_CHARS).collect::<String>())
+            } else {
+                line
+            }
+        }
+        ClipboardObject::Image(ref img) => format!("[image {}x{}]", img.width, img.height),
+        ClipboardObject::Files(ref files) => {
+            let names = files
+                .iter()
+                .map(|file| file.name.as_str())
+                .collect::<Vec<_>>();
+            format!("[{} files] {}", files.len(), names.join(", "))
+        }
+        ClipboardObject::Clear => "[cleared]".to_string(),
+    }
+}
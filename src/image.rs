@@ -0,0 +1,144 @@
+//! Making copied images cheaper to sync: downscaling large ones and encoding them as PNG.
+
+use std::{borrow::Cow, io::Cursor};
+
+use arboard::ImageData;
+
+use crate::error::ClipshareError;
+
+/// How images are encoded on the wire, picked by the sender.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ImageFormat {
+    /// The RGBA pixels as they are, no work for either end but the most bytes.
+    #[default]
+    Raw,
+    /// Lossless PNG, often a fraction of the size for screenshots.
+    Png,
+}
+
+/// What happens to images larger than a maximum dimension before they are synced.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageLimit {
+    /// Longest side allowed, in pixels.
+    pub max_dimension: usize,
+    /// Leave larger images out entirely, instead of downscaling them.
+    pub reject: bool,
+}
+
+impl ImageLimit {
+    /// `img` made to fit the limit, or `None` when it is too large and rejected.
+    pub fn apply(&self, img: ImageData<'static>) -> Option<ImageData<'static>> {
+        if img.width.max(img.height) <= self.max_dimension {
+            return Some(img);
+        }
+        if self.reject {
+            return None;
+        }
+        // Pixels that don't add up are the receiver's problem, as they would be unscaled
+        if img.bytes.len() != img.width * img.height * 4 {
+            return Some(img);
+        }
+        Some(downscale(&img, self.max_dimension.max(1)))
+    }
+}
+
+/// Shrinks `img` so its longest side is `max_dimension` pixels, keeping its aspect ratio. Every
+/// pixel is the average of the ones it covers.
+fn downscale(img: &ImageData, max_dimension: usize) -> ImageData<'static> {
+    let longest = img.width.max(img.height);
+    let scale = |len: usize| (len * max_dimension / longest).max(1);
+    let (width, height) = (scale(img.width), scale(img.height));
+    // The source pixels covered by destination pixel `index` of `len`, out of `source` of them
+    let span = |index: usize, len: usize, source: usize| {
+        let start = index * source / len;
+        start..((index + 1) * source / len).max(start + 1)
+    };
+
+    let mut bytes = Vec::with_capacity(width * height * 4);
+    for y in 0..height {
+        let rows = span(y, height, img.height);
+        for x in 0..width {
+            let columns = span(x, width, img.width);
+            let mut sum = [0u64; 4];
+            for row in rows.clone() {
+                let start = (row * img.width + columns.start) * 4;
+                let end = (row * img.width + columns.end) * 4;
+                for pixel in img.bytes[start..end].chunks_exact(4) {
+                    for (sum, &channel) in sum.iter_mut().zip(pixel) {
+                        *sum += u64::from(channel);
+                    }
+                }
+            }
+            let count = (rows.len() * columns.len()) as u64;
+            bytes.extend(sum.map(|sum| (sum / count) as u8));
+        }
+    }
+
+    ImageData {
+        width,
+        height,
+        bytes: Cow::from(bytes),
+    }
+}
+
+/// Encodes the RGBA pixels of `img` as a PNG.
+pub fn encode_png(img: &ImageData) -> Result<Vec<u8>, ClipshareError> {
+    let invalid = |err: png::EncodingError| {
+        ClipshareError::protocol(format!("Could not encode image as PNG: {err}"))
+    };
+    let too_large = |_| ClipshareError::protocol("Image is too large to encode as PNG");
+    let mut png = Vec::new();
+    let mut encoder = png::Encoder::new(
+        &mut png,
+        img.width.try_into().map_err(too_large)?,
+        img.height.try_into().map_err(too_large)?,
+    );
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    let mut writer = encoder.write_header().map_err(invalid)?;
+    writer.write_image_data(&img.bytes).map_err(invalid)?;
+    writer.finish().map_err(invalid)?;
+    Ok(png)
+}
+
+/// Decodes a PNG made by [`encode_png`] back into RGBA pixels, refusing one that would take more
+/// than `max_size` bytes once decoded.
+pub fn decode_png(png: &[u8], max_size: u64) -> Result<ImageData<'static>, ClipshareError> {
+    let invalid =
+        |err: png::DecodingError| ClipshareError::protocol(format!("Invalid PNG image: {err}"));
+    let limits = png::Limits {
+        bytes: max_size.try_into().unwrap_or(usize::MAX),
+    };
+    let mut reader = png::Decoder::new_with_limits(Cursor::new(png), limits)
+        .read_info()
+        .map_err(invalid)?;
+    let info = reader.info();
+    if info.color_type != png::ColorType::Rgba || info.bit_depth != png::BitDepth::Eight {
+        return Err(ClipshareError::protocol(
+            "Only 8-bit RGBA PNG images are supported",
+        ));
+    }
+    let decoded = u64::from(info.width) * u64::from(info.height) * 4;
+    if decoded > max_size {
+        return Err(ClipshareError::too_large(
+            "Decoded PNG image",
+            decoded,
+            max_size,
+        ));
+    }
+    // Both fit in a `u32`, which fits in a `usize` on every platform arboard supports
+    let (width, height) = (info.width as usize, info.height as usize);
+
+    let mut bytes = vec![
+        0;
+        reader
+            .output_buffer_size()
+            .ok_or(ClipshareError::protocol("PNG image is too large"))?
+    ];
+    reader.next_frame(&mut bytes).map_err(invalid)?;
+    Ok(ImageData {
+        width,
+        height,
+        bytes: Cow::from(bytes),
+    })
+}
@@ -0,0 +1,23 @@
+//! Clipboard sharing between machines on a local network.
+//!
+//! [`session::ClipshareSession`] runs the clipshare protocol over any stream, syncing a
+//! [`clipboard::Clipboard`] with the peer on the other end.
+
+pub mod backend;
+pub mod clipboard;
+pub mod codec;
+pub mod confirm;
+pub mod crypto;
+pub mod discovery;
+pub mod error;
+pub mod filter;
+pub mod handshake;
+pub mod history;
+pub mod image;
+pub mod pairing;
+pub mod protocol;
+pub mod relay;
+pub mod session;
+pub mod state;
+pub mod tls;
+pub mod ws;
@@ -1,194 +1,2372 @@
-use crate::clipboard::Clipboard;
-use clap::{command, Parser};
-use clipboard::ClipboardObject;
-use std::{error::Error, sync::Arc};
+use clap::{ArgAction, CommandFactory, Parser, Subcommand, ValueEnum};
+use clap_complete::Shell;
+use clipshare::{
+    backend::{self, ClipboardBackend, MemoryClipboard, StdioClipboard},
+    clipboard::{self, Clipboard, ClipboardKind, ClipboardObject, Origin, Selection},
+    confirm::Confirm,
+    crypto::Cipher,
+    discovery,
+    error::ClipshareError,
+    filter::Filter,
+    handshake::{self, Keys},
+    history,
+    image::{ImageFormat, ImageLimit},
+    pairing::{Paired, Pairing},
+    protocol::{self, Protocol},
+    relay::Relay,
+    session::{ClipshareSession, Connection, Direction, SessionOptions},
+    state, tls, ws,
+};
+use config::{Config, Urls};
+use events::Event;
+use ipnet::IpNet;
+use paired::PairedMachines;
+use regex::Regex;
+use serde::Deserialize;
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    io::{self, IsTerminal},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, PoisonError,
+    },
+    time::Duration,
+};
 use tokio::{
-    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
-    net::{TcpListener, TcpStream},
+    io::{AsyncRead, AsyncWrite},
+    net::{lookup_host, TcpListener, TcpStream},
     select,
+    sync::{mpsc, Semaphore},
+    task::JoinSet,
+    time::{sleep, timeout},
 };
-use tracing::{debug, error_span, info, instrument, trace, Instrument, Level};
-use tracing_subscriber::FmtSubscriber;
+use tokio_rustls::{TlsAcceptor, TlsConnector};
+use tracing::{debug, error, error_span, field, info, instrument, trace, warn, Instrument, Level};
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::{
+    field::RecordFields,
+    fmt::{
+        self,
+        format::{DefaultFields, Writer},
+        writer::BoxMakeWriter,
+        FormatFields,
+    },
+    layer::SubscriberExt,
+    EnvFilter, Layer, Registry,
+};
+
+mod audit;
+mod config;
+mod events;
+mod metrics;
+mod notify;
+mod paired;
+mod progress;
+mod qr;
 
-mod clipboard;
+/// The system clipboard, one in memory, or stdin and stdout with `--stdio`.
+type Backend = Box<dyn ClipboardBackend>;
+
+/// Where the local clipboard lives, picked with `--backend`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum BackendKind {
+    /// The clipboard of the desktop session
+    #[default]
+    System,
+    /// A clipboard kept in memory, to relay between peers on a host without a display
+    Memory,
+}
+
+/// Which selections are synced, picked with `--selection`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum SelectionKind {
+    /// What is copied with Ctrl-C
+    #[default]
+    Clipboard,
+    /// What is selected and pasted with a middle click, on X11 and Wayland
+    Primary,
+    /// Both, each synced on its own
+    Both,
+}
+
+/// How the connections are carried, picked with `--transport`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum TransportKind {
+    /// Plain TCP, or TLS with `--tls`
+    #[default]
+    Tcp,
+    /// A WebSocket, over TLS with `--tls`
+    Ws,
+    /// A WebSocket over TLS, like `ws` with `--tls`
+    Wss,
+}
+
+/// How images are encoded on the wire, picked with `--image-format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ImageFormatKind {
+    /// The RGBA pixels as they are
+    #[default]
+    Raw,
+    /// Lossless PNG, much smaller for screenshots but slower to encode
+    Png,
+}
+
+/// Which clipboards are cleared on start, picked with `--clear-on-start`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum ClearOnStart {
+    /// None, what was copied before gets synced
+    Never,
+    /// The one of this machine
+    #[default]
+    Local,
+    /// The one of this machine, and the ones of the peers when they connect
+    Both,
+}
+
+/// How log lines are written, picked with `--log-format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, ValueEnum, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+enum LogFormat {
+    /// Human readable lines, colored on the console
+    #[default]
+    Text,
+    /// One JSON object per line, with the fields of the event and of its spans
+    Json,
+}
+
+const DEFAULT_KEY: &str = "clipshare";
+const DEFAULT_PORT: u16 = 11337;
+const DEFAULT_MAX_FILE_SIZE: u64 = 16 * 1024 * 1024;
+const DEFAULT_MAX_SIZE: u64 = 64 * 1024 * 1024;
+const HISTORY_MAX_SIZE: usize = 32 * 1024 * 1024;
+const DEFAULT_KEEPALIVE: u64 = 15;
+const DEFAULT_HEARTBEAT: u64 = 15;
+const DEFAULT_MAX_CONNECTIONS: usize = 256;
+/// Daily log files kept with `--log-file`.
+const LOG_FILES: usize = 7;
+/// How long the code shown with `--pair` can be tried.
+const PAIRING_LIFETIME: Duration = Duration::from_secs(5 * 60);
+const INITIAL_BACKOFF: Duration = Duration::from_secs(1);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
 
 #[derive(Parser)]
-#[command(author, version, about, long_about = None)]
+#[command(author, version, about, long_about = None, args_conflicts_with_subcommands = true)]
 struct Cli {
-    /// Server port
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Options given without a subcommand, deprecated: connects when there is a server url or
+    /// `--discover`, serves otherwise
+    #[command(flatten)]
+    args: Args,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Run a server the other machines connect to
+    Serve(Args),
+    /// Connect to servers, given by their urls, or one found with `--discover`
+    Connect {
+        /// Server url, like `192.168.0.10:11337`, `[fe80::1]:11337` for IPv6 or `desktop` for the
+        /// default port, several of them to sync with all at once
+        #[arg(value_name = "URL", conflicts_with = "url")]
+        servers: Vec<String>,
+
+        #[command(flatten)]
+        args: Args,
+    },
+    /// Pair with a server running with `--pair` through the code it shows, so both remember a
+    /// key to connect with
+    Pair {
+        /// Server url, like `192.168.0.10:11337`, the same one later given to `connect`
+        #[arg(value_name = "URL", conflicts_with = "url")]
+        server: String,
+
+        /// Code shown by the server, asked for when omitted
+        #[arg(long)]
+        code: Option<String>,
+
+        #[command(flatten)]
+        args: Args,
+    },
+    /// Print the completion script for `shell` on stdout
+    #[command(hide = true)]
+    Completions { shell: Shell },
+}
+
+/// Whether to run a server or connect to one.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Mode {
+    Serve,
+    Connect,
+    /// Connect once, only to pair with the server.
+    Pair,
+    /// Picked from the options, as before there were subcommands.
+    Legacy,
+}
+
+impl Cli {
+    fn into_args(self) -> (Mode, Args) {
+        match self.command {
+            Some(Command::Serve(args)) => (Mode::Serve, args),
+            Some(Command::Connect { servers, args }) if !servers.is_empty() => (
+                Mode::Connect,
+                Args {
+                    url: servers,
+                    ..args
+                },
+            ),
+            Some(Command::Connect { args, .. }) => (Mode::Connect, args),
+            Some(Command::Pair { server, args, .. }) => (
+                Mode::Pair,
+                Args {
+                    url: vec![server],
+                    ..args
+                },
+            ),
+            None => (Mode::Legacy, self.args),
+            Some(Command::Completions { .. }) => {
+                unreachable!("completions are printed before looking at the options")
+            }
+        }
+    }
+}
+
+#[derive(clap::Args)]
+struct Args {
+    /// Server port (defaults to 11337)
     #[arg(short, long)]
     port: Option<u16>,
 
-    /// Remote server url
+    /// Listen on a port picked by the system when the one given with `--port` is taken, instead
+    /// of failing
+    #[arg(long)]
+    port_fallback: bool,
+
+    /// Address the server listens on, IPv4 or IPv6 (defaults to all IPv4 interfaces)
+    #[arg(short, long)]
+    bind: Option<IpAddr>,
+
+    /// Listen on this Unix socket instead of a TCP port, for peers on this machine only
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["port", "port_fallback", "bind"])]
+    socket: Option<PathBuf>,
+
+    /// Remote server url, like `192.168.0.10:11337`, `[fe80::1]:11337` for IPv6, a host name
+    /// with the port left out when it's 11337 or `unix:/path` for a Unix socket, can be repeated
+    /// to sync with several servers at once
     #[arg(short, long)]
-    url: Option<String>,
+    url: Vec<String>,
+
+    /// Find the server on the local network through mDNS, optionally by its instance name
+    #[arg(long, conflicts_with = "url")]
+    discover: Option<Option<String>>,
+
+    /// Find the server on the local network through a UDP broadcast, for networks without mDNS
+    #[arg(long, conflicts_with_all = ["url", "discover"])]
+    discover_broadcast: bool,
+
+    /// Seconds to wait for a server to be discovered
+    #[arg(long)]
+    discover_timeout: Option<u64>,
+
+    /// Only accept connections from this IP or CIDR range, can be repeated (defaults to anyone)
+    #[arg(long, value_name = "IP", value_parser = parse_allow)]
+    allow: Vec<IpNet>,
+
+    /// Most connections the server handles at once, the ones beyond it being closed right away
+    /// (256 by default)
+    #[arg(long)]
+    max_connections: Option<usize>,
+
+    /// Don't advertise the server through mDNS
+    #[arg(long)]
+    no_advertise: bool,
+
+    /// Answer the UDP broadcast probes of `--discover-broadcast` clients
+    #[arg(long)]
+    broadcast: bool,
+
+    /// Show the command to connect to this server as a QR code, for another device to scan
+    #[arg(long, conflicts_with_all = ["url", "discover", "discover_broadcast"])]
+    qr: bool,
+
+    /// Clipboards to clear on start, so nothing copied before syncs: `never`, `local` or `both`,
+    /// which clears the ones of the peers too when connecting (defaults to `local`)
+    #[arg(long, value_enum, conflicts_with_all = ["no_clear", "clear_remote"])]
+    clear_on_start: Option<ClearOnStart>,
 
-    /// Don´t clear the clipboard on start
+    /// Don´t clear the clipboard on start, like `--clear-on-start never`
     #[arg(long)]
     no_clear: bool,
 
-    /// Key
+    /// Clear the clipboard of the peers too when connecting, like `--clear-on-start both`
+    #[arg(long, conflicts_with_all = ["no_clear", "once", "relay"])]
+    clear_remote: bool,
+
+    /// Connect and go through every rule, but only log what would be sent to the peers and
+    /// applied here, leaving both clipboards alone. For trying out options like `--include`
+    #[arg(long, conflicts_with_all = ["clear_remote", "state_file"])]
+    dry_run: bool,
+
+    /// Sync copied files, sending their contents to the peer
+    #[arg(long)]
+    sync_files: bool,
+
+    /// Selections to sync: `clipboard`, `primary` or `both`, the primary selection only being
+    /// there on X11 and Wayland
+    #[arg(long, value_enum)]
+    selection: Option<SelectionKind>,
+
+    /// Sync what a password manager copied too, instead of keeping it on this machine
+    #[arg(long)]
+    sync_sensitive: bool,
+
+    /// Send empty text too, which some applications set for a moment, instead of skipping it
+    #[arg(long)]
+    sync_empty: bool,
+
+    /// Only send copied text matching this regex, can be repeated to send text matching any
+    #[arg(long, value_name = "REGEX", value_parser = parse_regex)]
+    include: Vec<Regex>,
+
+    /// Never send copied text matching this regex, even when it matches `--include`, can be
+    /// repeated
+    #[arg(long, value_name = "REGEX", value_parser = parse_regex)]
+    exclude: Vec<Regex>,
+
+    /// Largest total size in bytes of copied files that will be synced (defaults to 16 MiB)
+    #[arg(long)]
+    max_file_size: Option<u64>,
+
+    /// Downscale copied images with a side longer than this many pixels before syncing them
+    #[arg(long, value_name = "PIXELS")]
+    max_image_dimension: Option<usize>,
+
+    /// Don't sync images over `--max-image-dimension` at all, instead of downscaling them
+    #[arg(long, requires = "max_image_dimension")]
+    reject_large_images: bool,
+
+    /// How images are sent: `raw` pixels or `png` (defaults to raw)
+    #[arg(long, value_enum)]
+    image_format: Option<ImageFormatKind>,
+
+    /// Largest clipboard object in bytes accepted from the peer (defaults to 64 MiB). Both ends
+    /// go by the smaller of theirs, and larger objects aren't sent at all
+    #[arg(long)]
+    max_size: Option<u64>,
+
+    /// Largest clipboard object in bytes sent in a single frame, larger ones being streamed in
+    /// chunks that can be given up on halfway and show their progress (defaults to 64 KiB). Both
+    /// ends go by the smaller of theirs
+    #[arg(long, value_name = "SIZE")]
+    stream_threshold: Option<usize>,
+
+    /// Kinds of clipboard objects taken from the peer, comma separated among `text`, `image`,
+    /// `files`, `clear`, `primary` and `html` (defaults to all of them, leaving files out without
+    /// `--sync-files`). The peer is told in the handshake and sends HTML as plain text when only
+    /// that is accepted, and nothing for the other kinds
+    #[arg(long, value_name = "KINDS", value_delimiter = ',')]
+    accept: Vec<ClipboardKind>,
+
+    /// Ask on the terminal before applying received files, and objects larger than this many
+    /// bytes, discarding them unless confirmed
+    #[arg(long, value_name = "SIZE")]
+    confirm_over: Option<u64>,
+
+    /// Apply what `--confirm-over` would ask about when stdin isn't a terminal, instead of
+    /// discarding it
+    #[arg(long, requires = "confirm_over")]
+    auto_accept: bool,
+
+    /// Key, visible to other users in the process list, prefer `--key-file` or `--key-stdin`.
+    /// A server accepts every one given
     #[arg(short, long)]
-    key: Option<String>,
+    key: Vec<String>,
+
+    /// TOML file of `name = "key"` entries, all of them accepted by the server
+    #[arg(long)]
+    keys_file: Option<PathBuf>,
+
+    /// Only sync with peers that joined this room, so separate groups sharing a server or a
+    /// relay don't see each other's clipboards
+    #[arg(long)]
+    room: Option<String>,
+
+    /// Name this machine goes by in the logs and notifications of its peers (defaults to the
+    /// hostname)
+    #[arg(long)]
+    name: Option<String>,
+
+    /// Read the key from the first line of this file
+    #[arg(long, conflicts_with = "key_stdin")]
+    key_file: Option<PathBuf>,
+
+    /// Read the key from stdin, prompting for it when stdin is a terminal
+    #[arg(long)]
+    key_stdin: bool,
+
+    /// Show a code a client can pair with through `clipshare pair`, instead of being given the
+    /// key, tried at most once within 5 minutes
+    #[arg(long, conflicts_with_all = ["url", "discover", "discover_broadcast", "once", "relay"])]
+    pair: bool,
+
+    /// Encrypt the connection with TLS
+    #[arg(long)]
+    tls: bool,
+
+    /// Carry the connection over `tcp`, or a WebSocket with `ws` and `wss`, for networks that
+    /// only let HTTP through (defaults to tcp)
+    #[arg(long, value_enum)]
+    transport: Option<TransportKind>,
+
+    /// TLS certificate (PEM) used by the server, a self-signed one is generated when omitted
+    #[arg(long, requires = "tls_key")]
+    tls_cert: Option<PathBuf>,
+
+    /// TLS private key (PEM) used by the server
+    #[arg(long, requires = "tls_cert")]
+    tls_key: Option<PathBuf>,
+
+    /// SHA-256 fingerprint of the server certificate the client expects
+    #[arg(long)]
+    tls_pin: Option<String>,
+
+    /// Encrypt clipboard contents with a cipher derived from the key
+    #[arg(long)]
+    encrypt: bool,
+
+    /// Refuse to start unless the connection is encrypted with `--encrypt`, `--tls` or
+    /// `--transport wss`, so nothing is ever synced in the clear. Peers that don't encrypt the
+    /// same way are then rejected in the handshake
+    #[arg(long)]
+    require_encryption: bool,
+
+    /// Compress clipboard contents with zstd, when the peer supports it too
+    #[arg(long)]
+    compress: bool,
+
+    /// Replace invalid UTF-8 in received text instead of ignoring that text
+    #[arg(long)]
+    lossy: bool,
+
+    /// Only send the local clipboard to the peer, ignoring what it copies
+    #[arg(long, conflicts_with = "recv_only")]
+    send_only: bool,
+
+    /// Only apply what the peer copies, never sending the local clipboard
+    #[arg(long)]
+    recv_only: bool,
+
+    /// Sync a single clipboard with the server then exit: with --recv-only apply the last one it
+    /// synced, with --send-only send the local one
+    #[arg(long)]
+    once: bool,
+
+    /// Use stdin and stdout instead of the system clipboard: send what is piped in, print what
+    /// is received
+    #[arg(long, conflicts_with_all = ["key_stdin", "history", "backend"])]
+    stdio: bool,
+
+    /// Clipboard to sync: `system` or `memory`, which needs no display server
+    #[arg(long, value_enum)]
+    backend: Option<BackendKind>,
+
+    /// Run a server without a clipboard of its own, forwarding what a client copies to the
+    /// other clients using the same key
+    #[arg(long, conflicts_with_all = ["url", "discover", "discover_broadcast", "once", "stdio", "backend", "history", "notify"])]
+    relay: bool,
+
+    /// Convert the line endings of received text to the ones of this platform
+    #[arg(long)]
+    normalize_eol: bool,
+
+    /// Drop a single trailing newline from received text
+    #[arg(long)]
+    trim: bool,
+
+    /// Minimum milliseconds between two clipboard updates sent to the peer, changes made in
+    /// between are coalesced into the latest one (defaults to 200)
+    #[arg(long)]
+    min_interval_ms: Option<u64>,
+
+    /// Received clipboards waiting to be written to the local one before reading more from the
+    /// peer, only the latest of them is applied (defaults to 4)
+    #[arg(long, value_name = "N")]
+    recv_queue: Option<usize>,
+
+    /// Milliseconds between two reads of the local clipboard, where the platform can't tell when
+    /// it changes, right after it did (defaults to 1000)
+    #[arg(long)]
+    poll_interval_ms: Option<u64>,
+
+    /// Milliseconds the wait between two reads of the local clipboard grows to while it doesn't
+    /// change, going back to `--poll-interval-ms` once it does (defaults to 5000)
+    #[arg(long)]
+    idle_poll_interval_ms: Option<u64>,
+
+    /// Milliseconds a read of the local clipboard may take before it is given up on until the
+    /// next one, when an application holding it doesn't answer (defaults to 2000)
+    #[arg(long)]
+    paste_timeout_ms: Option<u64>,
+
+    /// Show a desktop notification whenever a clipboard is received
+    #[arg(long)]
+    notify: bool,
+
+    /// Keep the last N distinct clipboard entries, listed and recalled from stdin
+    #[arg(long, value_name = "N")]
+    history: Option<usize>,
+
+    /// Save the clipboard to this file on every change, and restore it from there on start
+    /// unless something newer is already on the clipboard
+    #[arg(long, value_name = "PATH", conflicts_with_all = ["once", "relay", "clear_remote"])]
+    state_file: Option<PathBuf>,
+
+    /// Seconds of silence before probing whether the peer is still there, 0 disables it
+    /// (defaults to 15)
+    #[arg(long)]
+    keepalive_secs: Option<u64>,
+
+    /// Seconds without sending anything before pinging the peer, which is dropped after three
+    /// of them without a word from it, 0 disables it. Both ends must agree (defaults to 15)
+    #[arg(long)]
+    heartbeat_secs: Option<u64>,
+
+    /// Seconds a peer has to complete the handshake before it is dropped (defaults to 5)
+    #[arg(long)]
+    handshake_timeout: Option<u64>,
+
+    /// How many times the client retries to connect before giving up, 0 retries forever. A
+    /// client the server rejects for its key gives up right away
+    #[arg(long)]
+    max_retries: Option<u32>,
+
+    /// Log a preview of every clipboard synced, which may contain passwords (debug level)
+    #[arg(long)]
+    log_content: bool,
+
+    /// Append a JSON line for every clipboard sent, received or declined to this file, with the
+    /// time, peer, type and size of the clipboard but not its content
+    #[arg(long, value_name = "PATH")]
+    audit_log: Option<PathBuf>,
+
+    /// Put a preview of every clipboard in the audit log too, which may contain passwords
+    #[arg(long, requires = "audit_log")]
+    audit_content: bool,
+
+    /// Print connection events on stdout as JSON lines, logs go to stderr instead
+    #[arg(long)]
+    json_events: bool,
+
+    /// Serve counters of connections and synced clipboards at `/metrics` on this address, for
+    /// Prometheus to scrape
+    #[arg(long, value_name = "ADDR")]
+    metrics_addr: Option<SocketAddr>,
+
+    /// Show a progress bar on stderr while large clipboards are sent or received
+    #[arg(long)]
+    progress: bool,
+
+    /// Log level (trace, debug, info, warn or error), `CLIPSHARE_LOG`/`RUST_LOG` are used when omitted
+    #[arg(long)]
+    log_level: Option<Level>,
+
+    /// Log more, up to trace with `-vv` from info: one level over `--log-level` for every `-v`
+    #[arg(short, long, action = ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Log less, down to errors only: one level under `--log-level` for every `-q`
+    #[arg(short, long, action = ArgAction::Count)]
+    quiet: u8,
+
+    /// How log lines are written, to the console and to `--log-file` (defaults to text)
+    #[arg(long, value_enum)]
+    log_format: Option<LogFormat>,
+
+    /// Also write the logs to this file, starting a new one every day named after the date and
+    /// keeping the last week of them
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+
+    /// Only write the logs to `--log-file`, not to the console
+    #[arg(long, requires = "log_file")]
+    no_console_log: bool,
+
+    /// Config file, defaults to `clipshare/config.toml` in the user config dir
+    #[arg(long)]
+    config: Option<PathBuf>,
+}
+
+impl Args {
+    /// Fills in every option not given on the command line from the config file, checking they
+    /// make sense for `mode`.
+    fn merge(self, config: Config, mode: Mode) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        if mode == Mode::Serve
+            && (!self.url.is_empty() || self.discover.is_some() || self.discover_broadcast)
+        {
+            return Err(
+                "serve doesn't connect to a server, use `clipshare connect` instead".into(),
+            );
+        }
+        let log_level = config.log_level()?;
+        let allow = if self.allow.is_empty() {
+            config
+                .allow
+                .iter()
+                .flatten()
+                .map(|allow| {
+                    parse_allow(allow)
+                        .map_err(|err| format!("Invalid allow in the config file: {err}"))
+                })
+                .collect::<Result<_, _>>()?
+        } else {
+            self.allow
+        };
+        let include = if self.include.is_empty() {
+            config
+                .include
+                .iter()
+                .flatten()
+                .map(|include| {
+                    parse_regex(include)
+                        .map_err(|err| format!("Invalid include in the config file: {err}"))
+                })
+                .collect::<Result<_, _>>()?
+        } else {
+            self.include
+        };
+        let exclude = if self.exclude.is_empty() {
+            config
+                .exclude
+                .iter()
+                .flatten()
+                .map(|exclude| {
+                    parse_regex(exclude)
+                        .map_err(|err| format!("Invalid exclude in the config file: {err}"))
+                })
+                .collect::<Result<_, _>>()?
+        } else {
+            self.exclude
+        };
+        // Any of the clear options on the command line wins over all of them in the config file
+        let clear_given = self.clear_on_start.is_some() || self.no_clear || self.clear_remote;
+        let args = Self {
+            port: self.port.or(config.port),
+            port_fallback: self.port_fallback || config.port_fallback.unwrap_or_default(),
+            bind: self.bind.or(config.bind),
+            socket: self.socket.or(config.socket),
+            // A url in the config file is for connecting, serving ignores it
+            url: match mode {
+                Mode::Serve => Vec::new(),
+                Mode::Connect | Mode::Legacy if self.url.is_empty() => {
+                    config.url.map(Urls::into_vec).unwrap_or_default()
+                }
+                Mode::Connect | Mode::Legacy | Mode::Pair => self.url,
+            },
+            allow,
+            max_connections: self.max_connections.or(config.max_connections),
+            discover_timeout: self.discover_timeout.or(config.discover_timeout),
+            no_advertise: self.no_advertise || config.no_advertise.unwrap_or_default(),
+            broadcast: self.broadcast || config.broadcast.unwrap_or_default(),
+            qr: self.qr || config.qr.unwrap_or_default(),
+            // The shorthands of the command line win over `clear-on-start` in the config file
+            clear_on_start: self
+                .clear_on_start
+                .or(self.no_clear.then_some(ClearOnStart::Never))
+                .or(self.clear_remote.then_some(ClearOnStart::Both))
+                .or(config.clear_on_start)
+                .or(config
+                    .no_clear
+                    .unwrap_or_default()
+                    .then_some(ClearOnStart::Never))
+                .or(config
+                    .clear_remote
+                    .unwrap_or_default()
+                    .then_some(ClearOnStart::Both)),
+            no_clear: self.no_clear || !clear_given && config.no_clear.unwrap_or_default(),
+            clear_remote: self.clear_remote
+                || !clear_given && config.clear_remote.unwrap_or_default(),
+            sync_files: self.sync_files || config.sync_files.unwrap_or_default(),
+            selection: self.selection.or(config.selection),
+            sync_sensitive: self.sync_sensitive || config.sync_sensitive.unwrap_or_default(),
+            sync_empty: self.sync_empty || config.sync_empty.unwrap_or_default(),
+            include,
+            exclude,
+            max_file_size: self.max_file_size.or(config.max_file_size),
+            max_image_dimension: self.max_image_dimension.or(config.max_image_dimension),
+            reject_large_images: self.reject_large_images
+                || config.reject_large_images.unwrap_or_default(),
+            image_format: self.image_format.or(config.image_format),
+            max_size: self.max_size.or(config.max_size),
+            stream_threshold: self.stream_threshold.or(config.stream_threshold),
+            accept: if self.accept.is_empty() {
+                config.accept.unwrap_or_default()
+            } else {
+                self.accept
+            },
+            confirm_over: self.confirm_over.or(config.confirm_over),
+            auto_accept: self.auto_accept || config.auto_accept.unwrap_or_default(),
+            key: if self.key.is_empty() {
+                config.key.into_iter().collect()
+            } else {
+                self.key
+            },
+            keys_file: self.keys_file.or(config.keys_file),
+            room: self.room.or(config.room),
+            name: self.name.or(config.name),
+            key_file: self.key_file.or(config.key_file),
+            tls: self.tls || config.tls.unwrap_or_default(),
+            transport: self.transport.or(config.transport),
+            tls_cert: self.tls_cert.or(config.tls_cert),
+            tls_key: self.tls_key.or(config.tls_key),
+            tls_pin: self.tls_pin.or(config.tls_pin),
+            encrypt: self.encrypt || config.encrypt.unwrap_or_default(),
+            require_encryption: self.require_encryption
+                || config.require_encryption.unwrap_or_default(),
+            compress: self.compress || config.compress.unwrap_or_default(),
+            lossy: self.lossy || config.lossy.unwrap_or_default(),
+            send_only: self.send_only || config.send_only.unwrap_or_default(),
+            recv_only: self.recv_only || config.recv_only.unwrap_or_default(),
+            normalize_eol: self.normalize_eol || config.normalize_eol.unwrap_or_default(),
+            trim: self.trim || config.trim.unwrap_or_default(),
+            min_interval_ms: self.min_interval_ms.or(config.min_interval_ms),
+            poll_interval_ms: self.poll_interval_ms.or(config.poll_interval_ms),
+            idle_poll_interval_ms: self.idle_poll_interval_ms.or(config.idle_poll_interval_ms),
+            paste_timeout_ms: self.paste_timeout_ms.or(config.paste_timeout_ms),
+            recv_queue: self.recv_queue.or(config.recv_queue),
+            notify: self.notify || config.notify.unwrap_or_default(),
+            history: self.history.or(config.history),
+            state_file: self.state_file.or(config.state_file),
+            backend: self.backend.or(config.backend),
+            relay: self.relay || config.relay.unwrap_or_default(),
+            keepalive_secs: self.keepalive_secs.or(config.keepalive_secs),
+            heartbeat_secs: self.heartbeat_secs.or(config.heartbeat_secs),
+            handshake_timeout: self.handshake_timeout.or(config.handshake_timeout),
+            max_retries: self.max_retries.or(config.max_retries),
+            log_content: self.log_content || config.log_content.unwrap_or_default(),
+            audit_log: self.audit_log.or(config.audit_log),
+            audit_content: self.audit_content || config.audit_content.unwrap_or_default(),
+            json_events: self.json_events || config.json_events.unwrap_or_default(),
+            metrics_addr: self.metrics_addr.or(config.metrics_addr),
+            progress: self.progress || config.progress.unwrap_or_default(),
+            log_level: self.log_level.or(log_level),
+            log_format: self.log_format.or(config.log_format),
+            log_file: self.log_file.or(config.log_file),
+            no_console_log: self.no_console_log || config.no_console_log.unwrap_or_default(),
+            ..self
+        };
+
+        if args.send_only && args.recv_only {
+            return Err("send-only and recv-only can't be used together".into());
+        }
+        if args.require_encryption
+            && !(args.encrypt || args.tls || args.transport == Some(TransportKind::Wss))
+        {
+            return Err("require-encryption needs --encrypt, --tls or --transport wss".into());
+        }
+        if args.audit_content && args.audit_log.is_none() {
+            return Err("audit-content needs audit-log".into());
+        }
+        if args.poll_interval_ms == Some(0) {
+            return Err("poll-interval-ms must be at least 1".into());
+        }
+        let poll_interval_ms = args
+            .poll_interval_ms
+            .unwrap_or(clipboard::POLL_INTERVAL.as_millis() as u64);
+        if args
+            .idle_poll_interval_ms
+            .is_some_and(|idle| idle < poll_interval_ms)
+        {
+            return Err("idle-poll-interval-ms must be at least poll-interval-ms".into());
+        }
+        if args.paste_timeout_ms == Some(0) {
+            return Err("paste-timeout-ms must be at least 1".into());
+        }
+        if args
+            .room
+            .as_ref()
+            .is_some_and(|room| room.len() > handshake::MAX_ROOM_LEN)
+        {
+            return Err(format!(
+                "room names are at most {} bytes long",
+                handshake::MAX_ROOM_LEN
+            )
+            .into());
+        }
+        if args
+            .name
+            .as_ref()
+            .is_some_and(|name| name.len() > handshake::MAX_NAME_LEN)
+        {
+            return Err(format!("names are at most {} bytes long", handshake::MAX_NAME_LEN).into());
+        }
+        if args.max_image_dimension == Some(0) {
+            return Err("max-image-dimension must be at least 1".into());
+        }
+        if args.reject_large_images && args.max_image_dimension.is_none() {
+            return Err("reject-large-images needs max-image-dimension".into());
+        }
+        if args.confirm_over.is_some() && (args.relay || args.history.is_some()) {
+            return Err("confirm-over can't be used with relay or history".into());
+        }
+        if args.socket.is_some() && !args.url.is_empty() {
+            return Err("--socket runs a server, it can't be used with url".into());
+        }
+        if args.relay && !args.url.is_empty() {
+            return Err("--relay runs a server, it can't be used with url".into());
+        }
+        if args.qr && !args.url.is_empty() {
+            return Err(
+                "--qr shows how to connect to this server, it can't be used with url".into(),
+            );
+        }
+        if args.pair && !args.url.is_empty() {
+            return Err("--pair runs a server, use `clipshare pair <url>` on the client".into());
+        }
+        let clear_remote = args.clear_on_start == Some(ClearOnStart::Both);
+        if args.state_file.is_some() && (args.relay || args.once || clear_remote) {
+            return Err("state-file can't be used with relay, once or clear-on-start both".into());
+        }
+        if clear_remote && (args.relay || args.once || args.dry_run) {
+            return Err("clear-on-start both can't be used with relay, once or dry-run".into());
+        }
+        if args
+            .stream_threshold
+            .is_some_and(|threshold| !(1..=protocol::MAX_STREAM_THRESHOLD).contains(&threshold))
+        {
+            return Err(format!(
+                "stream-threshold must be between 1 and {}",
+                protocol::MAX_STREAM_THRESHOLD
+            )
+            .into());
+        }
+        if args.max_connections == Some(0) {
+            return Err("max-connections must be at least 1".into());
+        }
+        if args.recv_queue == Some(0) {
+            return Err("recv-queue must be at least 1".into());
+        }
+        if mode == Mode::Connect
+            && args.url.is_empty()
+            && args.discover.is_none()
+            && !args.discover_broadcast
+        {
+            return Err(
+                "connect needs a server url, `--discover` or `--discover-broadcast`".into(),
+            );
+        }
+        if args.no_console_log && args.log_file.is_none() {
+            return Err("no-console-log needs a log-file to write the logs to".into());
+        }
+        if args.once && !args.send_only && !args.recv_only {
+            return Err("--once needs either --send-only or --recv-only".into());
+        }
+
+        let url = args
+            .url
+            .iter()
+            .map(|url| server_url(url))
+            .collect::<Result<_, _>>()?;
+        Ok(Self { url, ..args })
+    }
+}
+
+/// `url` in the form it is dialed and remembered in: a host name or IPv4 address and a port, an
+/// IPv6 address in brackets and a port, or a Unix socket with a `unix:` prefix. The port may be
+/// left out, and an IPv6 address given without brackets then.
+fn server_url(url: &str) -> Result<String, String> {
+    if url.starts_with("unix:") {
+        return Ok(url.to_string());
+    }
+    let invalid = || format!("invalid url `{url}`, expected host, host:port or unix:path");
+    if url.contains("://") {
+        return Err(invalid());
+    }
+    if let Ok(ip) = url.parse::<Ipv6Addr>() {
+        return Ok(SocketAddr::new(ip.into(), DEFAULT_PORT).to_string());
+    }
+    let (host, port) = match url.strip_prefix('[') {
+        Some(rest) => {
+            let (ip, port) = rest.split_once(']').ok_or_else(invalid)?;
+            ip.parse::<Ipv6Addr>().map_err(|_| invalid())?;
+            let port = match port {
+                "" => None,
+                port => Some(port.strip_prefix(':').ok_or_else(invalid)?),
+            };
+            (&url[..ip.len() + 2], port)
+        }
+        None => match url.split_once(':') {
+            Some((host, port)) => (host, Some(port)),
+            None => (url, None),
+        },
+    };
+    let valid_host = |c: char| c.is_ascii_alphanumeric() || "-._".contains(c);
+    if host.is_empty() || !(host.starts_with('[') || host.chars().all(valid_host)) {
+        return Err(invalid());
+    }
+    let port = match port {
+        Some(port) => port
+            .parse::<u16>()
+            .ok()
+            .filter(|&port| port != 0)
+            .ok_or_else(|| format!("invalid port `{port}` in url `{url}`"))?,
+        None => DEFAULT_PORT,
+    };
+    Ok(format!("{host}:{port}"))
+}
+
+/// Picks the key from, in order: `--key-file`, `--key-stdin`, `CLIPSHARE_KEY`, `--key`, the one
+/// remembered when pairing with the server when there is a single one, falling back to the
+/// default key.
+fn resolve_key(args: &Args) -> Result<String, Box<dyn Error + Send + Sync>> {
+    if let Some(ref path) = args.key_file {
+        let key = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read key file {}: {err}", path.display()))?;
+        return Ok(key.lines().next().unwrap_or_default().to_string());
+    }
+
+    if args.key_stdin {
+        let key = if io::stdin().is_terminal() {
+            rpassword::prompt_password("Key: ")?
+        } else {
+            let mut key = String::new();
+            io::stdin().read_line(&mut key)?;
+            key.trim_end_matches(['\r', '\n']).to_string()
+        };
+        return Ok(key);
+    }
+
+    if let Some(key) = std::env::var("CLIPSHARE_KEY")
+        .ok()
+        .or_else(|| args.key.first().cloned())
+    {
+        return Ok(key);
+    }
+
+    // The key of a server is only known when it is the only one
+    if let [url] = &args.url[..] {
+        if let Some(key) = PairedMachines::load()?.servers.remove(url) {
+            return Ok(key);
+        }
+    }
+    Ok(DEFAULT_KEY.to_string())
+}
+
+/// Asks for the code shown by the server, on the terminal or from the first line of stdin.
+fn ask_pairing_code() -> io::Result<String> {
+    if io::stdin().is_terminal() {
+        eprint!("Pairing code shown by the server: ");
+    }
+    let mut code = String::new();
+    io::stdin().read_line(&mut code)?;
+    Ok(code.trim().to_string())
+}
+
+/// Remembers the key of a client that paired with this server, so it is still accepted after a
+/// restart.
+fn remember_client(paired: &Paired) {
+    let saved = PairedMachines::load().and_then(|mut machines| {
+        machines
+            .clients
+            .insert(paired.name.clone(), paired.key.clone());
+        machines.save()
+    });
+    match saved {
+        Ok(path) => eprintln!(
+            "Paired with {}, its key is saved in {}",
+            paired.name,
+            path.display()
+        ),
+        Err(err) => error!(error = %err, "Failed to remember the paired client"),
+    }
+}
+
+/// Asks on the terminal whether to apply a received object of `kind`, `size` bytes long. Only one
+/// connection asks at a time, so the answers don't get mixed up.
+fn ask_confirm(kind: ClipboardKind, size: usize) -> bool {
+    static PROMPT: Mutex<()> = Mutex::new(());
+    let _prompt = PROMPT.lock().unwrap_or_else(PoisonError::into_inner);
+    eprint!("Apply the {kind} received, {size} bytes? [y/N] ");
+    let mut answer = String::new();
+    io::stdin().read_line(&mut answer).is_ok()
+        && matches!(answer.trim().to_lowercase().as_str(), "y" | "yes")
+}
+
+/// The keys a server accepts: the ones from `--keys-file` and every `--key` when there are
+/// several of them or a keys file, otherwise just `key`, and those of the paired clients.
+fn server_keys(args: &Args, key: &str) -> Result<Keys, Box<dyn Error + Send + Sync>> {
+    let keys = Keys::new();
+    if let Some(ref path) = args.keys_file {
+        let contents = std::fs::read_to_string(path)
+            .map_err(|err| format!("Could not read keys file {}: {err}", path.display()))?;
+        let named: BTreeMap<String, String> = toml::from_str(&contents)
+            .map_err(|err| format!("Invalid keys file {}: {err}", path.display()))?;
+        for (name, key) in named {
+            keys.add(name, key);
+        }
+    }
+    if args.keys_file.is_some() || args.key.len() > 1 {
+        for (index, key) in args.key.iter().enumerate() {
+            keys.add(format!("key{}", index + 1), key);
+        }
+    }
+    if keys.is_empty() {
+        keys.add("default", key);
+    }
+    for (name, key) in PairedMachines::load()?.clients {
+        keys.add(name, key);
+    }
+    Ok(keys)
+}
+
+/// The default span fields format, as a type of its own so the file layer doesn't reuse the
+/// fields the console layer formatted, colors included, and both don't record them twice.
+#[derive(Default)]
+struct FileFields(DefaultFields);
+
+impl<'writer> FormatFields<'writer> for FileFields {
+    fn format_fields<R: RecordFields>(
+        &self,
+        writer: Writer<'writer>,
+        fields: R,
+    ) -> std::fmt::Result {
+        self.0.format_fields(writer, fields)
+    }
+}
+
+/// A log file at `path` rotated daily, the date being appended to its name.
+fn log_file(path: &Path) -> Result<RollingFileAppender, Box<dyn Error + Send + Sync>> {
+    let name = path
+        .file_name()
+        .ok_or_else(|| format!("Log file {} has no file name", path.display()))?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    std::fs::create_dir_all(dir)
+        .map_err(|err| format!("Could not create log directory {}: {err}", dir.display()))?;
+    RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(name.to_string_lossy())
+        .max_log_files(LOG_FILES)
+        .build(dir)
+        .map_err(|err| format!("Could not open log file {}: {err}", path.display()).into())
+}
+
+/// Parses a pattern copied text is matched against.
+fn parse_regex(pattern: &str) -> Result<Regex, String> {
+    Regex::new(pattern).map_err(|err| format!("`{pattern}` is not a valid regex: {err}"))
+}
+
+/// Parses an allowed IP, or range of IPs in CIDR notation.
+fn parse_allow(allow: &str) -> Result<IpNet, String> {
+    allow
+        .parse()
+        .or_else(|_| allow.parse::<IpAddr>().map(IpNet::from))
+        .map_err(|_| format!("`{allow}` is neither an IP nor a CIDR range"))
 }
 
 #[tokio::main(flavor = "current_thread")]
 async fn main() -> Result<(), Box<dyn Error + Send + Sync>> {
-    // a builder for `FmtSubscriber`.
-    let subscriber = FmtSubscriber::builder()
-        // all spans/events with a level higher than TRACE (e.g, debug, info, warn, etc.)
-        // will be written to stdout.
-        .with_max_level(Level::TRACE)
-        // completes the builder.
-        .finish();
+    let cli = Cli::parse();
+    if let Some(Command::Completions { shell }) = cli.command {
+        clap_complete::generate(shell, &mut Cli::command(), "clipshare", &mut io::stdout());
+        return Ok(());
+    }
+    let code = match cli.command {
+        Some(Command::Pair { ref code, .. }) => code.clone(),
+        _ => None,
+    };
+    let (mode, args) = cli.into_args();
+    // Printed by hand, as the `Debug` output of the error would mangle toml's multi-line message
+    let args = match Config::load(args.config.clone()).and_then(|config| args.merge(config, mode)) {
+        Ok(args) => args,
+        Err(err) => {
+            eprintln!("{err}");
+            std::process::exit(2);
+        }
+    };
+    if mode == Mode::Legacy {
+        eprintln!("Running without a subcommand is deprecated, use `clipshare serve` or `clipshare connect <url>` instead");
+    }
+
+    let filter = match args.log_level {
+        _ if args.verbose > 0 || args.quiet > 0 => EnvFilter::new(
+            offset_level(
+                args.log_level.unwrap_or(Level::INFO),
+                args.verbose,
+                args.quiet,
+            )
+            .to_string(),
+        ),
+        Some(level) => EnvFilter::new(level.to_string()),
+        None => std::env::var("CLIPSHARE_LOG")
+            .or_else(|_| std::env::var("RUST_LOG"))
+            .map_or_else(
+                |_| Ok(EnvFilter::new(Level::INFO.to_string())),
+                EnvFilter::try_new,
+            )?,
+    };
+
+    if args.json_events {
+        events::enable();
+    }
+    if args.progress {
+        progress::enable();
+    }
+    // Keep stdout for the events or the received clipboards alone
+    let writer = if args.json_events || args.stdio {
+        BoxMakeWriter::new(io::stderr)
+    } else {
+        BoxMakeWriter::new(io::stdout)
+    };
+
+    let format = args.log_format.unwrap_or_default();
+    let mut layers: Vec<Box<dyn Layer<Registry> + Send + Sync>> = Vec::new();
+    if !args.no_console_log {
+        layers.push(match format {
+            LogFormat::Text => fmt::layer().with_writer(writer).boxed(),
+            LogFormat::Json => fmt::layer().json().with_writer(writer).boxed(),
+        });
+    }
+    if let Some(ref path) = args.log_file {
+        let file = log_file(path)?;
+        layers.push(match format {
+            LogFormat::Text => fmt::layer()
+                .with_ansi(false)
+                .fmt_fields(FileFields::default())
+                .with_writer(file)
+                .boxed(),
+            LogFormat::Json => fmt::layer().json().with_writer(file).boxed(),
+        });
+    }
+    // Only spans and events allowed by the configured filter are written anywhere
+    let subscriber = tracing_subscriber::registry().with(layers).with(filter);
 
     tracing::subscriber::set_global_default(subscriber).expect("setting default subscriber failed");
 
-    let args = Cli::parse();
+    // Clearing would throw away what a one shot send is about to send, and pairing syncs nothing
+    let memory = args.relay || mode == Mode::Pair || args.backend == Some(BackendKind::Memory);
+    let backend: Backend = if args.stdio {
+        Box::new(StdioClipboard::new()?)
+    } else if memory {
+        Box::new(MemoryClipboard::new())
+    } else if args.clear_on_start == Some(ClearOnStart::Never) || args.once || args.dry_run {
+        Box::new(arboard::Clipboard::new()?)
+    } else {
+        Box::new(backend::cleared()?)
+    };
+    let clipboard = Clipboard::with_backend(backend)
+        .with_poll_interval(
+            args.poll_interval_ms
+                .map_or(clipboard::POLL_INTERVAL, Duration::from_millis),
+        )
+        .with_idle_poll_interval(
+            args.idle_poll_interval_ms
+                .map_or(clipboard::IDLE_POLL_INTERVAL, Duration::from_millis),
+        )
+        .with_paste_timeout(
+            args.paste_timeout_ms
+                .map_or(clipboard::PASTE_TIMEOUT, Duration::from_millis),
+        )
+        .with_selection(match args.selection.unwrap_or_default() {
+            SelectionKind::Clipboard => Selection::Clipboard,
+            SelectionKind::Primary => Selection::Primary,
+            SelectionKind::Both => Selection::Both,
+        });
+    // Stdin and memory only change through clipshare, nothing to listen to
+    let clipboard = if args.stdio || memory {
+        clipboard
+    } else {
+        clipboard.with_change_events()
+    };
+    let clipboard = if args.sync_files {
+        clipboard.with_files(args.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE))
+    } else {
+        clipboard
+    };
+    let clipboard = match args.max_image_dimension {
+        Some(max_dimension) => clipboard.with_image_limit(ImageLimit {
+            max_dimension,
+            reject: args.reject_large_images,
+        }),
+        None => clipboard,
+    };
+    let clipboard = Arc::new(match args.history {
+        Some(len) if len > 0 => clipboard.with_history(len, HISTORY_MAX_SIZE),
+        _ => clipboard,
+    });
+
+    // Before watching, so the clipboard already there is seen as newer than the saved one
+    if let Some(ref path) = args.state_file {
+        let max_size = args.max_size.unwrap_or(DEFAULT_MAX_SIZE);
+        if let Err(err) = state::restore(&clipboard, path, max_size).await {
+            warn!(path = %path.display(), error = %err, "Failed to restore the clipboard");
+        }
+        tokio::spawn(state::save(clipboard.clone(), path.clone()));
+    }
+
+    // A relay has no clipboard of its own to watch
+    if !args.relay {
+        tokio::spawn({
+            let clipboard = clipboard.clone();
+            async move {
+                if let Err(err) = clipboard.watch().await {
+                    error!(error = %err, "Failed to read the local clipboard");
+                }
+            }
+        });
+    }
 
-    let clipboard = Arc::new(if args.no_clear {
-        Clipboard::new()
+    // Sent to every peer as the latest change, until something is copied
+    if args.clear_on_start == Some(ClearOnStart::Both) {
+        clipboard
+            .copy(Origin::Local, ClipboardObject::Clear)
+            .await?;
+    }
+
+    // Stdin is only read once, so its contents won't show up as a change
+    if args.stdio && !args.once {
+        clipboard.publish_current().await?;
+    }
+
+    if args.history.is_some_and(|len| len > 0) {
+        tokio::spawn(history::prompt(clipboard.clone()));
+    }
+
+    let key = resolve_key(&args)?;
+    let keys = server_keys(&args, &key)?;
+    log_config(&args, mode, &key, &keys);
+    let transport = args.transport.unwrap_or_default();
+    let tls = args.tls || transport == TransportKind::Wss;
+    let websocket = transport != TransportKind::Tcp;
+    let service = discovery::Service {
+        key_required: !keys.contains(DEFAULT_KEY),
+        tls,
+        mdns: !args.no_advertise,
+        broadcast: args.broadcast,
+    };
+
+    let protocol = Protocol::new(args.max_size.unwrap_or(DEFAULT_MAX_SIZE));
+    let protocol = if args.encrypt {
+        protocol.encrypted(Cipher::new(&key))
+    } else {
+        protocol
+    };
+    let protocol = if args.compress {
+        protocol.compressed()
+    } else {
+        protocol
+    };
+    let protocol = if args.lossy {
+        protocol.lossy()
+    } else {
+        protocol
+    };
+    let protocol = protocol.image_format(match args.image_format.unwrap_or_default() {
+        ImageFormatKind::Raw => ImageFormat::Raw,
+        ImageFormatKind::Png => ImageFormat::Png,
+    });
+    let protocol = match args.heartbeat_secs.unwrap_or(DEFAULT_HEARTBEAT) {
+        0 => protocol,
+        secs => protocol.heartbeat(Duration::from_secs(secs)),
+    };
+    let protocol =
+        protocol.streaming_over(args.stream_threshold.unwrap_or(protocol::STREAM_THRESHOLD));
+    let accept = if args.accept.is_empty() {
+        // A relay forwards files whatever it syncs itself
+        ClipboardKind::ALL
+            .into_iter()
+            .filter(|&kind| kind != ClipboardKind::Files || args.sync_files || args.relay)
+            .collect()
+    } else {
+        args.accept.clone()
+    };
+    let protocol = protocol.accepting(&accept);
+
+    let direction = if args.send_only {
+        Direction::Send
+    } else if args.recv_only {
+        Direction::Recv
     } else {
-        Clipboard::cleared()
+        Direction::Both
+    };
+    let paused = Arc::new(AtomicBool::new(false));
+    #[cfg(unix)]
+    if !args.once {
+        tokio::spawn(pause_on_signals(paused.clone(), args.notify));
+    }
+
+    let defaults = SessionOptions::default();
+    let options = SessionOptions {
+        handshake_timeout: args
+            .handshake_timeout
+            .map_or(defaults.handshake_timeout, Duration::from_secs),
+        direction,
+        min_interval: args
+            .min_interval_ms
+            .map_or(defaults.min_interval, Duration::from_millis),
+        log_content: args.log_content,
+        report_content: args.audit_content,
+        normalize_eol: args.normalize_eol,
+        trim: args.trim,
+        recv_queue: args.recv_queue.unwrap_or(defaults.recv_queue),
+        sync_sensitive: args.sync_sensitive,
+        sync_empty: args.sync_empty,
+        filter: Arc::new(Filter::new(args.include, args.exclude)),
+        confirm: args.confirm_over.map(|over| {
+            // Stdin is the clipboard with --stdio, and nobody answers when it isn't a terminal
+            let interactive = io::stdin().is_terminal() && !args.stdio;
+            let auto_accept = args.auto_accept;
+            Confirm::new(over, move |kind, size| {
+                if interactive {
+                    ask_confirm(kind, size)
+                } else {
+                    auto_accept
+                }
+            })
+        }),
+        paused,
+        dry_run: args.dry_run,
+    };
+    let name = args.name.unwrap_or_else(handshake::default_name);
+    let relay = args.relay.then(|| {
+        Relay::new(keys.clone(), protocol.clone())
+            .with_name(name.clone())
+            .with_options(options.clone())
     });
+    let session = ClipshareSession::new(clipboard, key, protocol)
+        .with_keys(keys)
+        .with_room(args.room.clone().unwrap_or_default())
+        .with_name(name)
+        .with_options(options);
+    let pairing = args
+        .pair
+        .then(|| Pairing::new(PAIRING_LIFETIME, remember_client));
+    // Kept to show along with the QR code
+    let offered_code = pairing.as_ref().and_then(Pairing::code);
+    let session = match pairing {
+        Some(pairing) => {
+            eprintln!(
+                "Pairing code {}, enter it within {} minutes with `clipshare pair <url of this server>` on the other machine",
+                offered_code.as_deref().unwrap_or_default(),
+                PAIRING_LIFETIME.as_secs() / 60
+            );
+            session.with_pairing(pairing)
+        }
+        None => session,
+    };
+    let keepalive = Duration::from_secs(args.keepalive_secs.unwrap_or(DEFAULT_KEEPALIVE));
+
+    if mode == Mode::Pair {
+        let code = match code {
+            Some(code) => code,
+            None => ask_pairing_code()?,
+        };
+        let transport = Transport {
+            tls: tls.then(|| tls::connector(args.tls_pin)).transpose()?,
+            websocket,
+        };
+        return pair(&session, &args.url[0], transport, keepalive, &code).await;
+    }
+
+    if let Some(path) = &args.audit_log {
+        audit::open(path)
+            .map_err(|err| format!("Could not open audit log {}: {err}", path.display()))?;
+    }
+
+    if let Some(addr) = args.metrics_addr {
+        let listener = metrics::listen(addr)
+            .await
+            .map_err(|err| format!("Could not serve metrics on {addr}: {err}"))?;
+        info!("Serving metrics at http://{addr}/metrics");
+        tokio::spawn(metrics::serve(listener));
+    }
 
-    let key = std::env::var("CLIPSHARE_KEY").unwrap_or(args.key.unwrap_or("clipshare".to_string()));
-    trace!(key);
+    let wait = Duration::from_secs(args.discover_timeout.unwrap_or(5));
+    let urls = match args.discover {
+        Some(name) => {
+            let addr = discovery::discover(name.as_deref(), wait).await?;
+            eprintln!("Discovered clipshare server at {addr}");
+            vec![addr]
+        }
+        None if args.discover_broadcast => {
+            let addr = discovery::discover_broadcast(wait).await?;
+            eprintln!("Discovered clipshare server at {addr}");
+            vec![addr]
+        }
+        None => args.url,
+    };
 
-    match args.url {
-        Some(url) => start_client(clipboard, url, key).await,
-        None => start_server(clipboard, args.port, key).await,
+    match urls.len() {
+        1.. => {
+            let transport = Transport {
+                tls: tls.then(|| tls::connector(args.tls_pin)).transpose()?,
+                websocket,
+            };
+            let max_retries = args.max_retries.unwrap_or(0);
+            start_clients(
+                session,
+                urls,
+                transport,
+                max_retries,
+                keepalive,
+                args.notify,
+                args.once,
+            )
+            .await
+        }
+        0 if args.once => Err("--once only works when connecting to a server".into()),
+        0 => {
+            let transport = Transport {
+                tls: tls
+                    .then(|| tls::acceptor(args.tls_cert, args.tls_key))
+                    .transpose()?,
+                websocket,
+            };
+            let listener = match args.socket {
+                Some(path) => Listener::unix(path)?,
+                None => {
+                    let bind = args.bind.unwrap_or(IpAddr::V4(Ipv4Addr::UNSPECIFIED));
+                    let addr = SocketAddr::new(bind, args.port.unwrap_or(DEFAULT_PORT));
+                    Listener::Tcp(listen(addr, args.port_fallback).await?)
+                }
+            };
+            if args.qr {
+                let url = match listener {
+                    Listener::Tcp(ref listener) => qr::url(listener.local_addr()?),
+                    #[cfg(unix)]
+                    Listener::Unix(..) => Some(listener.to_string()),
+                };
+                let invitation = url.map(|url| qr::Invitation {
+                    url,
+                    room: args.room.as_deref(),
+                    transport: args.transport.unwrap_or_default(),
+                    tls_pin: transport.tls.as_ref().map(|tls| tls.fingerprint.as_str()),
+                    encrypt: args.encrypt,
+                    key_required: service.key_required,
+                    code: offered_code.as_deref(),
+                });
+                match invitation.map(|invitation| qr::show(&invitation.to_string())) {
+                    Some(Ok(())) => {}
+                    Some(Err(err)) => warn!(error = %err, "Failed to draw the QR code"),
+                    None => warn!("Could not find the address of this machine for the QR code"),
+                }
+            }
+            let admission = Admission {
+                allow: args.allow,
+                max_connections: args.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS),
+            };
+            let hub = match relay {
+                Some(relay) => Hub::Relay(Arc::new(relay)),
+                None => Hub::Clipboard(Box::new(session)),
+            };
+            start_server(
+                hub,
+                listener,
+                admission,
+                transport,
+                service,
+                keepalive,
+                args.notify,
+            )
+            .await
+        }
     }
 }
 
-#[instrument(skip(clipboard))]
+/// What the TCP connections are wrapped in, `T` setting up TLS on either end.
+#[derive(Clone)]
+struct Transport<T> {
+    tls: Option<T>,
+    /// Whether a WebSocket is run inside, after TLS.
+    websocket: bool,
+}
+
+/// What a server does with the clients that connect to it.
+#[derive(Clone)]
+enum Hub {
+    /// Syncs them with the local clipboard.
+    Clipboard(Box<ClipshareSession<Backend>>),
+    /// Forwards what each of them copies to the others, with `--relay`.
+    Relay(Arc<Relay>),
+}
+
+impl Hub {
+    fn options(&self) -> &SessionOptions {
+        match *self {
+            Self::Clipboard(ref session) => session.options(),
+            Self::Relay(ref relay) => relay.options(),
+        }
+    }
+}
+
+/// A stream to a peer, whichever way it connected.
+trait Stream: AsyncRead + AsyncWrite + Send + Unpin {}
+
+impl<S: AsyncRead + AsyncWrite + Send + Unpin> Stream for S {}
+
+/// Where a server takes its connections from.
+enum Listener {
+    Tcp(TcpListener),
+    /// A Unix socket with `--socket`, removed once the server stops.
+    #[cfg(unix)]
+    Unix(tokio::net::UnixListener, PathBuf),
+}
+
+impl std::fmt::Display for Listener {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match *self {
+            Self::Tcp(ref listener) => match listener.local_addr() {
+                Ok(addr) => write!(f, "{addr}"),
+                Err(_) => write!(f, "tcp"),
+            },
+            #[cfg(unix)]
+            Self::Unix(_, ref path) => write!(f, "unix:{}", path.display()),
+        }
+    }
+}
+
+impl Listener {
+    /// Listens on the Unix socket at `path`, only the current user being allowed to connect.
+    fn unix(path: PathBuf) -> Result<Self, Box<dyn Error + Send + Sync>> {
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+
+            let listener =
+                tokio::net::UnixListener::bind(&path).map_err(|err| match err.kind() {
+                    io::ErrorKind::AddrInUse => format!(
+                        "{} already exists, is clipshare already running? Remove it if not",
+                        path.display()
+                    ),
+                    _ => format!("Could not listen on {}: {err}", path.display()),
+                })?;
+            // Built first, so the socket is removed when the rest fails
+            let listener = Self::Unix(listener, path.clone());
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o600))?;
+            Ok(listener)
+        }
+        #[cfg(not(unix))]
+        Err(format!(
+            "Unix sockets are only available on unix, can't listen on {}",
+            path.display()
+        )
+        .into())
+    }
+
+    /// Waits for the next peer, returning it along with its address, localhost through a Unix
+    /// socket. TCP keepalive is enabled on the TCP ones.
+    async fn accept(&self, keepalive: Duration) -> io::Result<(Box<dyn Stream>, IpAddr)> {
+        match *self {
+            Self::Tcp(ref listener) => {
+                let (stream, addr) = listener.accept().await?;
+                set_keepalive(&stream, keepalive);
+                // An IPv4 client of a server bound to `[::]` shows up as an IPv4-mapped IPv6
+                // address
+                Ok((Box::new(stream), addr.ip().to_canonical()))
+            }
+            #[cfg(unix)]
+            Self::Unix(ref listener, _) => {
+                let (stream, _) = listener.accept().await?;
+                Ok((Box::new(stream), IpAddr::V4(Ipv4Addr::LOCALHOST)))
+            }
+        }
+    }
+}
+
+impl Drop for Listener {
+    fn drop(&mut self) {
+        #[cfg(unix)]
+        if let Self::Unix(_, ref path) = *self {
+            if let Err(err) = std::fs::remove_file(path) {
+                warn!(path = %path.display(), error = %err, "Failed to remove the socket");
+            }
+        }
+    }
+}
+
+/// Which connections a server takes on.
+#[derive(Debug)]
+struct Admission {
+    /// Addresses allowed to connect, anyone when empty.
+    allow: Vec<IpNet>,
+    /// Most connections handled at once.
+    max_connections: usize,
+}
+
+#[instrument(skip(hub, listener, transport), fields(addr = %listener))]
 async fn start_server(
-    clipboard: Arc<Clipboard>,
-    port: Option<u16>,
-    key: String,
+    hub: Hub,
+    listener: Listener,
+    admission: Admission,
+    transport: Transport<tls::Acceptor>,
+    service: discovery::Service,
+    keepalive: Duration,
+    notify: bool,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    let listener = TcpListener::bind(("0.0.0.0", port.unwrap_or(0))).await?;
-    let port = listener.local_addr()?.port();
-    eprintln!("Run `clipshare ip:{port}` on another machine of your network");
+    // Nobody on the network can reach a Unix socket, so it is never advertised
+    let port = match listener {
+        Listener::Tcp(ref listener) => {
+            let addr = listener.local_addr()?;
+            let port = addr.port();
+            events::emit(Event::Listening { port });
+            if addr.ip().is_unspecified() {
+                eprintln!("Run `clipshare connect ip:{port}` on another machine of your network");
+            } else {
+                eprintln!("Run `clipshare connect {addr}` on another machine of your network");
+            }
+            Some(port)
+        }
+        #[cfg(unix)]
+        Listener::Unix(_, ref path) => {
+            events::emit(Event::ListeningSocket { path });
+            eprintln!(
+                "Run `clipshare connect unix:{}` on this machine",
+                path.display()
+            );
+            None
+        }
+    };
+
+    let _advertisement = port.filter(|_| service.mdns).and_then(|port| {
+        service
+            .advertise(port)
+            .inspect_err(|err| warn!(error = %err, "Failed to advertise server through mDNS"))
+            .ok()
+    });
+
+    if let Some(port) = port.filter(|_| service.broadcast) {
+        tokio::spawn(async move {
+            if let Err(err) = service.answer_probes(port).await {
+                warn!(error = %err, "Failed to answer UDP broadcast probes");
+            }
+        });
+    }
+
+    let websocket = transport.websocket;
+    let acceptor = transport.tls.map(
+        |tls::Acceptor {
+             acceptor,
+             fingerprint,
+         }| {
+            eprintln!("TLS certificate fingerprint: {fingerprint}");
+            acceptor
+        },
+    );
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    let mut connections = JoinSet::new();
+    let permits = Arc::new(Semaphore::new(admission.max_connections));
+
+    loop {
+        let (stream, ip) = select! {
+            accepted = listener.accept(keepalive) => match accepted {
+                Ok(accepted) => accepted,
+                Err(err) => {
+                    error!(error = %err, "Failed to accept connection");
+                    events::emit(Event::Error { error: &err.to_string() });
+                    break;
+                }
+            },
+            // Reap finished connections so the set doesn't grow forever
+            Some(_) = connections.join_next() => continue,
+            _ = &mut shutdown => {
+                eprintln!("Shutting down");
+                break;
+            }
+        };
 
-    while let Ok((stream, addr)) = listener.accept().await {
         trace!("New connection arrived");
-        let ip = addr.ip();
-        let clipboard = clipboard.clone();
-        let key: String = key.clone();
-        tokio::spawn(
+        let allow = &admission.allow;
+        if !allow.is_empty() && !allow.iter().any(|net| net.contains(&ip)) {
+            warn!(%ip, "Rejecting connection from an address not in --allow");
+            continue;
+        }
+        // Held until the connection is over, dropping the stream closes it right away otherwise
+        let Ok(permit) = permits.clone().try_acquire_owned() else {
+            warn!(
+                %ip,
+                max = admission.max_connections,
+                "Rejecting connection, already handling --max-connections of them"
+            );
+            continue;
+        };
+        events::emit(Event::Connected { ip });
+        let hub = hub.clone();
+        let acceptor = acceptor.clone();
+        connections.spawn(
             async move {
-                let (mut reader, mut writer) = tokio::io::split(stream);
-
-                let mut buf = [0; 1];
-                reader.read_exact(&mut buf).await?;
-                trace!("Read kind {buf:?}");
-                match buf[0] {
-                    0 => {
-                        let mut buf = [0; std::mem::size_of::<u64>()];
-                        reader.read_exact(&mut buf).await?;
-                        let len = u64::from_be_bytes(buf).try_into()?;
-                        trace!(len, "Read key len");
-
-                        let mut buf = vec![0; len];
-                        reader.read_exact(&mut buf).await?;
-                        trace!(len, "Read key");
-
-                        let client_key = std::str::from_utf8(&buf)?;
-                        trace!(client_key);
-
-                        if !key.eq(&client_key) {
-                            error_span!("Key mismatch");
-                            writer.shutdown().await?;
+                let _permit = permit;
+                let result = match acceptor {
+                    Some(acceptor) => {
+                        let wait = hub.options().handshake_timeout;
+                        match accept_tls(acceptor, stream, wait).await {
+                            Ok(stream) => accept_transport(hub, stream, ip, notify, websocket).await,
+                            Err(err) => Err(err),
                         }
                     }
-                    _n => {
-                        error_span!("Key error");
-                        writer.shutdown().await?;
+                    None => accept_transport(hub, stream, ip, notify, websocket).await,
+                };
+                if let Err(err) = result {
+                    // Like a port scan or a health check
+                    if matches!(err.downcast_ref(), Some(ClipshareError::Closed)) {
+                        debug!("Peer closed the connection before completing the handshake");
+                    } else {
+                        warn!(error = %err, "Handshake failed, dropping connection");
                     }
+                    events::emit(Event::HandshakeFailed {
+                        ip,
+                        error: &err.to_string(),
+                    });
                 }
+            }
+            .instrument(error_span!("Connection", %ip, version = field::Empty, key = field::Empty, room = field::Empty, peer = field::Empty)),
+        );
+    }
+
+    // Stop accepting and close every open connection
+    drop(listener);
+    connections.shutdown().await;
+    Ok(())
+}
 
-                if let Err(err) = select! {
-                    result = recv_clipboard(clipboard.clone(), reader) => result,
-                    result = send_clipboard(clipboard.clone(), writer) => result,
-                } {
-                    debug!(error = %err, "Server error");
+/// Listens on `addr`, or on a port picked by the system when `fallback` is set and the port
+/// asked for is taken.
+async fn listen(
+    addr: SocketAddr,
+    fallback: bool,
+) -> Result<TcpListener, Box<dyn Error + Send + Sync>> {
+    match TcpListener::bind(addr).await {
+        Ok(listener) => Ok(listener),
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse && fallback => {
+            eprintln!(
+                "Port {} is already in use, listening on a port picked by the system instead",
+                addr.port()
+            );
+            Ok(TcpListener::bind(SocketAddr::new(addr.ip(), 0)).await?)
+        }
+        Err(err) if err.kind() == io::ErrorKind::AddrInUse => Err(format!(
+            "Port {} is already in use, is clipshare already running? Pick another one with --port, or use --port-fallback",
+            addr.port()
+        )
+        .into()),
+        Err(err) => Err(format!("Could not listen on {addr}: {err}").into()),
+    }
+}
+
+/// Enables TCP keepalive on `stream`, so a peer that vanished without closing the connection
+/// (gone to sleep, dropped off the network) makes reads fail instead of hanging forever.
+fn set_keepalive(stream: &TcpStream, idle: Duration) {
+    if idle.is_zero() {
+        return;
+    }
+    let keepalive = TcpKeepalive::new().with_time(idle).with_interval(idle);
+    // Give up after a few unanswered probes rather than the system default, often 9
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd"
+    ))]
+    let keepalive = keepalive.with_retries(3);
+    if let Err(err) = SockRef::from(stream).set_tcp_keepalive(&keepalive) {
+        warn!(error = %err, "Failed to enable TCP keepalive");
+    }
+}
+
+/// Resolves once the process is asked to stop, through Ctrl-C or, on unix, SIGTERM.
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
                 }
-                trace!("Finishing server connection");
-                Ok::<_, Box<dyn Error + Send + Sync>>(())
             }
-            .instrument(error_span!("Connection", %ip)),
-        );
+            Err(err) => {
+                warn!(error = %err, "Failed to listen for SIGTERM");
+                let _ = tokio::signal::ctrl_c().await;
+            }
+        }
+    }
+
+    #[cfg(not(unix))]
+    let _ = tokio::signal::ctrl_c().await;
+}
+
+/// Pauses syncing on SIGUSR1 and resumes it on SIGUSR2, showing a notification when `notify`
+/// is set.
+#[cfg(unix)]
+async fn pause_on_signals(paused: Arc<AtomicBool>, notify: bool) {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let signals = (
+        signal(SignalKind::user_defined1()),
+        signal(SignalKind::user_defined2()),
+    );
+    let (mut pause, mut resume) = match signals {
+        (Ok(pause), Ok(resume)) => (pause, resume),
+        (Err(err), _) | (_, Err(err)) => {
+            warn!(error = %err, "Failed to listen for SIGUSR1 and SIGUSR2, syncing can't be paused");
+            return;
+        }
+    };
+    loop {
+        let pausing = select! {
+            Some(()) = pause.recv() => true,
+            Some(()) = resume.recv() => false,
+            else => return,
+        };
+        if paused.swap(pausing, Ordering::SeqCst) == pausing {
+            continue;
+        }
+        if pausing {
+            info!("Syncing paused, send SIGUSR2 to resume");
+        } else {
+            info!("Syncing resumed");
+        }
+        if notify {
+            notify::paused(pausing);
+        }
+    }
+}
+
+async fn accept_tls<S: Stream>(
+    acceptor: TlsAcceptor,
+    stream: S,
+    wait: Duration,
+) -> Result<tokio_rustls::server::TlsStream<S>, Box<dyn Error + Send + Sync>> {
+    let Ok(stream) = timeout(wait, acceptor.accept(stream)).await else {
+        return Err("TLS handshake timed out".into());
+    };
+    trace!("TLS handshake completed");
+    Ok(stream?)
+}
+
+/// Runs a server connection on `stream`, inside a WebSocket when `websocket` is set.
+async fn accept_transport(
+    hub: Hub,
+    stream: impl AsyncRead + AsyncWrite + Send + Unpin,
+    ip: IpAddr,
+    notify: bool,
+    websocket: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    if !websocket {
+        return handle_connection(hub, stream, ip, notify).await;
     }
+    let wait = hub.options().handshake_timeout;
+    let Ok(stream) = timeout(wait, ws::accept(stream)).await else {
+        return Err("WebSocket handshake timed out".into());
+    };
+    handle_connection(hub, stream?, ip, notify).await
+}
 
+/// Runs a server connection, failing only when the handshake does.
+async fn handle_connection(
+    hub: Hub,
+    stream: impl AsyncRead + AsyncWrite + Send + Unpin,
+    ip: IpAddr,
+    notify: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match hub {
+        Hub::Clipboard(session) => serve(session.accept(stream).await?, ip, notify).await,
+        Hub::Relay(relay) => serve(relay.accept(stream).await?, ip, notify).await,
+    }
+    trace!("Finishing server connection");
     Ok(())
 }
 
-#[instrument(skip(clipboard))]
+/// Syncs a server connection that completed the handshake until either side stops.
+async fn serve<S, B>(connection: Connection<S, B>, ip: IpAddr, notify: bool)
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+    B: ClipboardBackend,
+{
+    events::emit(Event::HandshakeOk {
+        ip,
+        peer: connection.peer(),
+    });
+    if let Err(err) = sync(connection, ip, notify).await {
+        log_end(&err);
+    }
+    events::emit(Event::Disconnected { ip });
+}
+
+/// Syncs with every server in `urls` at once, sharing the clipboard of `session` so what one of
+/// them sends is passed on to the others, until all of them are done with. Returns the first
+/// error, once the other connections ended too.
+async fn start_clients(
+    session: ClipshareSession<Backend>,
+    urls: Vec<String>,
+    transport: Transport<TlsConnector>,
+    max_retries: u32,
+    keepalive: Duration,
+    notify: bool,
+    once: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut clients = JoinSet::new();
+    for url in urls {
+        clients.spawn(start_client(
+            session.clone(),
+            url,
+            transport.clone(),
+            max_retries,
+            keepalive,
+            notify,
+            once,
+        ));
+    }
+
+    let mut result = Ok(());
+    while let Some(joined) = clients.join_next().await {
+        if let Err(err) = joined? {
+            error!(error = %err, "Gave up on a server");
+            if result.is_ok() {
+                result = Err(err);
+            }
+        }
+    }
+    result
+}
+
+#[instrument(skip(session, transport))]
 async fn start_client(
-    clipboard: Arc<Clipboard>,
+    session: ClipshareSession<Backend>,
     addr: String,
-    key: String,
+    transport: Transport<TlsConnector>,
+    max_retries: u32,
+    keepalive: Duration,
+    notify: bool,
+    once: bool,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
     info!("starting client");
 
+    if once {
+        let connection = connect(&session, &addr, transport, keepalive, notify, once);
+        return select! {
+            result = connection => result.map_err(|err| format!("Could not sync with {addr}: {err}").into()),
+            _ = shutdown_signal() => Ok(()),
+        };
+    }
+
+    let mut backoff = INITIAL_BACKOFF;
+    let mut attempt = 0;
+
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+
+    loop {
+        let connection = connect(&session, &addr, transport.clone(), keepalive, notify, once);
+        let result = select! {
+            result = connection => result,
+            _ = &mut shutdown => {
+                eprintln!("Clipboard closed");
+                return Ok(());
+            }
+        };
+
+        match result {
+            Ok(()) => {
+                eprintln!("Clipboard closed");
+                backoff = INITIAL_BACKOFF;
+                attempt = 0;
+            }
+            Err(err) => {
+                eprintln!("Could not connect to {addr}: {err}");
+                events::emit(Event::Error {
+                    error: &format!("Could not connect to {addr}: {err}"),
+                });
+                // The server refuses the key or code the same way every time
+                let refused = err
+                    .downcast_ref::<ClipshareError>()
+                    .is_some_and(ClipshareError::is_auth);
+                if refused || (max_retries != 0 && attempt >= max_retries) {
+                    return Err(err);
+                }
+            }
+        }
+
+        attempt += 1;
+        eprintln!("Reconnecting in {}s (attempt {attempt})", backoff.as_secs());
+        select! {
+            _ = sleep(backoff) => {}
+            _ = &mut shutdown => {
+                eprintln!("Clipboard closed");
+                return Ok(());
+            }
+        }
+        backoff = (backoff * 2).min(MAX_BACKOFF);
+    }
+}
+
+async fn connect(
+    session: &ClipshareSession<Backend>,
+    addr: &str,
+    transport: Transport<TlsConnector>,
+    keepalive: Duration,
+    notify: bool,
+    once: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
     trace!("Begin client connection to {addr}");
-    let stream = TcpStream::connect(addr).await?;
+    let (stream, ip, host) = dial(addr, keepalive).await?;
+    events::emit(Event::Connected { ip });
+
+    async move {
+        let stream = client_transport(session, stream, host, transport, ip).await?;
+        client_session(session, stream, ip, notify, once).await
+    }
+    .instrument(error_span!("Connection", %ip, version = field::Empty, peer = field::Empty))
+    .await
+}
+
+/// Pairs with the server at `addr` through `code`, remembering the key they agreed on for `addr`.
+async fn pair(
+    session: &ClipshareSession<Backend>,
+    addr: &str,
+    transport: Transport<TlsConnector>,
+    keepalive: Duration,
+    code: &str,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let (stream, ip, host) = dial(addr, keepalive).await?;
+    events::emit(Event::Connected { ip });
+
+    let paired = async move {
+        let stream = client_transport(session, stream, host, transport, ip).await?;
+        let (connection, paired) = session.pair(stream, code).await.inspect_err(|err| {
+            events::emit(Event::HandshakeFailed {
+                ip,
+                error: &err.to_string(),
+            })
+        })?;
+        events::emit(Event::HandshakeOk {
+            ip,
+            peer: connection.peer(),
+        });
+        Ok::<_, Box<dyn Error + Send + Sync>>(paired)
+    }
+    .instrument(error_span!("Connection", %ip, version = field::Empty, peer = field::Empty))
+    .await?;
+    events::emit(Event::Disconnected { ip });
+
+    let mut machines = PairedMachines::load()?;
+    machines.servers.insert(addr.to_string(), paired.key);
+    let path = machines.save()?;
+    eprintln!(
+        "Paired with {}, its key is saved in {}, connect with `clipshare connect {addr}` from now on",
+        paired.name,
+        path.display()
+    );
+    Ok(())
+}
+
+/// Opens a connection to `addr`, a Unix socket with a `unix:` prefix or a host and port,
+/// returning it along with the IP and host of the server.
+async fn dial(
+    addr: &str,
+    keepalive: Duration,
+) -> Result<(Box<dyn Stream>, IpAddr, &str), Box<dyn Error + Send + Sync>> {
+    match addr.strip_prefix("unix:") {
+        Some(path) => {
+            let (stream, ip) = connect_unix(Path::new(path)).await?;
+            Ok((stream, ip, "localhost"))
+        }
+        None => {
+            let (stream, ip) = connect_tcp(addr, keepalive).await?;
+            Ok((stream, ip, addr))
+        }
+    }
+}
+
+/// Connects to the server at `addr`, a host and port, enabling TCP keepalive.
+async fn connect_tcp(
+    addr: &str,
+    keepalive: Duration,
+) -> Result<(Box<dyn Stream>, IpAddr), Box<dyn Error + Send + Sync>> {
+    // Resolved separately so a DNS failure can be told apart from an unreachable server
+    let addrs = lookup_host(addr)
+        .await
+        .map_err(|err| {
+            debug!(error = %err, "Failed to resolve {addr}");
+            match err.kind() {
+                io::ErrorKind::InvalidInput => "not a valid address, expected host:port",
+                _ => "couldn't resolve host, check its name or use its IP address",
+            }
+        })?
+        .collect::<Vec<_>>();
+    let stream = TcpStream::connect(&addrs[..]).await.map_err(|err| {
+        debug!(error = %err, "Failed to connect to {addr}");
+        match err.kind() {
+            io::ErrorKind::ConnectionRefused => {
+                "connection refused, is clipshare running on the server?".to_string()
+            }
+            io::ErrorKind::TimedOut
+            | io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable => {
+                "host unreachable, is it on the same network?".to_string()
+            }
+            _ => err.to_string(),
+        }
+    })?;
+    set_keepalive(&stream, keepalive);
     let ip = stream.peer_addr()?.ip();
+    Ok((Box::new(stream), ip))
+}
+
+/// Connects to the server listening on the Unix socket at `path`, the peer being localhost.
+async fn connect_unix(
+    path: &Path,
+) -> Result<(Box<dyn Stream>, IpAddr), Box<dyn Error + Send + Sync>> {
+    #[cfg(unix)]
+    {
+        let stream = tokio::net::UnixStream::connect(path).await.map_err(|err| {
+            debug!(error = %err, "Failed to connect to {}", path.display());
+            match err.kind() {
+                io::ErrorKind::NotFound | io::ErrorKind::ConnectionRefused => {
+                    "no socket there, is clipshare running with --socket?".to_string()
+                }
+                _ => err.to_string(),
+            }
+        })?;
+        Ok((Box::new(stream), IpAddr::V4(Ipv4Addr::LOCALHOST)))
+    }
+    #[cfg(not(unix))]
+    Err(format!(
+        "Unix sockets are only available on unix, can't connect to {}",
+        path.display()
+    )
+    .into())
+}
+
+/// Sets up TLS and a WebSocket on `stream` to `host`, as `transport` asks.
+async fn client_transport(
+    session: &ClipshareSession<Backend>,
+    stream: Box<dyn Stream>,
+    host: &str,
+    transport: Transport<TlsConnector>,
+    ip: IpAddr,
+) -> Result<Box<dyn Stream>, Box<dyn Error + Send + Sync>> {
+    let stream: Box<dyn Stream> = match transport.tls {
+        Some(ref connector) => {
+            let stream = connector
+                .connect(tls::server_name(), stream)
+                .await
+                .inspect_err(|err| {
+                    events::emit(Event::HandshakeFailed {
+                        ip,
+                        error: &err.to_string(),
+                    })
+                })?;
+            trace!("TLS handshake completed");
+            Box::new(stream)
+        }
+        None => stream,
+    };
+    if !transport.websocket {
+        return Ok(stream);
+    }
+
+    // Any path does, the server doesn't look at it
+    let url = match transport.tls {
+        Some(_) => format!("wss://{host}/"),
+        None => format!("ws://{host}/"),
+    };
+    let wait = session.options().handshake_timeout;
+    let stream = match timeout(wait, ws::connect(stream, &url)).await {
+        Ok(stream) => stream,
+        Err(_) => Err("The server did not complete the WebSocket handshake in time".into()),
+    }
+    .inspect_err(|err| {
+        events::emit(Event::HandshakeFailed {
+            ip,
+            error: &err.to_string(),
+        })
+    })?;
+    Ok(Box::new(stream))
+}
 
-    let (reader, mut writer) = tokio::io::split(stream);
-    let span = error_span!("Connection", %ip).entered();
-    eprintln!("Clipboards connected");
+async fn client_session(
+    session: &ClipshareSession<Backend>,
+    stream: impl AsyncRead + AsyncWrite + Send + Unpin,
+    ip: IpAddr,
+    notify: bool,
+    once: bool,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let connection = session.connect(stream).await.inspect_err(|err| {
+        events::emit(Event::HandshakeFailed {
+            ip,
+            error: &err.to_string(),
+        })
+    })?;
+    eprintln!("Clipboards connected to {}", connection.peer());
+    events::emit(Event::HandshakeOk {
+        ip,
+        peer: connection.peer(),
+    });
 
-    // 发送一个密钥
-    let buf = [
-        &[0][..],
-        &u64::try_from(key.as_bytes().len())?.to_be_bytes()[..],
-    ]
-    .concat();
-    writer.write_all(&buf).await?;
-    writer.write_all(key.as_bytes()).await?;
-    writer.flush().await?;
+    if once {
+        let (tx, rx) = mpsc::unbounded_channel();
+        let peer = connection.peer().to_string();
+        let once = once_session(session, connection.with_events(tx));
+        let (result, _) = tokio::join!(once, events::report(ip, &peer, rx));
+        events::emit(Event::Disconnected { ip });
+        return result;
+    }
 
-    if let Err(err) = select! {
-        result = recv_clipboard(clipboard.clone(), reader).in_current_span() => result,
-        result = send_clipboard(clipboard.clone(), writer).in_current_span() => result,
-    } {
-        debug!(error = %err, "Client error");
+    if let Err(err) = sync(connection, ip, notify).in_current_span().await {
+        log_end(&err);
+        if matches!(err, ClipshareError::Closed) {
+            eprintln!("The server went away");
+        }
     }
+    events::emit(Event::Disconnected { ip });
 
     trace!("Finish client connection");
-    span.exit();
-    eprintln!("Clipboard closed");
     Ok(())
 }
 
-#[instrument(skip(clipboard, stream))]
-async fn send_clipboard(
-    clipboard: Arc<Clipboard>,
-    mut stream: impl AsyncWrite + Send + Unpin,
+/// Sends or receives a single clipboard on `connection`, depending on the direction.
+async fn once_session<S: AsyncRead + AsyncWrite + Send + Unpin>(
+    session: &ClipshareSession<Backend>,
+    connection: Connection<S, Backend>,
 ) -> Result<(), Box<dyn Error + Send + Sync>> {
-    loop {
-        clipboard
-            .paste()
-            .in_current_span()
-            .await?
-            .write(&mut stream)
-            .in_current_span()
-            .await?;
-        stream.flush().await?;
+    match session.options().direction {
+        Direction::Send => {
+            let obj = session
+                .clipboard()
+                .current()
+                .await?
+                .ok_or("The clipboard is empty, nothing to send")?;
+            connection.send_once(&obj).await?;
+            if !session.options().dry_run {
+                eprintln!("Clipboard sent");
+            }
+        }
+        _ => {
+            connection.recv_once().await?;
+            if !session.options().dry_run {
+                eprintln!("Clipboard received");
+            }
+        }
     }
+    Ok(())
 }
 
-#[instrument(skip(clipboard, stream))]
-async fn recv_clipboard(
-    clipboard: Arc<Clipboard>,
-    mut stream: impl AsyncRead + Send + Unpin,
-) -> Result<(), Box<dyn Error + Send + Sync>> {
-    loop {
-        let obj = ClipboardObject::from_reader(&mut stream)
-            .in_current_span()
-            .await?;
-        clipboard.copy(obj).in_current_span().await?;
+/// Logs the settings in effect once the command line, the config file and the defaults are
+/// merged, at debug level, along with the `key` picked and the `keys` accepted from wherever
+/// they came from. Keys are never logged, only whether the default one is used and the names of
+/// those accepted.
+fn log_config(args: &Args, mode: Mode, key: &str, keys: &Keys) {
+    let include = args.include.iter().map(Regex::as_str).collect::<Vec<_>>();
+    let exclude = args.exclude.iter().map(Regex::as_str).collect::<Vec<_>>();
+    debug!(
+        ?mode,
+        port = args.port.unwrap_or(DEFAULT_PORT),
+        bind = ?args.bind,
+        socket = ?args.socket,
+        url = ?args.url,
+        room = ?args.room,
+        transport = ?args.transport.unwrap_or_default(),
+        tls = args.tls,
+        encrypt = args.encrypt,
+        require_encryption = args.require_encryption,
+        compress = args.compress,
+        key = if key == DEFAULT_KEY { "default" } else { "<redacted>" },
+        ?keys,
+        key_file = ?args.key_file,
+        keys_file = ?args.keys_file,
+        send_only = args.send_only,
+        recv_only = args.recv_only,
+        selection = ?args.selection.unwrap_or_default(),
+        backend = ?args.backend.unwrap_or_default(),
+        relay = args.relay,
+        ?include,
+        ?exclude,
+        max_size = args.max_size.unwrap_or(DEFAULT_MAX_SIZE),
+        stream_threshold = args.stream_threshold.unwrap_or(protocol::STREAM_THRESHOLD),
+        accept = ?args.accept,
+        max_file_size = ?args.sync_files.then(|| args.max_file_size.unwrap_or(DEFAULT_MAX_FILE_SIZE)),
+        max_connections = args.max_connections.unwrap_or(DEFAULT_MAX_CONNECTIONS),
+        confirm_over = ?args.confirm_over,
+        heartbeat_secs = args.heartbeat_secs.unwrap_or(DEFAULT_HEARTBEAT),
+        clear_on_start = ?args.clear_on_start.unwrap_or_default(),
+        config = ?args.config,
+        "Effective configuration"
+    );
+}
+
+/// `level` made `verbose` levels more verbose and `quiet` levels less, staying between error and
+/// trace.
+fn offset_level(level: Level, verbose: u8, quiet: u8) -> Level {
+    const LEVELS: [Level; 5] = [
+        Level::ERROR,
+        Level::WARN,
+        Level::INFO,
+        Level::DEBUG,
+        Level::TRACE,
+    ];
+    let index = LEVELS
+        .iter()
+        .position(|&l| l == level)
+        .expect("every level is listed");
+    let index = (index + usize::from(verbose)).saturating_sub(usize::from(quiet));
+    LEVELS[index.min(LEVELS.len() - 1)]
+}
+
+/// Logs why a connection that completed the handshake ended: the peer closing it between two
+/// messages is an ordinary disconnect, it closing it partway through one a protocol error.
+fn log_end(err: &ClipshareError) {
+    match err {
+        ClipshareError::Closed => debug!("Peer disconnected"),
+        ClipshareError::Protocol(_) => warn!(error = %err, "Protocol error, dropping connection"),
+        _ => debug!(error = %err, "Connection error"),
     }
 }
+
+/// Syncs an established connection to `ip`, showing a notification for every clipboard received
+/// on it when `notify` is set and reporting what is sent and received with `--json-events`.
+async fn sync<S, B>(
+    connection: Connection<S, B>,
+    ip: IpAddr,
+    notify: bool,
+) -> Result<(), ClipshareError>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+    B: ClipboardBackend,
+{
+    let (tx, rx) = mpsc::unbounded_channel();
+    let connection = connection.with_events(tx);
+    let origin = connection.origin();
+    let clipboard = connection.clipboard().clone();
+    let peer = connection.peer().to_string();
+    let notifications = async {
+        if notify {
+            notify::received(clipboard, origin, &peer, ip).await;
+        } else {
+            std::future::pending().await
+        }
+    };
+    let sync = async {
+        select! {
+            result = connection.sync() => result,
+            () = notifications => Ok(()),
+        }
+    };
+    // The report ends once the connection is dropped, after the last of its events
+    let (result, stats) = tokio::join!(sync, events::report(ip, &peer, rx));
+    stats.show(ip);
+    result
+}
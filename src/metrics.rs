@@ -0,0 +1,205 @@
+//! Counters of what went through clipshare since it started, served at `/metrics` in the
+//! Prometheus text format with `--metrics-addr`.
+//!
+//! They are taken from the same [`Event`]s as `--json-events`, whether those are printed or not.
+
+use std::{
+    collections::BTreeMap,
+    fmt::Write,
+    io,
+    net::SocketAddr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex, PoisonError,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+    time::timeout,
+};
+use tracing::{debug, trace, warn};
+
+use clipshare::clipboard::ClipboardKind;
+
+use crate::events::Event;
+
+/// Most bytes of a request read before giving up on it, far more than a scraper sends.
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+/// Time a scraper has to send its request.
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static METRICS: Mutex<Metrics> = Mutex::new(Metrics::new());
+
+struct Metrics {
+    /// Connections accepted or opened, whether the handshake went through or not.
+    connections: u64,
+    /// Connections that completed the handshake and didn't end yet.
+    active: u64,
+    handshake_failures: u64,
+    /// Objects sent or received and the size of their contents, by direction and kind.
+    objects: BTreeMap<(&'static str, ClipboardKind), (u64, u64)>,
+}
+
+impl Metrics {
+    const fn new() -> Self {
+        Self {
+            connections: 0,
+            active: 0,
+            handshake_failures: 0,
+            objects: BTreeMap::new(),
+        }
+    }
+
+    fn object(&mut self, direction: &'static str, kind: ClipboardKind, size: usize) {
+        let (count, bytes) = self.objects.entry((direction, kind)).or_default();
+        *count += 1;
+        *bytes += size as u64;
+    }
+}
+
+/// Counts what `event` tells, once the metrics are served.
+pub fn record(event: &Event<'_>) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let mut metrics = METRICS.lock().unwrap_or_else(PoisonError::into_inner);
+    match *event {
+        Event::Connected { .. } => metrics.connections += 1,
+        Event::HandshakeOk { .. } => metrics.active += 1,
+        Event::HandshakeFailed { .. } => metrics.handshake_failures += 1,
+        Event::Disconnected { .. } => metrics.active = metrics.active.saturating_sub(1),
+        Event::Sent { kind, size, .. } => metrics.object("sent", kind, size),
+        Event::Received { kind, size, .. } => metrics.object("received", kind, size),
+        _ => {}
+    }
+}
+
+/// Listens for scrapers on `addr`, counting from then on.
+pub async fn listen(addr: SocketAddr) -> io::Result<TcpListener> {
+    let listener = TcpListener::bind(addr).await?;
+    ENABLED.store(true, Ordering::Relaxed);
+    Ok(listener)
+}
+
+/// Answers every request for `/metrics` on `listener` with the current metrics.
+pub async fn serve(listener: TcpListener) {
+    loop {
+        let (stream, addr) = match listener.accept().await {
+            Ok(accepted) => accepted,
+            Err(err) => {
+                warn!(error = %err, "Failed to accept metrics connection");
+                continue;
+            }
+        };
+        tokio::spawn(async move {
+            if let Err(err) = answer(stream).await {
+                debug!(error = %err, %addr, "Failed to answer metrics request");
+            }
+        });
+    }
+}
+
+async fn answer(mut stream: TcpStream) -> io::Result<()> {
+    let request = timeout(REQUEST_TIMEOUT, read_request(&mut stream))
+        .await
+        .map_err(|_| io::Error::from(io::ErrorKind::TimedOut))??;
+    // Only the request line matters, like `GET /metrics HTTP/1.1`
+    let line = String::from_utf8_lossy(request.split(|&b| b == b'\r').next().unwrap_or_default());
+    trace!(request = %line, "Read metrics request");
+    let mut parts = line.split(' ');
+    let (status, body) = match (parts.next(), parts.next(), parts.next()) {
+        (Some("GET"), Some(path), Some(_)) if path.split('?').next() == Some("/metrics") => {
+            ("200 OK", render())
+        }
+        (Some("GET"), Some(_), Some(_)) => {
+            ("404 Not Found", "Not found, try /metrics\n".to_string())
+        }
+        _ => ("400 Bad Request", "Bad request\n".to_string()),
+    };
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await
+}
+
+/// Reads up to the end of the headers, the body of a `GET` being of no interest.
+async fn read_request(stream: &mut TcpStream) -> io::Result<Vec<u8>> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|end| end == b"\r\n\r\n") {
+        if request.len() >= MAX_REQUEST_LEN {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "metrics request is too large",
+            ));
+        }
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            return Err(io::ErrorKind::UnexpectedEof.into());
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+    Ok(request)
+}
+
+/// The metrics in the Prometheus text format.
+fn render() -> String {
+    let metrics = METRICS.lock().unwrap_or_else(PoisonError::into_inner);
+    let mut out = String::new();
+    let mut metric = |name: &str, kind: &str, help: &str, samples: &[(String, u64)]| {
+        let _ = writeln!(out, "# HELP {name} {help}\n# TYPE {name} {kind}");
+        for (labels, value) in samples {
+            let _ = writeln!(out, "{name}{labels} {value}");
+        }
+    };
+    metric(
+        "clipshare_connections_active",
+        "gauge",
+        "Connections that completed the handshake and are still open.",
+        &[(String::new(), metrics.active)],
+    );
+    metric(
+        "clipshare_connections_total",
+        "counter",
+        "Connections accepted or opened.",
+        &[(String::new(), metrics.connections)],
+    );
+    metric(
+        "clipshare_handshake_failures_total",
+        "counter",
+        "Connections dropped because the handshake failed, a wrong key among other reasons.",
+        &[(String::new(), metrics.handshake_failures)],
+    );
+    let labels = |direction: &str, kind: &ClipboardKind| {
+        format!("{{direction=\"{direction}\",kind=\"{kind}\"}}")
+    };
+    let objects = metrics
+        .objects
+        .iter()
+        .map(|((direction, kind), &(count, _))| (labels(direction, kind), count))
+        .collect::<Vec<_>>();
+    metric(
+        "clipshare_objects_total",
+        "counter",
+        "Clipboard objects sent or received.",
+        &objects,
+    );
+    let bytes = metrics
+        .objects
+        .iter()
+        .map(|((direction, kind), &(_, bytes))| (labels(direction, kind), bytes))
+        .collect::<Vec<_>>();
+    metric(
+        "clipshare_bytes_total",
+        "counter",
+        "Size of the contents of the clipboard objects sent or received, before compression and encryption.",
+        &bytes,
+    );
+    out
+}
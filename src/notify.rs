@@ -0,0 +1,63 @@
+use std::{net::IpAddr, sync::Arc};
+
+use clipshare::{
+    backend::ClipboardBackend,
+    clipboard::{Clipboard, Origin},
+    history,
+};
+use notify_rust::Notification;
+use tokio::sync::broadcast::error::RecvError;
+use tracing::{trace, warn};
+
+/// Shows a desktop notification for every clipboard change received from `origin`, the
+/// connection to the peer named `peer` at `ip`, until the clipboard is gone.
+pub async fn received<B: ClipboardBackend>(
+    clipboard: Arc<Clipboard<B>>,
+    origin: Origin,
+    peer: &str,
+    ip: IpAddr,
+) {
+    let mut updates = clipboard.subscribe();
+    loop {
+        let update = match updates.recv().await {
+            Ok(update) => update,
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => return,
+        };
+        if update.origin != origin {
+            continue;
+        }
+
+        let summary = format!(
+            "Clipboard received from {peer} ({ip}): {} bytes",
+            update.obj.size()
+        );
+        let body = history::summary(&update.obj);
+        trace!("Showing clipboard notification");
+        show(summary, body);
+    }
+}
+
+/// Shows a desktop notification that syncing was paused or resumed.
+pub fn paused(paused: bool) {
+    let summary = if paused {
+        "Clipboard syncing paused"
+    } else {
+        "Clipboard syncing resumed"
+    };
+    show(summary.to_string(), String::new());
+}
+
+fn show(summary: String, body: String) {
+    // Talking to the notification daemon blocks, so keep it off the runtime
+    tokio::task::spawn_blocking(move || {
+        if let Err(err) = Notification::new()
+            .appname("clipshare")
+            .summary(&summary)
+            .body(&body)
+            .show()
+        {
+            warn!(error = %err, "Failed to show notification");
+        }
+    });
+}
@@ -0,0 +1,70 @@
+//! The machines this one was paired with, remembered in `paired.toml` in the config dir.
+
+use std::{
+    collections::BTreeMap,
+    error::Error,
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Default, Deserialize, Serialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PairedMachines {
+    /// Keys of the clients that paired with this server, by their name.
+    pub clients: BTreeMap<String, String>,
+    /// Keys of the servers this machine paired with, by the url it paired through.
+    pub servers: BTreeMap<String, String>,
+}
+
+impl PairedMachines {
+    /// Where the paired machines are kept, `None` when there is no config dir.
+    pub fn path() -> Option<PathBuf> {
+        dirs::config_dir().map(|dir| dir.join("clipshare").join("paired.toml"))
+    }
+
+    /// The paired machines, none when the file doesn't exist yet.
+    pub fn load() -> Result<Self, Box<dyn Error + Send + Sync>> {
+        let Some(path) = Self::path() else {
+            return Ok(Self::default());
+        };
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => {
+                return Err(format!("Could not read {}: {err}", path.display()).into());
+            }
+        };
+        toml::from_str(&contents)
+            .map_err(|err| format!("Invalid paired machines file {}: {err}", path.display()).into())
+    }
+
+    /// Writes the paired machines back, only readable by the current user as they hold keys.
+    pub fn save(&self) -> Result<PathBuf, Box<dyn Error + Send + Sync>> {
+        let path = Self::path().ok_or("Could not find a config dir to remember the pairing")?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        write_private(&path, &toml::to_string(self)?)
+            .map_err(|err| format!("Could not write {}: {err}", path.display()))?;
+        Ok(path)
+    }
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: &str) -> io::Result<()> {
+    fs::write(path, contents)
+}
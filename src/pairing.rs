@@ -0,0 +1,262 @@
+//! Pairing two machines with a short numeric code, instead of typing a long key on both.
+//!
+//! The server hosts and shows a code, a client joins and is given it by the user, as part of the
+//! [`handshake`](crate::handshake). Both ends run SPAKE2 keyed with the code and send their name
+//! along: someone who doesn't know the code can't learn the resulting secret, and a failed
+//! attempt tells nothing about the code worth guessing offline. The joiner then proves it derived
+//! the same secret, the host answers with its own proof, and both turn the secret into the key
+//! they connect with from then on.
+
+use std::{
+    fmt::{self, Write},
+    sync::{Arc, Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use aes_gcm::aead::{rand_core::RngCore, OsRng};
+use hkdf::Hkdf;
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use spake2::{Ed25519Group, Identity, Password, Spake2};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt};
+use tracing::{debug, trace};
+
+use crate::{codec, error::ClipshareError, handshake::MAX_NAME_LEN};
+
+/// Tag of the SPAKE2 message of either end, followed by its name.
+const EXCHANGE: u8 = 1;
+/// Tag of the proof of either end that it derived the same secret.
+const CONFIRM: u8 = 2;
+/// Tag of the empty message sent by the host when the joiner's proof is wrong.
+const FAILED: u8 = 3;
+
+/// Digits of a pairing code.
+pub const CODE_LEN: usize = 6;
+/// Length of a SPAKE2 message over Ed25519, its side byte included.
+const EXCHANGE_LEN: usize = 33;
+const PROOF_LEN: usize = 32;
+const HOST_ID: &[u8] = b"clipshare pairing host";
+const JOINER_ID: &[u8] = b"clipshare pairing joiner";
+const KEY_INFO: &[u8] = b"clipshare paired key";
+
+/// What pairing agreed on with the other machine.
+#[derive(Debug, Clone)]
+pub struct Paired {
+    /// Name the other machine goes by.
+    pub name: String,
+    /// Key both ends connect with, as hex.
+    pub key: String,
+}
+
+/// A code a server offers the clients asking to pair, until it expires or one of them tries it.
+#[derive(Clone)]
+pub struct Pairing {
+    code: Arc<Mutex<Option<String>>>,
+    expires: Instant,
+    on_paired: Arc<dyn Fn(&Paired) + Send + Sync>,
+}
+
+impl fmt::Debug for Pairing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Pairing")
+            .field("expires", &self.expires)
+            .finish_non_exhaustive()
+    }
+}
+
+impl Pairing {
+    /// Offers a fresh random code for `lifetime`, calling `on_paired` once a client paired with
+    /// it, after the key was added to the ones the server accepts.
+    pub fn new(lifetime: Duration, on_paired: impl Fn(&Paired) + Send + Sync + 'static) -> Self {
+        let code = OsRng.next_u32() % 10u32.pow(CODE_LEN as u32);
+        Self {
+            code: Arc::new(Mutex::new(Some(format!("{code:0CODE_LEN$}")))),
+            expires: Instant::now() + lifetime,
+            on_paired: Arc::new(on_paired),
+        }
+    }
+
+    /// The code to show the user, unless it expired or was already tried.
+    pub fn code(&self) -> Option<String> {
+        let code = self.code.lock().unwrap_or_else(PoisonError::into_inner);
+        code.clone().filter(|_| Instant::now() < self.expires)
+    }
+
+    /// Takes the code for a client to try, so no other client can.
+    pub(crate) fn take(&self) -> Option<String> {
+        let code = self
+            .code
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        code.filter(|_| Instant::now() < self.expires)
+    }
+
+    pub(crate) fn paired(&self, paired: &Paired) {
+        (self.on_paired)(paired)
+    }
+}
+
+/// Runs the host side of pairing as `name`, with the `code` it showed the user.
+///
+/// Any failure means the machines aren't paired, and the code must not be used again.
+pub async fn host(
+    mut reader: impl AsyncRead + Send + Unpin,
+    mut writer: impl AsyncWrite + Send + Unpin,
+    code: &str,
+    name: &str,
+) -> Result<Paired, ClipshareError> {
+    let (spake, message) = Spake2::<Ed25519Group>::start_a(
+        &Password::new(code),
+        &Identity::new(HOST_ID),
+        &Identity::new(JOINER_ID),
+    );
+    let (theirs, peer) = read_exchange(&mut reader).await?;
+    write_exchange(&mut writer, &message, name.as_bytes()).await?;
+    let secret = spake
+        .finish(&theirs)
+        .map_err(|err| ClipshareError::protocol(format!("Invalid pairing message: {err}")))?;
+
+    // The joiner goes first, so a wrong code never gets a proof of the right one back
+    let (tag, proof) = codec::read_message(&mut reader, PROOF_LEN as u64, "Pairing proof").await?;
+    if tag != CONFIRM || !verify(&secret, JOINER_ID, name.as_bytes(), &peer, &proof) {
+        codec::write_message(&mut writer, FAILED, &[]).await?;
+        writer.shutdown().await?;
+        return Err(ClipshareError::auth(
+            "Pairing failed, the code entered was wrong",
+        ));
+    }
+    let proof = prove(&secret, HOST_ID, name.as_bytes(), &peer);
+    codec::write_message(&mut writer, CONFIRM, &proof).await?;
+    writer.flush().await?;
+    let peer = peer_name(&peer);
+    debug!(peer, "Paired");
+
+    Ok(Paired {
+        name: peer,
+        key: derive_key(&secret),
+    })
+}
+
+/// Runs the joining side of pairing as `name`, with the `code` the host showed.
+pub async fn join(
+    mut reader: impl AsyncRead + Send + Unpin,
+    mut writer: impl AsyncWrite + Send + Unpin,
+    code: &str,
+    name: &str,
+) -> Result<Paired, ClipshareError> {
+    let (spake, message) = Spake2::<Ed25519Group>::start_b(
+        &Password::new(code),
+        &Identity::new(HOST_ID),
+        &Identity::new(JOINER_ID),
+    );
+    write_exchange(&mut writer, &message, name.as_bytes()).await?;
+    let (theirs, peer) = read_exchange(&mut reader).await?;
+    let secret = spake
+        .finish(&theirs)
+        .map_err(|err| ClipshareError::protocol(format!("Invalid pairing message: {err}")))?;
+
+    let proof = prove(&secret, JOINER_ID, &peer, name.as_bytes());
+    codec::write_message(&mut writer, CONFIRM, &proof).await?;
+    writer.flush().await?;
+
+    let (tag, proof) = codec::read_message(&mut reader, PROOF_LEN as u64, "Pairing proof").await?;
+    match tag {
+        CONFIRM if verify(&secret, HOST_ID, &peer, name.as_bytes(), &proof) => {}
+        FAILED => {
+            return Err(ClipshareError::auth(
+                "Pairing failed, the code entered was wrong",
+            ))
+        }
+        _ => {
+            return Err(ClipshareError::auth(
+                "Pairing failed, the other machine's proof is wrong",
+            ))
+        }
+    }
+    let peer = peer_name(&peer);
+    debug!(peer, "Paired");
+
+    Ok(Paired {
+        name: peer,
+        key: derive_key(&secret),
+    })
+}
+
+async fn write_exchange(
+    mut writer: impl AsyncWrite + Send + Unpin,
+    message: &[u8],
+    name: &[u8],
+) -> Result<(), ClipshareError> {
+    if name.len() > MAX_NAME_LEN {
+        return Err(ClipshareError::handshake(format!(
+            "Names are at most {MAX_NAME_LEN} bytes long"
+        )));
+    }
+    codec::write_message(&mut writer, EXCHANGE, &[message, name].concat()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// The SPAKE2 message of the other end and its name, as it was sent.
+async fn read_exchange(
+    mut reader: impl AsyncRead + Send + Unpin,
+) -> Result<(Vec<u8>, Vec<u8>), ClipshareError> {
+    let (tag, body) = codec::read_message(
+        &mut reader,
+        (EXCHANGE_LEN + MAX_NAME_LEN) as u64,
+        "Pairing message",
+    )
+    .await?;
+    trace!("Read pairing message");
+    if tag != EXCHANGE || body.len() < EXCHANGE_LEN {
+        return Err(ClipshareError::protocol("Malformed pairing message"));
+    }
+    let (message, name) = body.split_at(EXCHANGE_LEN);
+    Ok((message.to_vec(), name.to_vec()))
+}
+
+/// The name the other end sent, without anything that would garble a terminal.
+fn peer_name(name: &[u8]) -> String {
+    String::from_utf8_lossy(name)
+        .chars()
+        .filter(|c| !c.is_control())
+        .collect()
+}
+
+/// HMAC of the names of both ends keyed with the secret, as the end identified by `id`, so a
+/// proof can't be sent back as the other end's and the names can't be swapped on the way.
+fn proof(secret: &[u8], id: &[u8], host: &[u8], joiner: &[u8]) -> Hmac<Sha256> {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret).expect("HMAC takes keys of any length");
+    mac.update(id);
+    mac.update(&[host.len() as u8]);
+    mac.update(host);
+    mac.update(joiner);
+    mac
+}
+
+fn prove(secret: &[u8], id: &[u8], host: &[u8], joiner: &[u8]) -> Vec<u8> {
+    proof(secret, id, host, joiner)
+        .finalize()
+        .into_bytes()
+        .to_vec()
+}
+
+/// Whether `expected` is the proof of the end identified by `id`, compared in constant time.
+fn verify(secret: &[u8], id: &[u8], host: &[u8], joiner: &[u8], expected: &[u8]) -> bool {
+    proof(secret, id, host, joiner)
+        .verify_slice(expected)
+        .is_ok()
+}
+
+/// The key both ends keep, derived from the secret so it isn't the one the proofs are keyed with.
+fn derive_key(secret: &[u8]) -> String {
+    let mut key = [0; 32];
+    Hkdf::<Sha256>::new(None, secret)
+        .expand(KEY_INFO, &mut key)
+        .expect("32 bytes is a valid HKDF-SHA256 output length");
+    key.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{byte:02x}");
+        hex
+    })
+}
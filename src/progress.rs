@@ -0,0 +1,41 @@
+//! Terminal progress bar for large transfers with `--progress`, drawn on stderr.
+
+use std::{
+    io::{self, Write},
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+const WIDTH: u64 = 30;
+
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// Redraws the bar for a transfer `done` of `total` bytes in, ending the line once it's over.
+pub fn show(label: &str, done: u64, total: u64) {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return;
+    }
+    let total = total.max(1);
+    let filled = (done.saturating_mul(WIDTH) / total).min(WIDTH) as usize;
+    let mut stderr = io::stderr().lock();
+    let _ = write!(
+        stderr,
+        "\r{label} [{:<width$}] {:>3}% {} / {}",
+        "#".repeat(filled),
+        done.saturating_mul(100) / total,
+        mebibytes(done),
+        mebibytes(total),
+        width = WIDTH as usize,
+    );
+    if done >= total {
+        let _ = writeln!(stderr);
+    }
+    let _ = stderr.flush();
+}
+
+fn mebibytes(bytes: u64) -> String {
+    format!("{:.1} MiB", bytes as f64 / (1024.0 * 1024.0))
+}
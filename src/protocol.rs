@@ -0,0 +1,537 @@
+use std::{io, mem, sync::Arc, time::Duration};
+
+use tokio::{
+    io::{AsyncRead, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
+use tracing::trace;
+
+use crate::{
+    clipboard::{ClipboardKind, ClipboardObject},
+    codec,
+    crypto::Cipher,
+    error::ClipshareError,
+    image::ImageFormat,
+};
+
+/// Version of the wire protocol, bumped whenever the encoding changes incompatibly.
+pub const VERSION: u8 = 18;
+
+/// Sent by both ends before anything else, followed by their protocol version.
+const MAGIC: &[u8; 4] = b"CLIP";
+
+/// Handshake flags for a plaintext, uncompressed session.
+pub const PLAIN: u8 = 0;
+/// Handshake flag for a session where every clipboard object is encrypted.
+pub const ENCRYPTED: u8 = 0b01;
+/// Handshake flag offering (or, in the server reply, accepting) compression.
+pub const COMPRESSED: u8 = 0b10;
+/// Every handshake flag this version understands.
+pub const FLAGS: u8 = ENCRYPTED | COMPRESSED;
+
+/// Room allowed on top of `--max-size` for the object header, and on top of a chunk for the
+/// frame tag and the cipher nonce and tag.
+const FRAME_OVERHEAD: usize = 1024;
+
+/// Largest piece of an object streamed in a frame.
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Largest object sent in a single frame unless told otherwise, bigger ones being streamed.
+pub const STREAM_THRESHOLD: usize = CHUNK_SIZE;
+
+/// Largest stream threshold an end can ask for, as the peer holds a whole frame in memory.
+pub const MAX_STREAM_THRESHOLD: usize = 16 * 1024 * 1024;
+
+/// Tag of a frame followed by more of the same object.
+const MORE: u8 = 0;
+/// Tag of the frame completing an object.
+const LAST: u8 = 1;
+/// Tag of an empty frame telling the object was given up on.
+const ABORT: u8 = 2;
+/// Tag of an empty frame sent when there was nothing else to send for a while, telling the
+/// peer is still there.
+const PING: u8 = 3;
+
+/// Heartbeat intervals the peer can miss before it is taken as gone.
+const MISSED_PINGS: u32 = 3;
+
+/// Bytes of the sequence number and node id preceding every object, two big endian `u64`s.
+const STAMP_LEN: usize = 2 * mem::size_of::<u64>();
+
+/// Payloads smaller than this are sent uncompressed, as compressing them isn't worth it.
+const COMPRESSION_THRESHOLD: usize = 1024;
+
+/// Writes the magic prefix and the protocol version this build speaks.
+pub async fn write_hello(mut writer: impl AsyncWrite + Send + Unpin) -> Result<(), ClipshareError> {
+    writer.write_all(&[&MAGIC[..], &[VERSION]].concat()).await?;
+    writer.flush().await?;
+    Ok(())
+}
+
+/// Reads the peer's magic prefix, returning its protocol version.
+pub async fn read_hello(mut reader: impl AsyncRead + Send + Unpin) -> Result<u8, ClipshareError> {
+    let mut buf = [0; MAGIC.len() + 1];
+    codec::read_start(&mut reader, &mut buf).await?;
+    if buf[..MAGIC.len()] != MAGIC[..] {
+        return Err(ClipshareError::protocol(
+            "Peer is not speaking the clipshare protocol (or is too old)",
+        ));
+    }
+    let version = buf[MAGIC.len()];
+    trace!(version, "Read peer protocol version");
+    Ok(version)
+}
+
+/// How clipboard objects are framed on a connection once the handshake is done.
+///
+/// Each object, preceded by the sequence number of the change and the
+/// [`Clipboard::node`](crate::clipboard::Clipboard::node) it was made on, both as big endian
+/// `u64`s, is sent in a single frame when it is no larger than the stream threshold both ends
+/// agreed on, 64 KiB by default. A larger one is streamed, split into chunks of at most 64 KiB
+/// (or the threshold when smaller), so it doesn't have to be sent (or given up on) all at once
+/// and its progress can be followed. Every chunk is sent as a frame of its length as a big
+/// endian `u32` followed by the body:
+/// a tag telling whether more of the object follows, a byte telling whether the chunk is zstd
+/// compressed (only when compression was negotiated) and the chunk itself, all of it encrypted
+/// when a cipher is in use. An empty frame tagged as aborted makes the peer drop what it received
+/// of the current object.
+///
+/// With a heartbeat, both ends send an empty ping frame whenever they had nothing to send for
+/// its interval, and give up on a peer they heard nothing from for three of them. Each end
+/// pings on its own, so there are no pongs to wait for.
+#[derive(Debug, Clone)]
+pub struct Protocol {
+    cipher: Option<Arc<Cipher>>,
+    compress: bool,
+    max_size: u64,
+    lossy: bool,
+    heartbeat: Option<Duration>,
+    image_format: ImageFormat,
+    stream_threshold: usize,
+    /// Bits of the kinds this end accepts, see [`ClipboardKind::bit`].
+    accepts: u16,
+    /// Bits of the kinds the peer accepts, once negotiated.
+    peer_accepts: u16,
+}
+
+impl Protocol {
+    pub fn new(max_size: u64) -> Self {
+        Self {
+            cipher: None,
+            compress: false,
+            max_size,
+            lossy: false,
+            heartbeat: None,
+            image_format: ImageFormat::Raw,
+            stream_threshold: STREAM_THRESHOLD,
+            accepts: u16::MAX,
+            peer_accepts: u16::MAX,
+        }
+    }
+
+    pub fn encrypted(self, cipher: Cipher) -> Self {
+        Self {
+            cipher: Some(Arc::new(cipher)),
+            ..self
+        }
+    }
+
+    pub fn compressed(self) -> Self {
+        Self {
+            compress: true,
+            ..self
+        }
+    }
+
+    /// Replaces invalid UTF-8 in received text instead of skipping it.
+    pub fn lossy(self) -> Self {
+        Self {
+            lossy: true,
+            ..self
+        }
+    }
+
+    /// Pings the peer after `interval` without sending anything else, and drops it after not
+    /// hearing from it for a few of them. The peer should use the same interval.
+    pub fn heartbeat(self, interval: Duration) -> Self {
+        Self {
+            heartbeat: Some(interval),
+            ..self
+        }
+    }
+
+    /// Sends images encoded as `format`, the peer reads any of them.
+    pub fn image_format(self, format: ImageFormat) -> Self {
+        Self {
+            image_format: format,
+            ..self
+        }
+    }
+
+    /// Sends objects of up to `bytes` in a single frame, and streams larger ones, once the peer
+    /// agreed. At most [`MAX_STREAM_THRESHOLD`].
+    pub fn streaming_over(self, bytes: usize) -> Self {
+        Self {
+            stream_threshold: bytes.clamp(1, MAX_STREAM_THRESHOLD),
+            ..self
+        }
+    }
+
+    /// Only takes `kinds` from the peer, which it is told in the handshake so it sends the
+    /// richest form of its objects among them, or nothing. Every kind by default.
+    pub fn accepting(self, kinds: &[ClipboardKind]) -> Self {
+        Self {
+            accepts: kinds.iter().fold(0, |bits, kind| bits | kind.bit()),
+            ..self
+        }
+    }
+
+    /// Whether this end takes objects of `kind`.
+    pub fn accepts(&self, kind: ClipboardKind) -> bool {
+        self.accepts & kind.bit() != 0
+    }
+
+    /// Whether the peer takes objects of `kind`.
+    pub fn peer_accepts(&self, kind: ClipboardKind) -> bool {
+        self.peer_accepts & kind.bit() != 0
+    }
+
+    /// Bits of the kinds this end accepts, announced in the handshake.
+    pub fn accepted_kinds(&self) -> u16 {
+        self.accepts
+    }
+
+    /// Largest object sent in a single frame, announced in the handshake.
+    pub fn stream_threshold(&self) -> usize {
+        self.stream_threshold
+    }
+
+    /// How long to wait before pinging an idle peer, if at all.
+    pub fn heartbeat_interval(&self) -> Option<Duration> {
+        self.heartbeat
+    }
+
+    /// Largest payload accepted from the peer, and sent to it once negotiated.
+    pub fn max_size(&self) -> u64 {
+        self.max_size
+    }
+
+    /// Flags announced in the handshake.
+    pub fn flags(&self) -> u8 {
+        let mut flags = PLAIN;
+        if self.cipher.is_some() {
+            flags |= ENCRYPTED;
+        }
+        if self.compress {
+            flags |= COMPRESSED;
+        }
+        flags
+    }
+
+    /// The protocol to use with a peer announcing `flags`, accepting payloads of up to
+    /// `max_size` bytes and objects of the kinds in `accepts`, and asking for
+    /// `stream_threshold`, compressing only if both ends support it, and holding payloads and
+    /// single frame objects both ways to the smaller of the two.
+    pub fn negotiate(
+        &self,
+        flags: u8,
+        max_size: u64,
+        stream_threshold: usize,
+        accepts: u16,
+    ) -> Self {
+        Self {
+            compress: self.compress && flags & COMPRESSED != 0,
+            max_size: self.max_size.min(max_size),
+            stream_threshold: self.stream_threshold.min(stream_threshold.max(1)),
+            peer_accepts: accepts,
+            ..self.clone()
+        }
+    }
+
+    /// Reads the next clipboard object, the sequence number of its change and the node it was
+    /// made on, skipping over the ones that can't be used or that the peer gave up on.
+    pub async fn read(
+        &self,
+        reader: impl AsyncRead + Send + Unpin,
+    ) -> Result<(u64, u64, ClipboardObject), ClipshareError> {
+        self.read_with_progress(reader, |_, _| {}).await
+    }
+
+    /// Like [`Self::read`], calling `progress` with the bytes received so far and the total
+    /// after every frame of an object sent over several of them.
+    pub async fn read_with_progress(
+        &self,
+        mut reader: impl AsyncRead + Send + Unpin,
+        mut progress: impl FnMut(u64, u64) + Send,
+    ) -> Result<(u64, u64, ClipboardObject), ClipshareError> {
+        loop {
+            if let (seq, node, Some(obj)) = self.read_object(&mut reader, &mut progress).await? {
+                return Ok((seq, node, obj));
+            }
+        }
+    }
+
+    async fn read_object(
+        &self,
+        mut reader: impl AsyncRead + Send + Unpin,
+        progress: &mut (impl FnMut(u64, u64) + Send),
+    ) -> Result<(u64, u64, Option<ClipboardObject>), ClipshareError> {
+        let mut object = Vec::new();
+        let mut frames = 0;
+        loop {
+            let (tag, data) = match self.read_frame(&mut reader).await {
+                Err(ClipshareError::Closed) if !object.is_empty() => {
+                    return Err(ClipshareError::protocol(
+                        "The peer closed the connection partway through a clipboard object",
+                    ))
+                }
+                result => result?,
+            };
+            match tag {
+                MORE | LAST => {
+                    if (object.len() + data.len()) as u64 > self.object_limit() {
+                        return Err(ClipshareError::too_large(
+                            "Clipboard object",
+                            (object.len() + data.len()) as u64,
+                            self.max_size,
+                        ));
+                    }
+                    object.extend_from_slice(&data);
+                    frames += 1;
+                    if let Some(total) = object_len(&object).filter(|_| tag == MORE || frames > 1) {
+                        progress(object.len() as u64, total);
+                    }
+                    if tag == LAST {
+                        break;
+                    }
+                }
+                ABORT => {
+                    trace!(received = object.len(), "Peer gave up on the object");
+                    object.clear();
+                    frames = 0;
+                }
+                PING => trace!("Peer pinged"),
+                tag => return Err(ClipshareError::protocol(format!("Unknown frame tag {tag}"))),
+            }
+        }
+        trace!(len = object.len(), "Read object");
+
+        read_stamped(&object[..], self.max_size, self.lossy).await
+    }
+
+    /// Reads a frame, returning its tag and its decrypted and decompressed data. With a
+    /// heartbeat, the whole frame has to arrive in time, so a peer stalling halfway through one
+    /// is dropped as well.
+    async fn read_frame(
+        &self,
+        reader: impl AsyncRead + Send + Unpin,
+    ) -> Result<(u8, Vec<u8>), ClipshareError> {
+        let mut body = match self.heartbeat {
+            Some(interval) => timeout(interval * MISSED_PINGS, self.read_body(reader))
+                .await
+                .map_err(|_| {
+                    io::Error::new(io::ErrorKind::TimedOut, "The peer stopped answering")
+                })??,
+            None => self.read_body(reader).await?,
+        };
+
+        if let Some(ref cipher) = self.cipher {
+            body = cipher.decrypt(&body)?;
+        }
+
+        let (&tag, data) = body
+            .split_first()
+            .ok_or(ClipshareError::protocol("Empty clipboard frame"))?;
+        if !self.compress {
+            return Ok((tag, data.to_vec()));
+        }
+        match data.split_first() {
+            Some((&0, data)) => Ok((tag, data.to_vec())),
+            Some((_, data)) => {
+                let data = zstd::bulk::decompress(data, self.frame_limit()).map_err(|err| {
+                    ClipshareError::protocol(format!("Invalid compressed frame: {err}"))
+                })?;
+                trace!(len = data.len(), "Decompressed frame");
+                Ok((tag, data))
+            }
+            None => Err(ClipshareError::protocol(
+                "Clipboard frame is missing its compression flag",
+            )),
+        }
+    }
+
+    /// Reads the length prefixed body of a frame, as it was sent.
+    async fn read_body(
+        &self,
+        mut reader: impl AsyncRead + Send + Unpin,
+    ) -> Result<Vec<u8>, ClipshareError> {
+        let mut buf = [0; mem::size_of::<u32>()];
+        codec::read_start(&mut reader, &mut buf).await?;
+        let len = u32::from_be_bytes(buf);
+        trace!(len, "Read frame len");
+
+        if len as usize > self.frame_limit() + FRAME_OVERHEAD {
+            return Err(ClipshareError::too_large(
+                "Clipboard frame",
+                len.into(),
+                (self.frame_limit() + FRAME_OVERHEAD) as u64,
+            ));
+        }
+
+        let mut body = vec![0; len as usize];
+        codec::read_exact(&mut reader, &mut body).await?;
+        Ok(body)
+    }
+
+    /// Bytes of an object in each frame but the last one when it is streamed: the stream
+    /// threshold, up to [`CHUNK_SIZE`].
+    pub fn chunk_size(&self) -> usize {
+        self.stream_threshold.min(CHUNK_SIZE)
+    }
+
+    /// Encodes `obj` as the frames to send one after the other, along with the length of the
+    /// whole encoded object: a single one when it is no larger than the stream threshold, or
+    /// else chunks of [`Self::chunk_size`] bytes of it. Writing
+    /// [`Self::abort`] instead of the remaining frames makes the peer discard what it received of
+    /// the object.
+    ///
+    /// An object the peer would refuse as larger than [`Self::max_size`] fails with
+    /// [`ClipshareError::TooLarge`] instead, before anything is sent.
+    pub async fn frames(
+        &self,
+        seq: u64,
+        node: u64,
+        obj: &ClipboardObject,
+    ) -> Result<(Vec<Vec<u8>>, u64), ClipshareError> {
+        let mut object = [seq.to_be_bytes(), node.to_be_bytes()].concat();
+        obj.write_as(&mut object, self.image_format).await?;
+        // The payload is what the peer checks, and the pixels of a PNG once decoded
+        let payload = (object.len() - STAMP_LEN - codec::HEADER_LEN) as u64;
+        let size = payload.max(obj.size() as u64);
+        if size > self.max_size {
+            return Err(ClipshareError::too_large(
+                "Clipboard object",
+                size,
+                self.max_size,
+            ));
+        }
+
+        // Small objects go in one frame for latency, larger ones are streamed
+        let chunk_size = if object.len() <= self.stream_threshold {
+            object.len()
+        } else {
+            self.chunk_size()
+        };
+        let mut chunks = object.chunks(chunk_size).peekable();
+        let mut frames = Vec::new();
+        while let Some(chunk) = chunks.next() {
+            let tag = if chunks.peek().is_some() { MORE } else { LAST };
+            frames.push(self.frame(tag, chunk)?);
+        }
+        trace!(len = object.len(), frames = frames.len(), "Encoded object");
+        Ok((frames, object.len() as u64))
+    }
+
+    pub async fn write(
+        &self,
+        seq: u64,
+        node: u64,
+        obj: &ClipboardObject,
+        mut writer: impl AsyncWrite + Send + Unpin,
+    ) -> Result<(), ClipshareError> {
+        for frame in self.frames(seq, node, obj).await?.0 {
+            writer.write_all(&frame).await?;
+        }
+        trace!("Object sent");
+        Ok(())
+    }
+
+    /// Tells the peer this end is still there, between two objects.
+    pub async fn ping(
+        &self,
+        mut writer: impl AsyncWrite + Send + Unpin,
+    ) -> Result<(), ClipshareError> {
+        writer.write_all(&self.frame(PING, &[])?).await?;
+        writer.flush().await?;
+        trace!("Pinged peer");
+        Ok(())
+    }
+
+    /// Tells the peer to discard the object whose frames were only partly written.
+    pub async fn abort(
+        &self,
+        mut writer: impl AsyncWrite + Send + Unpin,
+    ) -> Result<(), ClipshareError> {
+        writer.write_all(&self.frame(ABORT, &[])?).await?;
+        trace!("Object aborted");
+        Ok(())
+    }
+
+    fn frame(&self, tag: u8, data: &[u8]) -> Result<Vec<u8>, ClipshareError> {
+        let mut body = vec![tag];
+        if self.compress {
+            let compressed = if data.len() >= COMPRESSION_THRESHOLD {
+                Some(zstd::bulk::compress(data, 0)?)
+                    .filter(|compressed| compressed.len() < data.len())
+            } else {
+                None
+            };
+            match compressed {
+                Some(compressed) => {
+                    trace!(
+                        len = data.len(),
+                        compressed = compressed.len(),
+                        "Compressed frame"
+                    );
+                    body.push(1);
+                    body.extend_from_slice(&compressed);
+                }
+                None => {
+                    body.push(0);
+                    body.extend_from_slice(data);
+                }
+            }
+        } else {
+            body.extend_from_slice(data);
+        }
+
+        if let Some(ref cipher) = self.cipher {
+            body = cipher.encrypt(&body)?;
+        }
+
+        // Chunks are small enough that a frame always fits
+        Ok([&(body.len() as u32).to_be_bytes()[..], &body[..]].concat())
+    }
+
+    /// Largest data a frame carries, a streamed chunk or an object sent in one.
+    fn frame_limit(&self) -> usize {
+        self.stream_threshold.max(CHUNK_SIZE)
+    }
+
+    /// Largest object accepted, its header, sequence number and node id included.
+    fn object_limit(&self) -> u64 {
+        self.max_size.saturating_add(FRAME_OVERHEAD as u64)
+    }
+}
+
+/// Length of the object being received, sequence number, node id and header included, once its
+/// header is there.
+fn object_len(object: &[u8]) -> Option<u64> {
+    let header = STAMP_LEN + codec::HEADER_LEN;
+    let len = object.get(header - mem::size_of::<u64>()..header)?;
+    let len = u64::from_be_bytes(len.try_into().ok()?);
+    Some(len.saturating_add(header as u64))
+}
+
+/// Reads a sequence number, a node id and the object following them.
+async fn read_stamped(
+    mut reader: impl AsyncRead + Send + Unpin,
+    max_size: u64,
+    lossy: bool,
+) -> Result<(u64, u64, Option<ClipboardObject>), ClipshareError> {
+    let seq = codec::read_u64(&mut reader).await?;
+    let node = codec::read_u64(&mut reader).await?;
+    trace!(seq, node, "Read sequence number and node");
+    let obj = ClipboardObject::from_reader(reader, max_size, lossy).await?;
+    Ok((seq, node, obj))
+}
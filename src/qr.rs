@@ -0,0 +1,105 @@
+//! The command another device runs to connect to this server, shown as a QR code with `--qr`.
+
+use std::{
+    error::Error,
+    fmt,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, UdpSocket},
+};
+
+use clap::ValueEnum;
+use qrcode::{render::unicode::Dense1x2, QrCode};
+
+use crate::TransportKind;
+
+/// Everything a client needs to connect to this server but the key, which is only hinted at.
+pub struct Invitation<'a> {
+    /// Server url, as given to `clipshare connect`.
+    pub url: String,
+    pub room: Option<&'a str>,
+    pub transport: TransportKind,
+    /// Fingerprint of the TLS certificate, when the server uses TLS.
+    pub tls_pin: Option<&'a str>,
+    pub encrypt: bool,
+    /// Whether the client needs a key of its own, not being given the default one.
+    pub key_required: bool,
+    /// Code to pair with instead of a key, with `--pair`.
+    pub code: Option<&'a str>,
+}
+
+impl fmt::Display for Invitation<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.code {
+            Some(code) => write!(f, "clipshare pair {} --code {code}", quote(&self.url))?,
+            None => write!(f, "clipshare connect {}", quote(&self.url))?,
+        }
+        if let Some(room) = self.room.filter(|room| !room.is_empty()) {
+            write!(f, " --room {}", quote(room))?;
+        }
+        if self.transport != TransportKind::Tcp {
+            let transport = self
+                .transport
+                .to_possible_value()
+                .expect("no variant is skipped");
+            write!(f, " --transport {}", transport.get_name())?;
+        }
+        if let Some(pin) = self.tls_pin {
+            // `wss` implies TLS already
+            if self.transport != TransportKind::Wss {
+                write!(f, " --tls")?;
+            }
+            write!(f, " --tls-pin {pin}")?;
+        }
+        if self.encrypt {
+            write!(f, " --encrypt")?;
+        }
+        if self.key_required && self.code.is_none() {
+            write!(f, " --key-stdin")?;
+        }
+        Ok(())
+    }
+}
+
+/// The url of a server listening on `addr`, with the address other machines reach this one on
+/// when it listens on all of them.
+pub fn url(addr: SocketAddr) -> Option<String> {
+    if !addr.ip().is_unspecified() {
+        return Some(addr.to_string());
+    }
+    // Connecting a UDP socket sends nothing, it only picks the interface of the default route
+    let (local, remote): (IpAddr, IpAddr) = match addr {
+        SocketAddr::V4(_) => (
+            Ipv4Addr::UNSPECIFIED.into(),
+            Ipv4Addr::new(192, 0, 2, 1).into(),
+        ),
+        SocketAddr::V6(_) => (
+            Ipv6Addr::UNSPECIFIED.into(),
+            Ipv6Addr::new(0x2001, 0xdb8, 0, 0, 0, 0, 0, 1).into(),
+        ),
+    };
+    let socket = UdpSocket::bind((local, 0)).ok()?;
+    socket.connect((remote, 9)).ok()?;
+    let ip = socket.local_addr().ok()?.ip();
+    Some(SocketAddr::new(ip, addr.port()).to_string())
+}
+
+/// Draws `text` as a QR code on stderr, with the text itself below for the ones who can't scan.
+pub fn show(text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let code = QrCode::new(text)?;
+    let image = code
+        .render::<Dense1x2>()
+        .dark_color(Dense1x2::Light)
+        .light_color(Dense1x2::Dark)
+        .build();
+    eprintln!("{image}\n{text}");
+    Ok(())
+}
+
+/// `arg` quoted for a POSIX shell, when it needs to be.
+fn quote(arg: &str) -> String {
+    let plain = |c: char| c.is_ascii_alphanumeric() || "-_.:/@%+=,".contains(c);
+    if !arg.is_empty() && arg.chars().all(plain) {
+        arg.to_string()
+    } else {
+        format!("'{}'", arg.replace('\'', r"'\''"))
+    }
+}
@@ -0,0 +1,130 @@
+//! Forwarding clipboard objects between clients that can't reach each other directly.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    sync::{Arc, Mutex},
+};
+
+use tokio::{
+    io::{split, AsyncRead, AsyncWrite},
+    time::timeout,
+};
+use tracing::debug;
+
+use crate::{
+    backend::MemoryClipboard,
+    clipboard::Clipboard,
+    error::ClipshareError,
+    handshake::{self, Keys},
+    protocol::Protocol,
+    session::{Connection, Devices, SessionOptions},
+};
+
+/// Clipboard fanning out the objects of the clients using a key and room.
+type Room = Arc<Clipboard<MemoryClipboard>>;
+
+/// A server that has no clipboard of its own, passing what a client copies on to every other
+/// client that authenticated with the same key and joined the same room.
+///
+/// Each key and room gets a clipboard of its own, only ever used to fan the objects out,
+/// created when the first client using it connects.
+pub struct Relay {
+    keys: Keys,
+    name: String,
+    protocol: Protocol,
+    options: SessionOptions,
+    rooms: Mutex<HashMap<(String, String), Room>>,
+    devices: Devices,
+}
+
+impl fmt::Debug for Relay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Relay")
+            .field("keys", &self.keys)
+            .field("name", &self.name)
+            .field("protocol", &self.protocol)
+            .field("options", &self.options)
+            .field("rooms", &self.rooms.lock().unwrap().len())
+            .finish_non_exhaustive()
+    }
+}
+
+impl Relay {
+    pub fn new(keys: Keys, protocol: Protocol) -> Self {
+        Self {
+            keys,
+            name: handshake::default_name(),
+            protocol,
+            options: SessionOptions::default(),
+            rooms: Mutex::new(HashMap::new()),
+            devices: Devices::default(),
+        }
+    }
+
+    /// Goes by `name` with the clients, instead of the hostname.
+    pub fn with_name(self, name: impl Into<String>) -> Self {
+        Self {
+            name: name.into(),
+            ..self
+        }
+    }
+
+    pub fn with_options(self, options: SessionOptions) -> Self {
+        Self { options, ..self }
+    }
+
+    pub fn options(&self) -> &SessionOptions {
+        &self.options
+    }
+
+    /// Runs the server side of the handshake on `stream`, returning a connection syncing with
+    /// the other clients using the same key and room.
+    pub async fn accept<S>(
+        &self,
+        stream: S,
+    ) -> Result<Connection<S, MemoryClipboard>, ClipshareError>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        let (mut reader, mut writer) = split(stream);
+        let handshake = handshake::server(
+            &mut reader,
+            &mut writer,
+            &self.keys,
+            None,
+            &self.name,
+            &self.protocol,
+            None,
+        );
+        let accepted = timeout(self.options.handshake_timeout, handshake)
+            .await
+            .map_err(|_| ClipshareError::handshake("Handshake timed out"))??;
+        let registration =
+            self.devices
+                .register(accepted.key.clone(), accepted.room.clone(), &accepted.peer);
+        let room = self.room(accepted.key, accepted.room);
+        Ok(Connection::new(
+            room,
+            accepted.protocol,
+            accepted.peer.name,
+            self.options.clone(),
+            reader,
+            writer,
+        )
+        .registered(registration))
+    }
+
+    /// The clipboard shared by the clients in `room` using the key named `key`.
+    fn room(&self, key: String, room: String) -> Room {
+        self.rooms
+            .lock()
+            .unwrap()
+            .entry((key, room))
+            .or_insert_with_key(|(key, room)| {
+                debug!(key, room, "Opening relay room");
+                Arc::new(Clipboard::with_backend(MemoryClipboard::new()).forwarding())
+            })
+            .clone()
+    }
+}
@@ -0,0 +1,1248 @@
+//! Running the protocol over a connection.
+
+use std::{
+    collections::HashMap,
+    fmt, future, io,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+
+use tokio::{
+    io::{split, AsyncRead, AsyncWrite, AsyncWriteExt, ReadHalf, WriteHalf},
+    select,
+    sync::{
+        broadcast::{
+            self,
+            error::{RecvError, TryRecvError},
+        },
+        mpsc, oneshot,
+    },
+    time::{sleep, sleep_until, timeout, Instant},
+};
+use tracing::{debug, info, instrument, trace, warn, Instrument};
+
+use crate::{
+    backend::ClipboardBackend,
+    clipboard::{Clipboard, ClipboardKind, ClipboardObject, Origin, Selection, Update},
+    confirm::Confirm,
+    crypto,
+    error::ClipshareError,
+    filter::Filter,
+    handshake::{self, Identity, Keys},
+    history,
+    pairing::{Paired, Pairing},
+    protocol::Protocol,
+};
+
+/// Times a write failing with a transient error is tried again before the connection is dropped.
+const WRITE_RETRIES: u32 = 3;
+const WRITE_RETRY_DELAY: Duration = Duration::from_millis(100);
+
+/// Which way clipboard changes flow on a connection.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Direction {
+    #[default]
+    Both,
+    Send,
+    Recv,
+}
+
+/// How a connection is set up and syncs the clipboards once the handshake is done.
+#[derive(Debug, Clone)]
+pub struct SessionOptions {
+    /// How long the peer has to complete the handshake.
+    pub handshake_timeout: Duration,
+    pub direction: Direction,
+    /// Minimum time between two updates sent to the peer.
+    pub min_interval: Duration,
+    /// Log a preview of every clipboard sent and received, instead of just its type and size.
+    pub log_content: bool,
+    /// Put a preview of every clipboard sent, received and declined in the [`SessionEvent`]s.
+    pub report_content: bool,
+    /// Convert the line endings of received text to the ones of this platform.
+    pub normalize_eol: bool,
+    /// Drop a single trailing newline from received text.
+    pub trim: bool,
+    /// Most received objects waiting to be written to the clipboard before reading more.
+    pub recv_queue: usize,
+    /// Send what a password manager copied too, see [`Update::sensitive`].
+    pub sync_sensitive: bool,
+    /// Send empty text too, instead of leaving the peer's clipboard alone.
+    pub sync_empty: bool,
+    /// Which copied text is sent to the peer.
+    pub filter: Arc<Filter>,
+    /// Asked before applying large or file objects, which are applied right away when `None`.
+    pub confirm: Option<Confirm>,
+    /// Set while syncing is paused: nothing is sent nor applied, and what is copied in the
+    /// meantime is never sent, even once it resumes.
+    pub paused: Arc<AtomicBool>,
+    /// Only log what would be sent and applied, after every other rule, without sending nor
+    /// applying anything.
+    pub dry_run: bool,
+}
+
+impl Default for SessionOptions {
+    fn default() -> Self {
+        Self {
+            handshake_timeout: Duration::from_secs(5),
+            direction: Direction::Both,
+            min_interval: Duration::from_millis(200),
+            log_content: false,
+            report_content: false,
+            normalize_eol: false,
+            trim: false,
+            recv_queue: 4,
+            sync_sensitive: false,
+            sync_empty: false,
+            filter: Arc::default(),
+            confirm: None,
+            paused: Arc::default(),
+            dry_run: false,
+        }
+    }
+}
+
+/// What a connection reports to the channel given to [`Connection::with_events`].
+///
+/// The `preview` of an object is only there with [`SessionOptions::report_content`].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SessionEvent {
+    /// A clipboard object was written to the peer.
+    Sent {
+        kind: ClipboardKind,
+        size: usize,
+        preview: Option<String>,
+    },
+    /// A clipboard object was received from the peer and copied.
+    Received {
+        kind: ClipboardKind,
+        size: usize,
+        preview: Option<String>,
+    },
+    /// A clipboard object was received from the peer and discarded, as it wasn't confirmed.
+    Declined {
+        kind: ClipboardKind,
+        size: usize,
+        preview: Option<String>,
+    },
+    /// Part of an object sent over several frames was written, `done` of `total` bytes so far.
+    Sending { done: u64, total: u64 },
+    /// Part of an object sent over several frames was received, `done` of `total` bytes so far.
+    Receiving { done: u64, total: u64 },
+}
+
+/// Everything needed to sync a clipboard with peers, over any stream.
+///
+/// ```no_run
+/// # async fn run() -> Result<(), clipshare::error::ClipshareError> {
+/// use std::sync::Arc;
+///
+/// use clipshare::{clipboard::Clipboard, protocol::Protocol, session::ClipshareSession};
+///
+/// let clipboard = Arc::new(Clipboard::new()?);
+/// let session = ClipshareSession::new(clipboard, "key", Protocol::new(1 << 20));
+/// let stream = tokio::net::TcpStream::connect("192.168.0.10:11337").await?;
+/// session.connect(stream).await?.sync().await
+/// # }
+/// ```
+pub struct ClipshareSession<B = arboard::Clipboard> {
+    clipboard: Arc<Clipboard<B>>,
+    key: String,
+    keys: Keys,
+    room: String,
+    identity: Identity,
+    protocol: Protocol,
+    options: SessionOptions,
+    pairing: Option<Pairing>,
+    devices: Devices,
+}
+
+// Implemented by hand, deriving would require the backend itself to be `Clone` and `Debug`
+impl<B> Clone for ClipshareSession<B> {
+    fn clone(&self) -> Self {
+        Self {
+            clipboard: self.clipboard.clone(),
+            key: self.key.clone(),
+            keys: self.keys.clone(),
+            room: self.room.clone(),
+            identity: self.identity.clone(),
+            protocol: self.protocol.clone(),
+            options: self.options.clone(),
+            pairing: self.pairing.clone(),
+            devices: self.devices.clone(),
+        }
+    }
+}
+
+impl<B> fmt::Debug for ClipshareSession<B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ClipshareSession")
+            .field("clipboard", &self.clipboard)
+            .field("keys", &self.keys)
+            .field("room", &self.room)
+            .field("identity", &self.identity)
+            .field("protocol", &self.protocol)
+            .field("options", &self.options)
+            .field("pairing", &self.pairing)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<B: ClipboardBackend> ClipshareSession<B> {
+    /// A session connecting with `key`, and accepting only that key until [`Self::with_keys`].
+    pub fn new(clipboard: Arc<Clipboard<B>>, key: impl Into<String>, protocol: Protocol) -> Self {
+        let key = key.into();
+        Self {
+            clipboard,
+            keys: Keys::single(key.clone()),
+            key,
+            room: handshake::DEFAULT_ROOM.to_string(),
+            identity: Identity {
+                name: handshake::default_name(),
+                // Ids of 0 stand for none
+                device: crypto::random_id().max(1),
+            },
+            protocol,
+            options: SessionOptions::default(),
+            pairing: None,
+            devices: Devices::default(),
+        }
+    }
+
+    /// Accepts clients using any of `keys`.
+    pub fn with_keys(self, keys: Keys) -> Self {
+        Self { keys, ..self }
+    }
+
+    /// Only syncs with peers in `room`, instead of the default one.
+    pub fn with_room(self, room: impl Into<String>) -> Self {
+        Self {
+            room: room.into(),
+            ..self
+        }
+    }
+
+    /// Goes by `name` with the peers, instead of the hostname.
+    pub fn with_name(self, name: impl Into<String>) -> Self {
+        Self {
+            identity: Identity {
+                name: name.into(),
+                ..self.identity
+            },
+            ..self
+        }
+    }
+
+    /// Tells the server `device` is the id of this end, so it closes what is left of the last
+    /// connection of this end going by the same name when it connects again. Every session
+    /// picks one of its own at random, so only its own reconnects take over, and `0` sends
+    /// none, leaving every connection alone.
+    pub fn with_device(self, device: u64) -> Self {
+        Self {
+            identity: Identity {
+                device,
+                ..self.identity
+            },
+            ..self
+        }
+    }
+
+    pub fn with_options(self, options: SessionOptions) -> Self {
+        Self { options, ..self }
+    }
+
+    /// Offers the code of `pairing` to the clients asking to pair.
+    pub fn with_pairing(self, pairing: Pairing) -> Self {
+        Self {
+            pairing: Some(pairing),
+            ..self
+        }
+    }
+
+    pub fn clipboard(&self) -> &Arc<Clipboard<B>> {
+        &self.clipboard
+    }
+
+    pub fn options(&self) -> &SessionOptions {
+        &self.options
+    }
+
+    /// Runs the server side of the handshake on `stream`, failing when the peer is rejected or
+    /// doesn't complete it in time.
+    pub async fn accept<S>(&self, stream: S) -> Result<Connection<S, B>, ClipshareError>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        let (mut reader, mut writer) = split(stream);
+        let handshake = handshake::server(
+            &mut reader,
+            &mut writer,
+            &self.keys,
+            Some(&self.room),
+            &self.identity.name,
+            &self.protocol,
+            self.pairing.as_ref(),
+        );
+        // A peer that connects and then stalls would otherwise hold on to the connection forever
+        let accepted = timeout(self.options.handshake_timeout, handshake)
+            .await
+            .map_err(|_| ClipshareError::handshake("Handshake timed out"))??;
+        let registration = self
+            .devices
+            .register(accepted.key, accepted.room, &accepted.peer);
+        Ok(self
+            .connection(accepted.protocol, accepted.peer.name, reader, writer)
+            .registered(registration))
+    }
+
+    /// Runs the client side of the handshake on `stream`.
+    pub async fn connect<S>(&self, stream: S) -> Result<Connection<S, B>, ClipshareError>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        let (mut reader, mut writer) = split(stream);
+        let handshake = handshake::client(
+            &mut reader,
+            &mut writer,
+            &self.key,
+            &self.room,
+            &self.identity,
+            &self.protocol,
+        );
+        let joined = timeout(self.options.handshake_timeout, handshake)
+            .await
+            .map_err(|_| {
+                ClipshareError::handshake("The server did not complete the handshake in time")
+            })??;
+        Ok(self.connection(joined.protocol, joined.name, reader, writer))
+    }
+
+    /// Runs the client side of the handshake on `stream`, pairing with the server through
+    /// `code` instead of using the session's key, returning also what pairing agreed on.
+    pub async fn pair<S>(
+        &self,
+        stream: S,
+        code: &str,
+    ) -> Result<(Connection<S, B>, Paired), ClipshareError>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        let (mut reader, mut writer) = split(stream);
+        let handshake = handshake::pair(
+            &mut reader,
+            &mut writer,
+            code,
+            &self.room,
+            &self.identity,
+            &self.protocol,
+        );
+        let (joined, paired) = timeout(self.options.handshake_timeout, handshake)
+            .await
+            .map_err(|_| {
+                ClipshareError::handshake("The server did not complete the handshake in time")
+            })??;
+        let connection = self.connection(joined.protocol, joined.name, reader, writer);
+        Ok((connection, paired))
+    }
+
+    fn connection<S>(
+        &self,
+        protocol: Protocol,
+        peer: String,
+        reader: ReadHalf<S>,
+        writer: WriteHalf<S>,
+    ) -> Connection<S, B>
+    where
+        S: AsyncRead + AsyncWrite + Send + Unpin,
+    {
+        Connection::new(
+            self.clipboard.clone(),
+            protocol,
+            peer,
+            self.options.clone(),
+            reader,
+            writer,
+        )
+    }
+}
+
+/// A connection that completed the handshake.
+pub struct Connection<S, B = arboard::Clipboard> {
+    clipboard: Arc<Clipboard<B>>,
+    origin: Origin,
+    protocol: Protocol,
+    peer: String,
+    options: SessionOptions,
+    events: Option<mpsc::UnboundedSender<SessionEvent>>,
+    registration: Option<Registration>,
+    reader: ReadHalf<S>,
+    writer: WriteHalf<S>,
+}
+
+impl<S, B> fmt::Debug for Connection<S, B> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("Connection")
+            .field("origin", &self.origin)
+            .field("protocol", &self.protocol)
+            .field("peer", &self.peer)
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl<S, B> Connection<S, B>
+where
+    S: AsyncRead + AsyncWrite + Send + Unpin,
+    B: ClipboardBackend,
+{
+    pub(crate) fn new(
+        clipboard: Arc<Clipboard<B>>,
+        protocol: Protocol,
+        peer: String,
+        options: SessionOptions,
+        reader: ReadHalf<S>,
+        writer: WriteHalf<S>,
+    ) -> Self {
+        Self {
+            origin: clipboard.peer(),
+            clipboard,
+            protocol,
+            peer,
+            options,
+            events: None,
+            registration: None,
+            reader,
+            writer,
+        }
+    }
+
+    /// Closes the connection once `registration` is taken over by the same peer connecting
+    /// again.
+    pub(crate) fn registered(self, registration: Option<Registration>) -> Self {
+        Self {
+            registration,
+            ..self
+        }
+    }
+
+    /// The clipboard this connection syncs.
+    pub fn clipboard(&self) -> &Arc<Clipboard<B>> {
+        &self.clipboard
+    }
+
+    /// Origin of the clipboard updates received on this connection.
+    pub fn origin(&self) -> Origin {
+        self.origin
+    }
+
+    /// The protocol negotiated with the peer.
+    pub fn protocol(&self) -> &Protocol {
+        &self.protocol
+    }
+
+    /// The name the peer goes by, its hostname unless it chose another.
+    pub fn peer(&self) -> &str {
+        &self.peer
+    }
+
+    /// Reports every object sent and received on this connection to `events`.
+    pub fn with_events(self, events: mpsc::UnboundedSender<SessionEvent>) -> Self {
+        Self {
+            events: Some(events),
+            ..self
+        }
+    }
+
+    /// Receives a single clipboard object from the peer and applies it, then closes the
+    /// connection.
+    pub async fn recv_once(mut self) -> Result<(), ClipshareError> {
+        let mut progress = Progress::new(&self.events, false);
+        let (seq, node, obj) = self
+            .protocol
+            .read_with_progress(&mut self.reader, |done, total| progress.update(done, total))
+            .await?;
+        log_object("Received clipboard", &obj, self.options.log_content);
+        let obj = normalize(obj, &self.options);
+        apply(
+            &self.clipboard,
+            self.origin,
+            seq,
+            node,
+            obj,
+            &self.options,
+            &self.events,
+        )
+        .await?;
+        self.writer.shutdown().await?;
+        Ok(())
+    }
+
+    /// Sends `obj` to the peer as a new change, then closes the connection.
+    pub async fn send_once(mut self, obj: &ClipboardObject) -> Result<(), ClipshareError> {
+        let obj = &obj
+            .clone()
+            .accepted_by(|kind| self.protocol.peer_accepts(kind))
+            .ok_or(ClipshareError::NotAccepted(obj.kind()))?;
+        if self.options.dry_run {
+            dry_run("send", obj, self.options.log_content);
+            self.writer.shutdown().await?;
+            return Ok(());
+        }
+        log_object("Sending clipboard", obj, self.options.log_content);
+        let seq = self.clipboard.next_seq(obj.selection());
+        let node = self.clipboard.node();
+        let (frames, total) = self.protocol.frames(seq, node, obj).await?;
+        let count = frames.len();
+        let mut progress = Progress::new(&self.events, true);
+        for (index, frame) in frames.into_iter().enumerate() {
+            self.writer.write_all(&frame).await?;
+            progress.written(index, count, total, self.protocol.chunk_size());
+        }
+        self.writer.flush().await?;
+        report(
+            &self.events,
+            SessionEvent::Sent {
+                kind: obj.kind(),
+                size: obj.size(),
+                preview: preview(obj, &self.options),
+            },
+        );
+        self.writer.shutdown().await?;
+        Ok(())
+    }
+
+    /// Syncs the clipboards until either side stops, or the peer connects again.
+    pub async fn sync(self) -> Result<(), ClipshareError> {
+        let sync = sync_clipboards(
+            self.clipboard,
+            self.origin,
+            self.protocol,
+            self.reader,
+            self.writer,
+            self.options,
+            self.events,
+        );
+        let Some(mut registration) = self.registration else {
+            return sync.await;
+        };
+        select! {
+            result = sync => result,
+            _ = &mut registration.replaced => {
+                info!(peer = self.peer, "Closing the connection, the peer connected again");
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Key of a connection in [`Devices`]: the name of the key and the room the peer joined with,
+/// and who it is.
+type DeviceKey = (String, String, Identity);
+/// Id of the registered connection of a peer, and what closes it.
+type Live = (u64, oneshot::Sender<()>);
+
+/// The connections of the peers that sent a device id, so one connecting again while its last
+/// connection isn't noticed to be gone yet takes over from it, instead of both being synced.
+///
+/// Clones share the connections.
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Devices {
+    live: Arc<Mutex<HashMap<DeviceKey, Live>>>,
+}
+
+impl Devices {
+    /// Registers a connection of `peer`, authenticated with the key named `key` in `room`,
+    /// closing any other one it has. `None` when it didn't send a device id.
+    pub(crate) fn register(
+        &self,
+        key: String,
+        room: String,
+        peer: &Identity,
+    ) -> Option<Registration> {
+        if peer.device == 0 {
+            return None;
+        }
+        let entry = (key, room, peer.clone());
+        let id = crypto::random_id();
+        let (replace, replaced) = oneshot::channel();
+        let stale = self
+            .live
+            .lock()
+            .unwrap()
+            .insert(entry.clone(), (id, replace));
+        if let Some((_, stale)) = stale {
+            debug!(
+                peer = peer.name,
+                device = peer.device,
+                "Replacing the stale connection of the peer"
+            );
+            let _ = stale.send(());
+        }
+        Some(Registration {
+            devices: self.clone(),
+            entry,
+            id,
+            replaced,
+        })
+    }
+}
+
+/// A connection in [`Devices`], until it is dropped.
+#[derive(Debug)]
+pub(crate) struct Registration {
+    devices: Devices,
+    entry: DeviceKey,
+    /// Tells this connection apart from a later one of the same peer.
+    id: u64,
+    /// Fires when the peer connects again.
+    replaced: oneshot::Receiver<()>,
+}
+
+impl Drop for Registration {
+    fn drop(&mut self) {
+        let mut live = self.devices.live.lock().unwrap();
+        if live.get(&self.entry).is_some_and(|(id, _)| *id == self.id) {
+            live.remove(&self.entry);
+        }
+    }
+}
+
+/// Syncs the clipboards over an established session until either side stops, `origin` being the
+/// one allocated to this connection.
+pub async fn sync_clipboards<B: ClipboardBackend>(
+    clipboard: Arc<Clipboard<B>>,
+    origin: Origin,
+    protocol: Protocol,
+    reader: impl AsyncRead + Send + Unpin,
+    writer: impl AsyncWrite + Send + Unpin,
+    options: SessionOptions,
+    events: Option<mpsc::UnboundedSender<SessionEvent>>,
+) -> Result<(), ClipshareError> {
+    match options.direction {
+        Direction::Both => select! {
+            result = recv_clipboard(clipboard.clone(), origin, protocol.clone(), reader, options.clone(), events.clone()).in_current_span() => result,
+            result = send_clipboard(clipboard.clone(), origin, protocol.clone(), writer, options, events).in_current_span() => result,
+        },
+        // Still reading, or writing pings, so the peer is noticed when it goes away
+        Direction::Send => select! {
+            result = discard_clipboard(protocol.clone(), reader).in_current_span() => result,
+            result = send_clipboard(clipboard, origin, protocol.clone(), writer, options.clone(), events).in_current_span() => result,
+        },
+        Direction::Recv => select! {
+            result = recv_clipboard(clipboard, origin, protocol.clone(), reader, options.clone(), events).in_current_span() => result,
+            result = keep_alive(protocol, writer).in_current_span() => result,
+        },
+    }
+}
+
+/// Sends the latest clipboard change, then every one not coming from `origin` to the peer, at
+/// most one every [`SessionOptions::min_interval`]. The local clipboard is looked at first, so a
+/// client coming back after a dropped connection sends what was copied meanwhile right away,
+/// while the peer sends its own latest change, the newest of the two winning on both ends. A
+/// change holding the same content as the last one sent is skipped, unless the peer copied
+/// something else since, and so is a sensitive one unless [`SessionOptions::sync_sensitive`] is
+/// set.
+///
+/// Changes only take over older ones of the same selection, those of the other one are held
+/// back and sent next.
+#[instrument(skip(clipboard, protocol, stream, options, events))]
+pub async fn send_clipboard<B: ClipboardBackend>(
+    clipboard: Arc<Clipboard<B>>,
+    origin: Origin,
+    protocol: Protocol,
+    mut stream: impl AsyncWrite + Send + Unpin,
+    options: SessionOptions,
+    events: Option<mpsc::UnboundedSender<SessionEvent>>,
+) -> Result<(), ClipshareError> {
+    let log_content = options.log_content;
+    // Left to the next poll when the clipboard can't be read right now
+    if let Err(err) = clipboard.refresh().await {
+        debug!(error = %err, "Failed to read the local clipboard");
+    }
+    let mut outgoing = Outgoing {
+        updates: clipboard.subscribe(),
+        origin,
+        sent: None,
+        // So a peer that just connected starts with what was last synced, older content loses
+        // anyway
+        held: clipboard
+            .latest()
+            .into_iter()
+            .filter(|latest| latest.origin != origin)
+            .collect(),
+    };
+    let mut last_sent: Option<Instant> = None;
+    loop {
+        let next = if outgoing.held.is_empty() {
+            idle_update(&protocol, &mut stream, &mut outgoing).await?
+        } else {
+            Some(outgoing.held.remove(0))
+        };
+        let Some(mut update) = next else {
+            return Ok(());
+        };
+
+        // Too soon after the last update, hold on to the latest change until the interval is over
+        if let Some(deadline) = last_sent.map(|sent| sent + options.min_interval) {
+            let wait = sleep_until(deadline);
+            tokio::pin!(wait);
+            loop {
+                select! {
+                    _ = &mut wait => break,
+                    newer = next_update(&mut outgoing) => match newer {
+                        Some(newer) if newer.obj.selection() == update.obj.selection() => {
+                            trace!("Coalescing clipboard update");
+                            update = newer;
+                        }
+                        Some(newer) => outgoing.hold(newer),
+                        None => return Ok(()),
+                    },
+                }
+            }
+        }
+
+        let Some(update) = sendable(update, &protocol, &outgoing, &options) else {
+            continue;
+        };
+        if options.dry_run {
+            dry_run("send", &update.obj, log_content);
+            outgoing.sent = Some(update.obj.digest());
+            continue;
+        }
+
+        log_object("Sending clipboard", &update.obj, log_content);
+        let written = match send_object(&protocol, &update, &mut stream, &mut outgoing, &events)
+            .in_current_span()
+            .await
+        {
+            // Refused before anything was written, the connection is fine
+            Err(ClipshareError::TooLarge { size, max, .. }) => {
+                warn!(
+                    size,
+                    max, "Not sending clipboard, the peer accepts at most {max} bytes"
+                );
+                None
+            }
+            // A large object is given up on as soon as something newer is copied, which goes
+            // through the same checks before it's sent in turn
+            result => match result? {
+                Some(newer) => {
+                    debug!("Clipboard changed during the transfer, sending the new one");
+                    outgoing.held.insert(0, newer);
+                    None
+                }
+                None => Some(update),
+            },
+        };
+        flush(&mut stream).await?;
+        let Some(update) = written else {
+            continue;
+        };
+        last_sent = Some(Instant::now());
+        outgoing.sent = Some(update.obj.digest());
+        report(
+            &events,
+            SessionEvent::Sent {
+                kind: update.obj.kind(),
+                size: update.obj.size(),
+                preview: preview(&update.obj, &options),
+            },
+        );
+    }
+}
+
+/// `update` in a form the peer accepts, or `None` when it isn't to be sent: syncing is paused,
+/// the peer accepts none of its forms, it's what was last sent, or the options leave it out.
+fn sendable(
+    mut update: Update,
+    protocol: &Protocol,
+    outgoing: &Outgoing,
+    options: &SessionOptions,
+) -> Option<Update> {
+    if options.paused.load(Ordering::SeqCst) {
+        debug!("Not sending clipboard, syncing is paused");
+        return None;
+    }
+    let kind = update.obj.kind();
+    if !protocol.peer_accepts(kind) {
+        match ClipboardObject::clone(&update.obj).accepted_by(|kind| protocol.peer_accepts(kind)) {
+            Some(obj) => {
+                debug!(kind = %kind, "Sending clipboard as {}, the peer doesn't accept {kind}", obj.kind());
+                update.obj = Arc::new(obj);
+            }
+            None => {
+                debug!(kind = %kind, "Not sending clipboard, the peer doesn't accept {kind}");
+                return None;
+            }
+        }
+    }
+    if outgoing.sent == Some(update.obj.digest()) {
+        trace!("Skipping clipboard identical to the last one sent");
+        return None;
+    }
+    if update.sensitive && !options.sync_sensitive {
+        info!("Skipped sensitive content");
+        return None;
+    }
+    if update.obj.is_empty_text() && !options.sync_empty {
+        warn!("Skipping empty clipboard, use --sync-empty to send it");
+        return None;
+    }
+    if !options.filter.allows(&update.obj) {
+        debug!("Skipping clipboard left out by --include and --exclude");
+        return None;
+    }
+    Some(update)
+}
+
+/// Waits for the next update like [`next_update`], pinging the peer whenever the heartbeat
+/// interval goes by without one.
+async fn idle_update(
+    protocol: &Protocol,
+    mut stream: impl AsyncWrite + Send + Unpin,
+    outgoing: &mut Outgoing,
+) -> Result<Option<Update>, ClipshareError> {
+    let Some(interval) = protocol.heartbeat_interval() else {
+        return Ok(next_update(outgoing).await);
+    };
+    loop {
+        select! {
+            update = next_update(outgoing) => return Ok(update),
+            _ = sleep(interval) => protocol.ping(&mut stream).await?,
+        }
+    }
+}
+
+/// Pings the peer every heartbeat interval, for a connection that sends nothing else, until
+/// writing fails.
+async fn keep_alive(
+    protocol: Protocol,
+    mut stream: impl AsyncWrite + Send + Unpin,
+) -> Result<(), ClipshareError> {
+    let Some(interval) = protocol.heartbeat_interval() else {
+        return future::pending().await;
+    };
+    loop {
+        sleep(interval).await;
+        protocol.ping(&mut stream).await?;
+    }
+}
+
+/// Reads and drops what the peer sends on a connection that only sends, until reading fails.
+async fn discard_clipboard(
+    protocol: Protocol,
+    mut stream: impl AsyncRead + Send + Unpin,
+) -> Result<(), ClipshareError> {
+    loop {
+        let (seq, _, obj) = protocol.read(&mut stream).await?;
+        debug!(
+            seq,
+            kind = %obj.kind(),
+            "Ignoring received clipboard, only sending"
+        );
+    }
+}
+
+/// Writes the frames of `update` one by one, aborting it when a newer update of the same
+/// selection shows up in between, which is then returned.
+/// An update the peer would refuse as too large fails before anything is written.
+async fn send_object(
+    protocol: &Protocol,
+    update: &Update,
+    mut stream: impl AsyncWrite + Send + Unpin,
+    outgoing: &mut Outgoing,
+    events: &Option<mpsc::UnboundedSender<SessionEvent>>,
+) -> Result<Option<Update>, ClipshareError> {
+    let (frames, total) = protocol
+        .frames(update.seq, update.node, &update.obj)
+        .await?;
+    let count = frames.len();
+    let mut progress = Progress::new(events, true);
+    for (index, frame) in frames.into_iter().enumerate() {
+        // Only between frames, as a frame written halfway would break the stream
+        if index > 0 {
+            if let Some(newer) = newer_update(outgoing, update.obj.selection()) {
+                trace!(index, count, "Aborting transfer");
+                protocol.abort(&mut stream).await?;
+                return Ok(Some(newer));
+            }
+        }
+        write_all(&mut stream, &frame).await?;
+        progress.written(index, count, total, protocol.chunk_size());
+    }
+    Ok(None)
+}
+
+/// Whether a failed write may go through when tried again: nothing was written, and the
+/// connection is still up, unlike when it was reset or closed. A TCP stream never fails this
+/// way, but the streams layered over it may. A write timing out means the keepalive found the
+/// peer gone, so that one isn't tried again.
+fn is_transient(err: &io::Error) -> bool {
+    matches!(
+        err.kind(),
+        io::ErrorKind::Interrupted | io::ErrorKind::WouldBlock
+    )
+}
+
+/// Writes all of `buf` like [`AsyncWriteExt::write_all`], trying a write that failed with a
+/// transient error again a few times, so a flaky link doesn't drop the connection.
+async fn write_all(
+    mut stream: impl AsyncWrite + Send + Unpin,
+    mut buf: &[u8],
+) -> Result<(), io::Error> {
+    let mut failures = 0;
+    while !buf.is_empty() {
+        match stream.write(buf).await {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(written) => {
+                buf = &buf[written..];
+                failures = 0;
+            }
+            Err(err) if is_transient(&err) && failures < WRITE_RETRIES => {
+                failures += 1;
+                warn!(error = %err, failures, "Failed to write to the peer, trying again");
+                sleep(WRITE_RETRY_DELAY).await;
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+/// Flushes `stream`, trying again on transient errors like [`write_all`].
+async fn flush(mut stream: impl AsyncWrite + Send + Unpin) -> Result<(), io::Error> {
+    let mut failures = 0;
+    loop {
+        match stream.flush().await {
+            Err(err) if is_transient(&err) && failures < WRITE_RETRIES => {
+                failures += 1;
+                warn!(error = %err, failures, "Failed to flush to the peer, trying again");
+                sleep(WRITE_RETRY_DELAY).await;
+            }
+            result => return result,
+        }
+    }
+}
+
+/// The updates on their way to the peer of a connection.
+struct Outgoing {
+    updates: broadcast::Receiver<Update>,
+    /// The connection, whose own updates aren't echoed back to it.
+    origin: Origin,
+    /// Digest of what the peer holds as far as we know, forgotten when it copies something.
+    sent: Option<u64>,
+    /// The latest update of each selection waiting for another one to be sent first.
+    held: Vec<Update>,
+}
+
+impl Outgoing {
+    /// Holds `update` back in place of the one of the same selection already held, if any.
+    fn hold(&mut self, update: Update) {
+        let selection = update.obj.selection();
+        self.held.retain(|held| held.obj.selection() != selection);
+        self.held.push(update);
+    }
+}
+
+/// The latest update of `selection` already waiting to be sent, if any, holding back those of
+/// the other selection and forgetting what was sent when the peer copied something in between.
+fn newer_update(outgoing: &mut Outgoing, selection: Selection) -> Option<Update> {
+    loop {
+        match outgoing.updates.try_recv() {
+            Ok(update) if update.origin != outgoing.origin => outgoing.hold(update),
+            Ok(_) | Err(TryRecvError::Lagged(_)) => outgoing.sent = None,
+            Err(TryRecvError::Empty | TryRecvError::Closed) => break,
+        }
+    }
+    let newer = outgoing
+        .held
+        .iter()
+        .position(|held| held.obj.selection() == selection)?;
+    Some(outgoing.held.remove(newer))
+}
+
+/// Waits for the next update to send, or `None` once the clipboard is gone, forgetting what
+/// was sent when the peer copies something in between.
+async fn next_update(outgoing: &mut Outgoing) -> Option<Update> {
+    loop {
+        let update = match outgoing.updates.recv().await {
+            Ok(update) => update,
+            Err(RecvError::Lagged(skipped)) => {
+                warn!(
+                    skipped,
+                    "Skipped clipboard updates, the connection is too slow"
+                );
+                outgoing.sent = None;
+                continue;
+            }
+            Err(RecvError::Closed) => return None,
+        };
+
+        // Don't echo an object back to the connection it came from
+        if update.origin != outgoing.origin {
+            return Some(update);
+        }
+        outgoing.sent = None;
+    }
+}
+
+/// Applies every clipboard object received from the peer on the connection of `origin`.
+///
+/// Objects are read off the network while the previous one is still being written to the
+/// clipboard, up to [`SessionOptions::recv_queue`] of them. Only the latest of the ones waiting
+/// is applied, and reading stops while the queue is full, so a slow clipboard neither holds up
+/// the connection nor lets received objects pile up in memory.
+#[instrument(skip(clipboard, protocol, stream, events))]
+pub async fn recv_clipboard<B: ClipboardBackend>(
+    clipboard: Arc<Clipboard<B>>,
+    origin: Origin,
+    protocol: Protocol,
+    stream: impl AsyncRead + Send + Unpin,
+    options: SessionOptions,
+    events: Option<mpsc::UnboundedSender<SessionEvent>>,
+) -> Result<(), ClipshareError> {
+    let (queued, pending) = mpsc::channel(options.recv_queue.max(1));
+    let read = read_objects(&protocol, stream, queued, &options, &events).in_current_span();
+    let apply = apply_objects(&clipboard, origin, pending, &options, &events).in_current_span();
+    tokio::pin!(read, apply);
+    select! {
+        result = &mut read => {
+            // Apply what was already received before reporting how the connection ended
+            apply.await?;
+            result
+        }
+        result = &mut apply => result,
+    }
+}
+
+/// Reads clipboard objects from the peer into `queued`, until the stream fails or nothing
+/// applies them anymore.
+async fn read_objects(
+    protocol: &Protocol,
+    mut stream: impl AsyncRead + Send + Unpin,
+    queued: mpsc::Sender<(u64, u64, ClipboardObject)>,
+    options: &SessionOptions,
+    events: &Option<mpsc::UnboundedSender<SessionEvent>>,
+) -> Result<(), ClipshareError> {
+    loop {
+        let mut progress = Progress::new(events, false);
+        let (seq, node, obj) = protocol
+            .read_with_progress(&mut stream, |done, total| progress.update(done, total))
+            .await?;
+        log_object("Received clipboard", &obj, options.log_content);
+        if options.paused.load(Ordering::SeqCst) {
+            debug!(seq, "Ignoring received clipboard, syncing is paused");
+            continue;
+        }
+        if !protocol.accepts(obj.kind()) {
+            debug!(seq, kind = %obj.kind(), "Ignoring received clipboard of a kind not accepted");
+            continue;
+        }
+        let obj = normalize(obj, options);
+        if queued.send((seq, node, obj)).await.is_err() {
+            return Ok(());
+        }
+    }
+}
+
+/// Applies the objects read into `pending`, skipping straight to the latest of each selection
+/// when several of them piled up while the clipboard was busy.
+async fn apply_objects<B: ClipboardBackend>(
+    clipboard: &Clipboard<B>,
+    origin: Origin,
+    mut pending: mpsc::Receiver<(u64, u64, ClipboardObject)>,
+    options: &SessionOptions,
+    events: &Option<mpsc::UnboundedSender<SessionEvent>>,
+) -> Result<(), ClipshareError> {
+    while let Some(next) = pending.recv().await {
+        let mut waiting = vec![next];
+        while let Ok(newer) = pending.try_recv() {
+            let selection = newer.2.selection();
+            match waiting
+                .iter_mut()
+                .find(|(_, _, obj)| obj.selection() == selection)
+            {
+                Some(older) => {
+                    trace!(
+                        seq = older.0,
+                        "Skipping received clipboard, a newer one is waiting"
+                    );
+                    *older = newer;
+                }
+                None => waiting.push(newer),
+            }
+        }
+        for (seq, node, obj) in waiting {
+            apply(clipboard, origin, seq, node, obj, options, events).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Copies a received object, the sequence number of its change and the node it was made on, to
+/// the clipboard, unless [`SessionOptions::confirm`] declines it.
+async fn apply<B: ClipboardBackend>(
+    clipboard: &Clipboard<B>,
+    origin: Origin,
+    seq: u64,
+    node: u64,
+    obj: ClipboardObject,
+    options: &SessionOptions,
+    events: &Option<mpsc::UnboundedSender<SessionEvent>>,
+) -> Result<(), ClipshareError> {
+    if options.dry_run {
+        dry_run("apply", &obj, options.log_content);
+        return Ok(());
+    }
+    let (kind, size, preview) = (obj.kind(), obj.size(), preview(&obj, options));
+    // Nobody is asked about a change made here, which the clipboard drops anyway
+    let confirm = options
+        .confirm
+        .as_ref()
+        .filter(|_| node != clipboard.node());
+    if let Some(confirm) = confirm {
+        if !confirm.allows(&obj).await {
+            info!(
+                seq,
+                kind = %kind,
+                size,
+                "Discarded received clipboard, it wasn't confirmed"
+            );
+            report(
+                events,
+                SessionEvent::Declined {
+                    kind,
+                    size,
+                    preview,
+                },
+            );
+            return Ok(());
+        }
+    }
+    clipboard
+        .receive(origin, seq, node, obj)
+        .await
+        .map_err(ClipshareError::Backend)?;
+    report(
+        events,
+        SessionEvent::Received {
+            kind,
+            size,
+            preview,
+        },
+    );
+    Ok(())
+}
+
+/// Reports how far along the transfer of a single object is, whenever another percent of it is
+/// done so large objects don't flood the logs and the events channel.
+struct Progress<'a> {
+    events: &'a Option<mpsc::UnboundedSender<SessionEvent>>,
+    sending: bool,
+    percent: Option<u64>,
+}
+
+impl<'a> Progress<'a> {
+    fn new(events: &'a Option<mpsc::UnboundedSender<SessionEvent>>, sending: bool) -> Self {
+        Self {
+            events,
+            sending,
+            percent: None,
+        }
+    }
+
+    /// Reports frame `index` of the `count` encoding an object `total` bytes long as written,
+    /// each but the last one holding `chunk_size` bytes of it.
+    fn written(&mut self, index: usize, count: usize, total: u64, chunk_size: usize) {
+        if count > 1 {
+            let done = ((index + 1) * chunk_size) as u64;
+            self.update(done.min(total), total);
+        }
+    }
+
+    fn update(&mut self, done: u64, total: u64) {
+        let percent = done.saturating_mul(100) / total.max(1);
+        if self.percent == Some(percent) {
+            return;
+        }
+        self.percent = Some(percent);
+
+        if self.sending {
+            debug!(done, total, "Sending clipboard: {percent}%");
+            report(self.events, SessionEvent::Sending { done, total });
+        } else {
+            debug!(done, total, "Receiving clipboard: {percent}%");
+            report(self.events, SessionEvent::Receiving { done, total });
+        }
+    }
+}
+
+/// Applies the text transforms asked for by `options` to a received object. The HTML of an
+/// [`ClipboardObject::Html`] is left alone, only its plain text is transformed.
+fn normalize(obj: ClipboardObject, options: &SessionOptions) -> ClipboardObject {
+    match obj {
+        ClipboardObject::Text(text) => ClipboardObject::Text(normalize_text(text, options)),
+        ClipboardObject::Primary(text) => ClipboardObject::Primary(normalize_text(text, options)),
+        ClipboardObject::Html { html, text } => ClipboardObject::Html {
+            html,
+            text: normalize_text(text, options),
+        },
+        obj => obj,
+    }
+}
+
+fn normalize_text(mut text: String, options: &SessionOptions) -> String {
+    if options.normalize_eol {
+        text = text.replace("\r\n", "\n");
+        if cfg!(windows) {
+            text = text.replace('\n', "\r\n");
+        }
+    }
+    if options.trim {
+        if let Some(trimmed) = text
+            .strip_suffix("\r\n")
+            .or_else(|| text.strip_suffix('\n'))
+        {
+            text.truncate(trimmed.len());
+        }
+    }
+    text
+}
+
+/// Nobody listening anymore isn't an error, the events are only informative.
+fn report(events: &Option<mpsc::UnboundedSender<SessionEvent>>, event: SessionEvent) {
+    if let Some(events) = events {
+        let _ = events.send(event);
+    }
+}
+
+/// The preview of `obj` reported in a [`SessionEvent`], only with
+/// [`SessionOptions::report_content`].
+fn preview(obj: &ClipboardObject, options: &SessionOptions) -> Option<String> {
+    options.report_content.then(|| history::summary(obj))
+}
+
+/// Logs what would have been done with `obj` but for [`SessionOptions::dry_run`].
+fn dry_run(action: &str, obj: &ClipboardObject, log_content: bool) {
+    let (kind, size) = (obj.kind(), obj.size());
+    if log_content {
+        info!(kind = %kind, size, content = %history::summary(obj), "Would {action} {size} bytes of {kind} (dry run)");
+    } else {
+        info!(
+            kind = %kind,
+            size, "Would {action} {size} bytes of {kind} (dry run)"
+        );
+    }
+}
+
+/// Clipboards often hold passwords, so their contents are only logged when asked to.
+fn log_object(message: &str, obj: &ClipboardObject, log_content: bool) {
+    if log_content {
+        debug!(kind = %obj.kind(), size = obj.size(), content = %history::summary(obj), "{message}");
+    } else {
+        debug!(kind = %obj.kind(), size = obj.size(), "{message}");
+    }
+}
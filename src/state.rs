@@ -0,0 +1,96 @@
+//! Keeping the last synced clipboard on disk, so it survives a restart.
+
+use std::{error::Error, path::Path, sync::Arc};
+
+use tokio::{fs, io::AsyncWriteExt, sync::broadcast::error::RecvError};
+use tracing::{debug, trace, warn};
+
+use crate::{
+    backend::ClipboardBackend,
+    clipboard::{Clipboard, ClipboardObject, Origin},
+    codec,
+};
+
+/// Reads the clipboard object saved at `path` and its sequence number, or `None` when nothing
+/// was saved there yet. Objects over `max_size` bytes are refused like received ones.
+pub async fn load(
+    path: &Path,
+    max_size: u64,
+) -> Result<Option<(u64, ClipboardObject)>, Box<dyn Error + Send + Sync>> {
+    let contents = match fs::read(path).await {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let mut reader = &contents[..];
+    let seq = codec::read_u64(&mut reader).await?;
+    // Lossy, as whatever was saved is better than nothing
+    let obj = ClipboardObject::from_reader(reader, max_size, true).await?;
+    Ok(obj.map(|obj| (seq, obj)))
+}
+
+/// Puts the object saved at `path` back on the clipboard with its original sequence number,
+/// so it loses to anything synced or copied since, including what the clipboard holds already.
+pub async fn restore<B: ClipboardBackend>(
+    clipboard: &Clipboard<B>,
+    path: &Path,
+    max_size: u64,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    match load(path, max_size).await? {
+        Some((seq, obj)) => {
+            debug!(path = %path.display(), seq, kind = %obj.kind(), "Restoring clipboard");
+            clipboard
+                .receive(Origin::Local, seq, clipboard.node(), obj)
+                .await
+        }
+        None => {
+            debug!(path = %path.display(), "No saved clipboard to restore");
+            Ok(())
+        }
+    }
+}
+
+/// Saves every clipboard change to `path`, until the clipboard is gone.
+pub async fn save<B: ClipboardBackend>(clipboard: Arc<Clipboard<B>>, path: impl AsRef<Path>) {
+    let path = path.as_ref();
+    let mut updates = clipboard.subscribe();
+    loop {
+        let update = match updates.recv().await {
+            Ok(update) => update,
+            // The latest change is what matters, the next one is saved anyway
+            Err(RecvError::Lagged(_)) => continue,
+            Err(RecvError::Closed) => break,
+        };
+        // A password is better lost on restart than left on disk
+        if update.sensitive {
+            trace!("Not saving sensitive clipboard");
+            continue;
+        }
+        if let Err(err) = write(path, update.seq, &update.obj).await {
+            warn!(path = %path.display(), error = %err, "Failed to save the clipboard");
+        }
+    }
+}
+
+/// Replaces the object saved at `path` in one go, so a crash halfway leaves the previous one.
+async fn write(
+    path: &Path,
+    seq: u64,
+    obj: &ClipboardObject,
+) -> Result<(), Box<dyn Error + Send + Sync>> {
+    let mut contents = seq.to_be_bytes().to_vec();
+    obj.write(&mut contents).await?;
+
+    let partial = path.with_extension("partial");
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    // Only readable by the user, as it may well hold a password
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options.open(&partial).await?;
+    file.write_all(&contents).await?;
+    file.sync_all().await?;
+    fs::rename(&partial, path).await?;
+    trace!(path = %path.display(), seq, len = contents.len(), "Saved clipboard");
+    Ok(())
+}
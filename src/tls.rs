@@ -0,0 +1,191 @@
+use std::{
+    error::Error,
+    fmt::Write,
+    fs,
+    io::BufReader,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use sha2::{Digest, Sha256};
+use tokio_rustls::{
+    rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{ring, verify_tls12_signature, verify_tls13_signature, CryptoProvider},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+    },
+    TlsAcceptor, TlsConnector,
+};
+use tracing::{info, trace, warn};
+
+/// Name the self-signed certificate is issued for. Peers are identified by their fingerprint,
+/// not by a hostname, so the client always asks for this name.
+const SERVER_NAME: &str = "clipshare";
+
+pub struct Acceptor {
+    pub acceptor: TlsAcceptor,
+    pub fingerprint: String,
+}
+
+/// Builds a TLS acceptor from the given cert/key pair, falling back to a self-signed certificate
+/// stored in the config dir (generated on first run).
+pub fn acceptor(
+    cert: Option<PathBuf>,
+    key: Option<PathBuf>,
+) -> Result<Acceptor, Box<dyn Error + Send + Sync>> {
+    let (cert, key) = match (cert, key) {
+        (Some(cert), Some(key)) => (cert, key),
+        (None, None) => self_signed()?,
+        _ => return Err("--tls-cert and --tls-key must be given together".into()),
+    };
+
+    let certs = rustls_pemfile::certs(&mut BufReader::new(fs::File::open(&cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(fs::File::open(&key)?))?
+        .ok_or_else(|| format!("No private key found in {}", key.display()))?;
+
+    let fingerprint = fingerprint(certs.first().ok_or("No certificate found")?);
+    trace!(fingerprint, "Loaded TLS certificate");
+
+    let config = ServerConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .with_no_client_auth()
+        .with_single_cert(certs, key)?;
+
+    Ok(Acceptor {
+        acceptor: TlsAcceptor::from(Arc::new(config)),
+        fingerprint,
+    })
+}
+
+/// Builds a TLS connector. When `pin` is given the server certificate must match that SHA-256
+/// fingerprint, otherwise any certificate is accepted (the channel is still encrypted).
+pub fn connector(pin: Option<String>) -> Result<TlsConnector, Box<dyn Error + Send + Sync>> {
+    if pin.is_none() {
+        warn!("No TLS fingerprint pinned, the server certificate will not be verified");
+    }
+
+    let verifier = PinnedCertVerifier {
+        pin: pin.map(|pin| pin.replace(':', "").to_lowercase()),
+        provider: provider(),
+    };
+
+    let config = ClientConfig::builder_with_provider(provider())
+        .with_safe_default_protocol_versions()?
+        .dangerous()
+        .with_custom_certificate_verifier(Arc::new(verifier))
+        .with_no_client_auth();
+
+    Ok(TlsConnector::from(Arc::new(config)))
+}
+
+pub fn server_name() -> ServerName<'static> {
+    ServerName::try_from(SERVER_NAME).unwrap()
+}
+
+fn provider() -> Arc<CryptoProvider> {
+    Arc::new(ring::default_provider())
+}
+
+fn fingerprint(cert: &CertificateDer) -> String {
+    Sha256::digest(cert)
+        .iter()
+        .fold(String::new(), |mut hex, byte| {
+            let _ = write!(hex, "{byte:02x}");
+            hex
+        })
+}
+
+fn self_signed() -> Result<(PathBuf, PathBuf), Box<dyn Error + Send + Sync>> {
+    let dir = dirs::config_dir()
+        .ok_or("Could not find a config dir to store the TLS certificate")?
+        .join("clipshare");
+    let cert = dir.join("cert.pem");
+    let key = dir.join("key.pem");
+
+    if !cert.exists() || !key.exists() {
+        info!(path = %dir.display(), "Generating self-signed TLS certificate");
+        let generated = rcgen::generate_simple_self_signed(vec![SERVER_NAME.to_string()])?;
+        fs::create_dir_all(&dir)?;
+        fs::write(&cert, generated.cert.pem())?;
+        write_private(&key, generated.key_pair.serialize_pem())?;
+    }
+
+    Ok((cert, key))
+}
+
+#[cfg(unix)]
+fn write_private(path: &Path, contents: String) -> std::io::Result<()> {
+    use std::{io::Write, os::unix::fs::OpenOptionsExt};
+    fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(true)
+        .mode(0o600)
+        .open(path)?
+        .write_all(contents.as_bytes())
+}
+
+#[cfg(not(unix))]
+fn write_private(path: &Path, contents: String) -> std::io::Result<()> {
+    fs::write(path, contents)
+}
+
+#[derive(Debug)]
+struct PinnedCertVerifier {
+    pin: Option<String>,
+    provider: Arc<CryptoProvider>,
+}
+
+impl ServerCertVerifier for PinnedCertVerifier {
+    fn verify_server_cert(
+        &self,
+        end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        match self.pin {
+            Some(ref pin) if *pin != fingerprint(end_entity) => Err(
+                tokio_rustls::rustls::Error::General("Certificate fingerprint mismatch".into()),
+            ),
+            _ => Ok(ServerCertVerified::assertion()),
+        }
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls12_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        verify_tls13_signature(
+            message,
+            cert,
+            dss,
+            &self.provider.signature_verification_algorithms,
+        )
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.provider
+            .signature_verification_algorithms
+            .supported_schemes()
+    }
+}
@@ -0,0 +1,117 @@
+//! Running the protocol inside a WebSocket, for networks that only let HTTP through.
+//!
+//! Whatever is written goes out as binary messages and the ones received are read back as a
+//! single byte stream, so the handshake and clipboard objects are the same as over TCP.
+
+use std::{
+    error::Error,
+    io,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use futures_util::{Sink, Stream};
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+use tokio_tungstenite::{
+    tungstenite::{Bytes, Message},
+    WebSocketStream,
+};
+use tracing::trace;
+
+/// Most bytes sent in a single message, well below what the peer accepts.
+const MAX_MESSAGE_LEN: usize = 1 << 20;
+
+/// A WebSocket connection read and written like any other stream.
+pub struct WsStream<S> {
+    inner: WebSocketStream<S>,
+    /// What is left of the last message received.
+    read: Bytes,
+}
+
+impl<S> WsStream<S> {
+    fn new(inner: WebSocketStream<S>) -> Self {
+        Self {
+            inner,
+            read: Bytes::new(),
+        }
+    }
+}
+
+/// Runs the server side of the WebSocket handshake on `stream`, whatever the path asked for.
+pub async fn accept<S>(stream: S) -> Result<WsStream<S>, Box<dyn Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let stream = tokio_tungstenite::accept_async(stream).await?;
+    trace!("WebSocket handshake completed");
+    Ok(WsStream::new(stream))
+}
+
+/// Runs the client side of the WebSocket handshake on `stream`, asking for `url`.
+pub async fn connect<S>(stream: S, url: &str) -> Result<WsStream<S>, Box<dyn Error + Send + Sync>>
+where
+    S: AsyncRead + AsyncWrite + Unpin,
+{
+    let (stream, _) = tokio_tungstenite::client_async(url, stream).await?;
+    trace!("WebSocket handshake completed");
+    Ok(WsStream::new(stream))
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncRead for WsStream<S> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        while self.read.is_empty() {
+            let message = match ready!(Pin::new(&mut self.inner).poll_next(cx)) {
+                Some(message) => message.map_err(io::Error::other)?,
+                // The end of the stream, like a TCP connection closed by the peer
+                None => return Poll::Ready(Ok(())),
+            };
+            match message {
+                Message::Binary(data) => self.read = data,
+                Message::Close(_) => return Poll::Ready(Ok(())),
+                Message::Text(_) => {
+                    return Poll::Ready(Err(io::Error::new(
+                        io::ErrorKind::InvalidData,
+                        "Unexpected WebSocket text message",
+                    )))
+                }
+                // Pings are answered by the WebSocket itself
+                Message::Ping(_) | Message::Pong(_) | Message::Frame(_) => {}
+            }
+        }
+        let len = self.read.len().min(buf.remaining());
+        buf.put_slice(&self.read.split_to(len));
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> AsyncWrite for WsStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        let mut inner = Pin::new(&mut self.inner);
+        ready!(inner.as_mut().poll_ready(cx)).map_err(io::Error::other)?;
+        let len = buf.len().min(MAX_MESSAGE_LEN);
+        inner
+            .start_send(Message::binary(buf[..len].to_vec()))
+            .map_err(io::Error::other)?;
+        Poll::Ready(Ok(len))
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_flush(cx)
+            .map_err(io::Error::other)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner)
+            .poll_close(cx)
+            .map_err(io::Error::other)
+    }
+}
@@ -0,0 +1,1006 @@
+//! Two ends syncing over an in-memory pipe, with clipboards kept in memory.
+
+use std::{
+    error::Error,
+    io,
+    path::PathBuf,
+    pin::Pin,
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use arboard::ImageData;
+use clipshare::{
+    backend::{ClipboardBackend, MemoryClipboard, OwnershipLost},
+    clipboard::{Clipboard, ClipboardKind, ClipboardObject, File, Origin},
+    codec,
+    crypto::Cipher,
+    error::ClipshareError,
+    protocol::{self, Protocol, CHUNK_SIZE},
+    session::{recv_clipboard, send_clipboard, ClipshareSession, SessionEvent, SessionOptions},
+};
+use tokio::{
+    io::{duplex, AsyncWrite, AsyncWriteExt},
+    sync::mpsc,
+    time::{sleep, timeout},
+};
+
+const MAX_SIZE: u64 = 1 << 20;
+
+fn session(key: &str) -> ClipshareSession<MemoryClipboard> {
+    let clipboard = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    ClipshareSession::new(clipboard, key, Protocol::new(MAX_SIZE)).with_name("test")
+}
+
+async fn text(clipboard: &Clipboard<MemoryClipboard>) -> Option<String> {
+    match clipboard.current().await.unwrap() {
+        Some(ClipboardObject::Text(text)) => Some(text),
+        _ => None,
+    }
+}
+
+/// A clipboard kept in memory that the test changes behind the back of clipshare, counting the
+/// text written to it. The next `lost` reads and writes of text fail as if another application
+/// took the clipboard over, and the `failures` ones after that as if it was busy.
+#[derive(Debug, Clone, Default)]
+struct Shared {
+    clipboard: Arc<std::sync::Mutex<MemoryClipboard>>,
+    writes: Arc<AtomicU32>,
+    lost: Arc<AtomicU32>,
+    failures: Arc<AtomicU32>,
+}
+
+impl Shared {
+    fn fail(&self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        let take = |left: &AtomicU32| {
+            left.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |left| {
+                left.checked_sub(1)
+            })
+            .is_ok()
+        };
+        if take(&self.lost) {
+            return Err(Box::new(OwnershipLost("taken over".into())));
+        }
+        if take(&self.failures) {
+            return Err("the clipboard is busy".into());
+        }
+        Ok(())
+    }
+
+    fn text(&self) -> Option<String> {
+        self.clipboard.lock().unwrap().paste_text().unwrap()
+    }
+
+    /// Copies `text` like another application would.
+    fn set_text(&self, text: &str) {
+        self.clipboard.lock().unwrap().copy_text(text).unwrap();
+    }
+}
+
+impl ClipboardBackend for Shared {
+    fn paste_text(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        self.fail()?;
+        self.clipboard.lock().unwrap().paste_text()
+    }
+
+    fn paste_image(&mut self) -> Result<Option<ImageData<'static>>, Box<dyn Error + Send + Sync>> {
+        self.clipboard.lock().unwrap().paste_image()
+    }
+
+    fn paste_files(&mut self) -> Result<Option<Vec<PathBuf>>, Box<dyn Error + Send + Sync>> {
+        self.clipboard.lock().unwrap().paste_files()
+    }
+
+    fn copy_text(&mut self, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.fail()?;
+        self.writes.fetch_add(1, Ordering::SeqCst);
+        self.clipboard.lock().unwrap().copy_text(text)
+    }
+
+    fn copy_image(&mut self, img: ImageData<'static>) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.clipboard.lock().unwrap().copy_image(img)
+    }
+
+    fn copy_files(&mut self, paths: &[PathBuf]) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.clipboard.lock().unwrap().copy_files(paths)
+    }
+
+    fn clear(&mut self) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.clipboard.lock().unwrap().clear()
+    }
+
+    fn is_sensitive(&mut self) -> Result<bool, Box<dyn Error + Send + Sync>> {
+        Ok(false)
+    }
+
+    fn paste_primary(&mut self) -> Result<Option<String>, Box<dyn Error + Send + Sync>> {
+        self.clipboard.lock().unwrap().paste_primary()
+    }
+
+    fn copy_primary(&mut self, text: &str) -> Result<(), Box<dyn Error + Send + Sync>> {
+        self.clipboard.lock().unwrap().copy_primary(text)
+    }
+}
+
+#[tokio::test]
+async fn copied_text_reaches_the_peer() {
+    let (server, client) = (session("key"), session("key"));
+    let (server_stream, client_stream) = duplex(64 * 1024);
+    let (accepted, connected) =
+        tokio::join!(server.accept(server_stream), client.connect(client_stream));
+    tokio::spawn(accepted.unwrap().sync());
+    tokio::spawn(connected.unwrap().sync());
+
+    client
+        .clipboard()
+        .copy(Origin::Local, ClipboardObject::Text("hello".into()))
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), async {
+        while text(server.clipboard()).await.as_deref() != Some("hello") {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the server got the text");
+}
+
+#[tokio::test]
+async fn text_copied_while_disconnected_is_sent_on_reconnect() {
+    let (server, client) = (session("key"), session("key"));
+    let (server_stream, client_stream) = duplex(64 * 1024);
+    let (accepted, connected) =
+        tokio::join!(server.accept(server_stream), client.connect(client_stream));
+    let (accepted, connected) = (accepted.unwrap(), connected.unwrap());
+    // The connection drops
+    drop((accepted, connected));
+
+    client
+        .clipboard()
+        .copy(Origin::Local, ClipboardObject::Text("meanwhile".into()))
+        .await
+        .unwrap();
+    let (server_stream, client_stream) = duplex(64 * 1024);
+    let (accepted, connected) =
+        tokio::join!(server.accept(server_stream), client.connect(client_stream));
+    tokio::spawn(accepted.unwrap().sync());
+    tokio::spawn(connected.unwrap().sync());
+    timeout(Duration::from_secs(5), async {
+        while text(server.clipboard()).await.as_deref() != Some("meanwhile") {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the server got the text");
+}
+
+#[tokio::test]
+async fn html_reaches_the_peer_with_its_text() {
+    let (server, client) = (session("key"), session("key"));
+    let (server_stream, client_stream) = duplex(64 * 1024);
+    let (accepted, connected) =
+        tokio::join!(server.accept(server_stream), client.connect(client_stream));
+    tokio::spawn(accepted.unwrap().sync());
+    tokio::spawn(connected.unwrap().sync());
+
+    let html = ClipboardObject::Html {
+        html: "<b>bold</b>".into(),
+        text: "bold".into(),
+    };
+    client.clipboard().copy(Origin::Local, html).await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        loop {
+            if let Some(ClipboardObject::Html { html, text }) =
+                server.clipboard().current().await.unwrap()
+            {
+                assert_eq!((html.as_str(), text.as_str()), ("<b>bold</b>", "bold"));
+                break;
+            }
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the server got the HTML");
+}
+
+#[tokio::test]
+async fn html_reaches_a_peer_accepting_only_text_as_text() {
+    let server = session("key");
+    let clipboard = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    let protocol = Protocol::new(MAX_SIZE).accepting(&[ClipboardKind::Text]);
+    let client = ClipshareSession::new(clipboard, "key", protocol);
+    let (server_stream, client_stream) = duplex(64 * 1024);
+    let (accepted, connected) =
+        tokio::join!(server.accept(server_stream), client.connect(client_stream));
+    let accepted = accepted.unwrap();
+    assert!(!accepted.protocol().peer_accepts(ClipboardKind::Html));
+    tokio::spawn(accepted.sync());
+    tokio::spawn(connected.unwrap().sync());
+
+    let html = ClipboardObject::Html {
+        html: "<b>bold</b>".into(),
+        text: "bold".into(),
+    };
+    server.clipboard().copy(Origin::Local, html).await.unwrap();
+    timeout(Duration::from_secs(5), async {
+        while text(client.clipboard()).await.as_deref() != Some("bold") {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the client got the text of the HTML");
+}
+
+#[tokio::test]
+async fn html_taking_over_a_transfer_reaches_a_peer_accepting_only_text_as_text() {
+    let sender = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    let receiver = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    // Smaller than a chunk, so the transfer waits on the receiver after its first frame
+    let (write, read) = duplex(1024);
+    let accepting = Protocol::new(MAX_SIZE).accepting(&[ClipboardKind::Text]);
+    let protocol = Protocol::new(MAX_SIZE).negotiate(
+        accepting.flags(),
+        MAX_SIZE,
+        accepting.stream_threshold(),
+        accepting.accepted_kinds(),
+    );
+    let options = SessionOptions::default();
+    tokio::spawn(send_clipboard(
+        sender.clone(),
+        sender.peer(),
+        protocol,
+        write,
+        options.clone(),
+        None,
+    ));
+    sleep(Duration::from_millis(100)).await;
+
+    let large = "x".repeat(3 * CHUNK_SIZE);
+    sender
+        .copy(Origin::Local, ClipboardObject::Text(large))
+        .await
+        .unwrap();
+    sleep(Duration::from_millis(100)).await;
+    let html = ClipboardObject::Html {
+        html: "<b>bold</b>".into(),
+        text: "bold".into(),
+    };
+    sender.copy(Origin::Local, html).await.unwrap();
+    tokio::spawn(recv_clipboard(
+        receiver.clone(),
+        receiver.peer(),
+        accepting,
+        read,
+        options,
+        None,
+    ));
+    timeout(Duration::from_secs(5), async {
+        while text(&receiver).await.as_deref() != Some("bold") {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the receiver got the text of the HTML");
+}
+
+#[tokio::test]
+async fn send_and_recv_clipboard_sync_over_a_pipe() {
+    let sender = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    let receiver = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    let (write, read) = duplex(64 * 1024);
+    let protocol = Protocol::new(MAX_SIZE);
+    let options = SessionOptions::default();
+    tokio::spawn(send_clipboard(
+        sender.clone(),
+        sender.peer(),
+        protocol.clone(),
+        write,
+        options.clone(),
+        None,
+    ));
+    tokio::spawn(recv_clipboard(
+        receiver.clone(),
+        receiver.peer(),
+        protocol,
+        read,
+        options,
+        None,
+    ));
+
+    // Large enough to take several frames
+    let large = "x".repeat(3 * CHUNK_SIZE);
+    sender
+        .copy(Origin::Local, ClipboardObject::Text(large.clone()))
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), async {
+        while text(&receiver).await.as_deref() != Some(&large) {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the receiver got the text");
+}
+
+/// A stream whose first write fails with an error worth trying again.
+struct Flaky<W> {
+    inner: W,
+    failed: bool,
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for Flaky<W> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        if !self.failed {
+            self.failed = true;
+            return Poll::Ready(Err(io::ErrorKind::Interrupted.into()));
+        }
+        Pin::new(&mut self.inner).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.inner).poll_shutdown(cx)
+    }
+}
+
+#[tokio::test]
+async fn write_failing_once_is_tried_again() {
+    let sender = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    let receiver = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    let (write, read) = duplex(64 * 1024);
+    let write = Flaky {
+        inner: write,
+        failed: false,
+    };
+    let protocol = Protocol::new(MAX_SIZE);
+    let options = SessionOptions::default();
+    let sending = tokio::spawn(send_clipboard(
+        sender.clone(),
+        sender.peer(),
+        protocol.clone(),
+        write,
+        options.clone(),
+        None,
+    ));
+    tokio::spawn(recv_clipboard(
+        receiver.clone(),
+        receiver.peer(),
+        protocol,
+        read,
+        options,
+        None,
+    ));
+
+    for copied in ["first", "second"] {
+        sender
+            .copy(Origin::Local, ClipboardObject::Text(copied.into()))
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), async {
+            while text(&receiver).await.as_deref() != Some(copied) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the receiver got the text");
+    }
+    assert!(!sending.is_finished());
+}
+
+#[tokio::test]
+async fn received_clipboard_is_not_echoed_back() {
+    let clipboard = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    let (mut to_server, from_peer) = duplex(64 * 1024);
+    let (to_peer, mut from_server) = duplex(64 * 1024);
+    let protocol = Protocol::new(MAX_SIZE);
+    let options = SessionOptions::default();
+    let origin = clipboard.peer();
+    tokio::spawn(recv_clipboard(
+        clipboard.clone(),
+        origin,
+        protocol.clone(),
+        from_peer,
+        options.clone(),
+        None,
+    ));
+    tokio::spawn(send_clipboard(
+        clipboard.clone(),
+        origin,
+        protocol.clone(),
+        to_peer,
+        options,
+        None,
+    ));
+
+    let received = ClipboardObject::Text("from the peer".into());
+    protocol
+        .write(1, 42, &received, &mut to_server)
+        .await
+        .unwrap();
+    timeout(Duration::from_secs(5), async {
+        while text(&clipboard).await.as_deref() != Some("from the peer") {
+            sleep(Duration::from_millis(10)).await;
+        }
+    })
+    .await
+    .expect("the clipboard got the text");
+    let echoed = timeout(Duration::from_millis(200), protocol.read(&mut from_server)).await;
+    assert!(echoed.is_err(), "the peer read {echoed:?}");
+
+    // What is copied here still goes out on the same connection
+    clipboard
+        .copy(Origin::Local, ClipboardObject::Text("local".into()))
+        .await
+        .unwrap();
+    let (_, _, sent) = protocol.read(&mut from_server).await.unwrap();
+    assert!(matches!(sent, ClipboardObject::Text(text) if text == "local"));
+}
+
+#[tokio::test]
+async fn wrong_key_is_rejected() {
+    let (server, client) = (session("right"), session("wrong"));
+    let (server_stream, client_stream) = duplex(64 * 1024);
+    let (accepted, connected) =
+        tokio::join!(server.accept(server_stream), client.connect(client_stream));
+    assert!(matches!(accepted, Err(ClipshareError::Auth(_))));
+    assert!(matches!(connected, Err(ClipshareError::Auth(_))));
+}
+
+#[tokio::test]
+async fn client_with_the_wrong_key_exchanges_no_clipboard() {
+    let (server, client) = (session("right"), session("wrong"));
+    client
+        .clipboard()
+        .copy(Origin::Local, ClipboardObject::Text("secret".into()))
+        .await
+        .unwrap();
+    let (server_stream, client_stream) = duplex(64 * 1024);
+    let (accepted, connected) =
+        tokio::join!(server.accept(server_stream), client.connect(client_stream));
+    assert!(accepted.is_err());
+    assert!(connected.is_err());
+
+    sleep(Duration::from_millis(50)).await;
+    assert!(server.clipboard().current().await.unwrap().is_none());
+    assert_eq!(text(client.clipboard()).await.as_deref(), Some("secret"));
+}
+
+#[tokio::test]
+async fn stalled_handshake_times_out() {
+    let options = SessionOptions {
+        handshake_timeout: Duration::from_millis(50),
+        ..SessionOptions::default()
+    };
+    let (server, client) = (
+        session("key").with_options(options.clone()),
+        session("key").with_options(options),
+    );
+    // The other ends are kept open, but never answer
+    let (client_stream, _silent_server) = duplex(64 * 1024);
+    let connected = timeout(Duration::from_secs(5), client.connect(client_stream))
+        .await
+        .expect("the handshake timeout reclaimed the connection");
+    assert!(matches!(connected, Err(ClipshareError::Handshake(_))));
+    let (server_stream, _silent_client) = duplex(64 * 1024);
+    let accepted = timeout(Duration::from_secs(5), server.accept(server_stream))
+        .await
+        .expect("the handshake timeout reclaimed the connection");
+    assert!(matches!(accepted, Err(ClipshareError::Handshake(_))));
+}
+
+#[tokio::test]
+async fn oversized_frame_is_refused() {
+    let (mut write, mut read) = duplex(1024);
+    write.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+    let result = Protocol::new(MAX_SIZE).read(&mut read).await;
+    assert!(matches!(result, Err(ClipshareError::TooLarge { .. })));
+}
+
+#[tokio::test]
+async fn oversized_length_prefixes_are_refused_before_reading_on() {
+    // Nothing follows the prefixes and the streams stay open, so reading the body or allocating
+    // for it would hang or abort instead of failing right away
+    let (mut write, mut read) = duplex(1024);
+    write.write_all(&u32::MAX.to_be_bytes()).await.unwrap();
+    let result = timeout(
+        Duration::from_secs(1),
+        Protocol::new(MAX_SIZE).read(&mut read),
+    )
+    .await
+    .expect("the frame was refused from its length");
+    assert!(
+        matches!(result, Err(ClipshareError::TooLarge { size, .. }) if size == u64::from(u32::MAX))
+    );
+
+    let (mut write, mut read) = duplex(1024);
+    write.write_all(&[1]).await.unwrap();
+    write.write_all(&u64::MAX.to_be_bytes()).await.unwrap();
+    let result = timeout(
+        Duration::from_secs(1),
+        codec::read_message(&mut read, MAX_SIZE, "Message"),
+    )
+    .await
+    .expect("the message was refused from its length");
+    assert!(matches!(
+        result,
+        Err(ClipshareError::TooLarge { size: u64::MAX, .. })
+    ));
+    drop(write);
+}
+
+#[tokio::test]
+async fn closing_between_frames_is_a_disconnect() {
+    let (write, mut read) = duplex(1024);
+    drop(write);
+    let result = Protocol::new(MAX_SIZE).read(&mut read).await;
+    assert!(matches!(result, Err(ClipshareError::Closed)));
+}
+
+#[tokio::test]
+async fn closing_partway_through_a_frame_is_a_protocol_error() {
+    let (mut write, mut read) = duplex(1024);
+    write.write_all(&100u32.to_be_bytes()).await.unwrap();
+    write.write_all(&[0; 10]).await.unwrap();
+    drop(write);
+    let result = Protocol::new(MAX_SIZE).read(&mut read).await;
+    assert!(matches!(result, Err(ClipshareError::Protocol(_))));
+}
+
+#[tokio::test]
+async fn stalling_partway_through_a_frame_times_out() {
+    let (mut write, mut read) = duplex(1024);
+    write.write_all(&100u32.to_be_bytes()).await.unwrap();
+    write.write_all(&[0; 10]).await.unwrap();
+    // Kept open without sending the rest
+    let protocol = Protocol::new(MAX_SIZE).heartbeat(Duration::from_millis(20));
+    let result = timeout(Duration::from_secs(5), protocol.read(&mut read))
+        .await
+        .expect("the stalled read gave up");
+    assert!(
+        matches!(result, Err(ClipshareError::Io(err)) if err.kind() == io::ErrorKind::TimedOut)
+    );
+    drop(write);
+}
+
+#[tokio::test]
+async fn text_that_is_not_utf8_is_skipped() {
+    let protocol = Protocol::new(MAX_SIZE);
+    let (mut frames, _) = protocol
+        .frames(1, 0, &ClipboardObject::Text("abcd".into()))
+        .await
+        .unwrap();
+    let mut invalid = frames.remove(0);
+    let end = invalid.len();
+    invalid[end - 4..].copy_from_slice(&[0xff, 0xfe, 0xfd, 0xfc]);
+    let (mut write, mut read) = duplex(1024);
+    write.write_all(&invalid).await.unwrap();
+    protocol
+        .write(2, 0, &ClipboardObject::Text("after".into()), &mut write)
+        .await
+        .unwrap();
+
+    // Dropped whole, the stream carrying on with the next object
+    let (seq, _, obj) = protocol.read(&mut read).await.unwrap();
+    assert_eq!(seq, 2);
+    assert!(matches!(obj, ClipboardObject::Text(text) if text == "after"));
+
+    // Unless asked to replace the invalid bytes
+    write.write_all(&invalid).await.unwrap();
+    let (seq, _, obj) = Protocol::new(MAX_SIZE)
+        .lossy()
+        .read(&mut read)
+        .await
+        .unwrap();
+    assert_eq!(seq, 1);
+    assert!(matches!(obj, ClipboardObject::Text(text) if text == "\u{fffd}".repeat(4)));
+}
+
+#[tokio::test]
+async fn image_not_matching_its_dimensions_is_refused() {
+    let protocol = Protocol::new(MAX_SIZE);
+    let (mut write, mut read) = duplex(1024);
+    let image = ImageData {
+        width: 2,
+        height: 2,
+        bytes: vec![0; 3].into(),
+    };
+    protocol
+        .write(1, 0, &ClipboardObject::Image(image), &mut write)
+        .await
+        .unwrap();
+    let result = protocol.read(&mut read).await;
+    assert!(matches!(result, Err(ClipshareError::Protocol(_))));
+}
+
+#[tokio::test]
+async fn received_files_going_by_the_same_name_are_all_kept() {
+    let clipboard = Clipboard::with_backend(MemoryClipboard::new()).with_files(MAX_SIZE);
+    let files = ["notes.txt", "notes.txt", "notes"].map(|name| File {
+        name: name.into(),
+        contents: name.into(),
+    });
+    clipboard
+        .copy(clipboard.peer(), ClipboardObject::Files(files.into()))
+        .await
+        .unwrap();
+    let Some(ClipboardObject::Files(files)) = clipboard.current().await.unwrap() else {
+        panic!("the clipboard holds the files");
+    };
+    let names: Vec<_> = files.iter().map(|file| file.name.as_str()).collect();
+    assert_eq!(names, ["notes.txt", "notes (2).txt", "notes"]);
+    // Neither of the first two overwrote the other
+    let contents: Vec<_> = files.iter().map(|file| file.contents.as_slice()).collect();
+    assert_eq!(contents, [&b"notes.txt"[..], b"notes.txt", b"notes"]);
+}
+
+#[tokio::test]
+async fn oversized_object_is_refused() {
+    let (mut write, mut read) = duplex(64 * 1024);
+    let obj = ClipboardObject::Text("x".repeat(2 * CHUNK_SIZE));
+    tokio::spawn(async move {
+        let _ = Protocol::new(MAX_SIZE).write(1, 0, &obj, &mut write).await;
+    });
+    let result = Protocol::new(CHUNK_SIZE as u64).read(&mut read).await;
+    assert!(matches!(result, Err(ClipshareError::TooLarge { .. })));
+}
+
+#[tokio::test]
+async fn only_objects_over_the_stream_threshold_are_streamed() {
+    let protocol = Protocol::new(MAX_SIZE).streaming_over(4 * CHUNK_SIZE);
+    let obj = ClipboardObject::Text("x".repeat(3 * CHUNK_SIZE));
+    assert_eq!(protocol.frames(1, 0, &obj).await.unwrap().0.len(), 1);
+    let obj = ClipboardObject::Text("x".repeat(5 * CHUNK_SIZE));
+    assert_eq!(protocol.frames(1, 0, &obj).await.unwrap().0.len(), 6);
+
+    // Sent in one frame, which a peer going by the same threshold reads
+    let (mut write, mut read) = duplex(64 * 1024);
+    let obj = ClipboardObject::Text("x".repeat(3 * CHUNK_SIZE));
+    tokio::spawn({
+        let protocol = protocol.clone();
+        async move { protocol.write(1, 0, &obj, &mut write).await }
+    });
+    let (_, _, obj) = protocol.read(&mut read).await.unwrap();
+    assert_eq!(obj.size(), 3 * CHUNK_SIZE);
+}
+
+#[tokio::test]
+async fn progress_follows_chunks_below_the_default_size() {
+    let server = session("key");
+    let clipboard = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    let protocol = Protocol::new(MAX_SIZE).streaming_over(1024);
+    let client = ClipshareSession::new(clipboard, "key", protocol);
+    let (server_stream, client_stream) = duplex(64 * 1024);
+    let (accepted, connected) =
+        tokio::join!(server.accept(server_stream), client.connect(client_stream));
+    let receiving = tokio::spawn(accepted.unwrap().recv_once());
+    let (events, mut reports) = mpsc::unbounded_channel();
+    let connected = connected.unwrap().with_events(events);
+    assert!(connected.protocol().chunk_size() < CHUNK_SIZE);
+
+    let text = ClipboardObject::Text("x".repeat(10 * 1024));
+    connected.send_once(&text).await.unwrap();
+    receiving.await.unwrap().unwrap();
+    let mut progress = Vec::new();
+    while let Ok(event) = reports.try_recv() {
+        if let SessionEvent::Sending { done, total } = event {
+            progress.push((done, total));
+        }
+    }
+    // One report per chunk, instead of a single one jumping to the end
+    assert!(progress.len() >= 10, "{progress:?}");
+    assert!(progress.windows(2).all(|pair| pair[0].0 < pair[1].0));
+    let &(done, total) = progress.last().unwrap();
+    assert_eq!(done, total);
+}
+
+#[tokio::test]
+async fn encrypted_connections_use_a_key_of_their_own() {
+    let encrypted = |key: &str| {
+        let clipboard = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+        let protocol = Protocol::new(MAX_SIZE).encrypted(Cipher::new(key));
+        ClipshareSession::new(clipboard, key, protocol)
+    };
+    let (server, client) = (encrypted("key"), encrypted("key"));
+    let (server_stream, client_stream) = duplex(64 * 1024);
+    let (accepted, connected) =
+        tokio::join!(server.accept(server_stream), client.connect(client_stream));
+    let (accepted, connected) = (accepted.unwrap(), connected.unwrap());
+
+    let obj = ClipboardObject::Text("secret".into());
+    let (mut to_peer, mut from_peer) = duplex(64 * 1024);
+    let (mut to_eavesdropper, mut from_eavesdropper) = duplex(64 * 1024);
+    for writer in [&mut to_peer, &mut to_eavesdropper] {
+        connected
+            .protocol()
+            .write(1, 0, &obj, writer)
+            .await
+            .unwrap();
+    }
+    let (_, _, obj) = accepted.protocol().read(&mut from_peer).await.unwrap();
+    assert!(matches!(obj, ClipboardObject::Text(text) if text == "secret"));
+    // Knowing the key isn't enough, the cipher comes from the key exchange of the connection
+    let result = Protocol::new(MAX_SIZE)
+        .encrypted(Cipher::new("key"))
+        .read(&mut from_eavesdropper)
+        .await;
+    assert!(matches!(result, Err(ClipshareError::Auth(_))));
+}
+
+#[tokio::test]
+async fn compressing_end_syncs_with_one_that_does_not() {
+    let clipboard = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    let server = ClipshareSession::new(clipboard, "key", Protocol::new(MAX_SIZE).compressed());
+    let client = session("key");
+    let (server_stream, client_stream) = duplex(64 * 1024);
+    let (accepted, connected) =
+        tokio::join!(server.accept(server_stream), client.connect(client_stream));
+    let (accepted, connected) = (accepted.unwrap(), connected.unwrap());
+    assert_eq!(accepted.protocol().flags() & protocol::COMPRESSED, 0);
+    assert_eq!(connected.protocol().flags() & protocol::COMPRESSED, 0);
+    tokio::spawn(accepted.sync());
+    tokio::spawn(connected.sync());
+
+    // Compressible, and large enough that it would be compressed
+    let large = "clipshare ".repeat(1000);
+    let both_ways = [
+        (&server, &client, format!("{large}from the server")),
+        (&client, &server, format!("{large}from the client")),
+    ];
+    for (from, to, copied) in both_ways {
+        from.clipboard()
+            .copy(Origin::Local, ClipboardObject::Text(copied.clone()))
+            .await
+            .unwrap();
+        timeout(Duration::from_secs(5), async {
+            while text(to.clipboard()).await.as_deref() != Some(copied.as_str()) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the text arrived intact");
+    }
+}
+
+#[tokio::test]
+async fn both_ends_adopt_the_smaller_max_size() {
+    let server = session("key");
+    let clipboard = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    let client = ClipshareSession::new(clipboard, "key", Protocol::new(1024));
+    let (server_stream, client_stream) = duplex(64 * 1024);
+    let (accepted, connected) =
+        tokio::join!(server.accept(server_stream), client.connect(client_stream));
+    assert_eq!(accepted.unwrap().protocol().max_size(), 1024);
+    assert_eq!(connected.unwrap().protocol().max_size(), 1024);
+}
+
+#[tokio::test]
+async fn reconnecting_device_closes_its_stale_connection() {
+    let server = session("key");
+    let client = session("key").with_device(7);
+    let mut connections = Vec::new();
+    for _ in 0..2 {
+        let (server_stream, client_stream) = duplex(64 * 1024);
+        let (accepted, connected) =
+            tokio::join!(server.accept(server_stream), client.connect(client_stream));
+        // The client end is kept open, as when its machine went away without closing it
+        connections.push((tokio::spawn(accepted.unwrap().sync()), connected.unwrap()));
+    }
+
+    let (stale, _) = connections.remove(0);
+    timeout(Duration::from_secs(5), stale)
+        .await
+        .expect("the stale connection was closed")
+        .unwrap()
+        .unwrap();
+    let (live, _) = &connections[0];
+    sleep(Duration::from_millis(50)).await;
+    assert!(!live.is_finished());
+}
+
+#[tokio::test]
+async fn clients_going_by_the_same_name_keep_their_connections() {
+    // Two processes on the same machine, each with an id of its own
+    let server = session("key");
+    let mut connections = Vec::new();
+    for client in [session("key"), session("key")] {
+        let (server_stream, client_stream) = duplex(64 * 1024);
+        let (accepted, connected) =
+            tokio::join!(server.accept(server_stream), client.connect(client_stream));
+        connections.push((tokio::spawn(accepted.unwrap().sync()), connected.unwrap()));
+    }
+
+    sleep(Duration::from_millis(50)).await;
+    assert!(connections.iter().all(|(sync, _)| !sync.is_finished()));
+}
+
+#[tokio::test]
+async fn change_made_here_is_dropped_when_it_comes_back() {
+    let clipboard = Clipboard::with_backend(MemoryClipboard::new());
+    clipboard
+        .copy(Origin::Local, ClipboardObject::Text("here".into()))
+        .await
+        .unwrap();
+    // Around a ring of peers, with a later sequence number than anything made since
+    clipboard
+        .receive(
+            clipboard.peer(),
+            u64::MAX,
+            clipboard.node(),
+            ClipboardObject::Text("came back".into()),
+        )
+        .await
+        .unwrap();
+    assert_eq!(text(&clipboard).await.as_deref(), Some("here"));
+}
+
+#[test]
+fn kinds_serialize_to_their_lowercase_name() {
+    let html = ClipboardObject::Html {
+        html: "<b>bold</b>".into(),
+        text: "bold".into(),
+    };
+    assert_eq!(html.kind(), ClipboardKind::Html);
+    assert_eq!(serde_json::to_string(&html.kind()).unwrap(), "\"html\"");
+    let kind: ClipboardKind = serde_json::from_str("\"primary\"").unwrap();
+    assert_eq!(kind.to_string(), "primary");
+}
+
+#[tokio::test]
+async fn clipboard_taken_over_is_written_again() {
+    // Once when it is first read, and once when the text is written
+    let backend = Shared::default();
+    backend.lost.store(2, Ordering::SeqCst);
+    let clipboard = Clipboard::with_backend(backend);
+    clipboard
+        .receive(
+            clipboard.peer(),
+            1,
+            0,
+            ClipboardObject::Text("taken back".into()),
+        )
+        .await
+        .unwrap();
+    match clipboard.current().await.unwrap() {
+        Some(ClipboardObject::Text(text)) => assert_eq!(text, "taken back"),
+        obj => panic!("expected the text, got {obj:?}"),
+    }
+}
+
+#[tokio::test]
+async fn identical_copies_write_the_backend_once() {
+    let backend = Shared::default();
+    let clipboard = Clipboard::with_backend(backend.clone());
+    for _ in 0..2 {
+        clipboard
+            .copy(Origin::Local, ClipboardObject::Text("same".into()))
+            .await
+            .unwrap();
+    }
+    assert_eq!(backend.writes.load(Ordering::SeqCst), 1);
+    assert_eq!(backend.text().as_deref(), Some("same"));
+}
+
+#[tokio::test]
+async fn sync_survives_a_clipboard_failing_for_a_while() {
+    let (sending, receiving) = (Shared::default(), Shared::default());
+    let sender = Arc::new(
+        Clipboard::with_backend(sending.clone()).with_poll_interval(Duration::from_millis(10)),
+    );
+    let receiver = Arc::new(Clipboard::with_backend(receiving.clone()));
+    let (write, read) = duplex(64 * 1024);
+    let protocol = Protocol::new(MAX_SIZE);
+    let options = SessionOptions::default();
+    let watching = tokio::spawn({
+        let sender = sender.clone();
+        async move { sender.watch().await }
+    });
+    let sending_task = tokio::spawn(send_clipboard(
+        sender.clone(),
+        sender.peer(),
+        protocol.clone(),
+        write,
+        options.clone(),
+        None,
+    ));
+    let receiving_task = tokio::spawn(recv_clipboard(
+        receiver.clone(),
+        receiver.peer(),
+        protocol,
+        read,
+        options,
+        None,
+    ));
+
+    for copied in ["first", "second"] {
+        // Both ends fail a few times, fewer than the writes of an object are tried
+        sending.failures.store(3, Ordering::SeqCst);
+        receiving.failures.store(2, Ordering::SeqCst);
+        sending.set_text(copied);
+        timeout(Duration::from_secs(5), async {
+            while receiving.text().as_deref() != Some(copied) {
+                sleep(Duration::from_millis(10)).await;
+            }
+        })
+        .await
+        .expect("the receiver got the text");
+        assert_eq!(sending.failures.load(Ordering::SeqCst), 0);
+        assert_eq!(receiving.failures.load(Ordering::SeqCst), 0);
+    }
+    assert!(!watching.is_finished());
+    assert!(!sending_task.is_finished());
+    assert!(!receiving_task.is_finished());
+}
+
+#[tokio::test]
+async fn identical_content_copied_again_is_sent_once() {
+    let backend = Shared::default();
+    let clipboard = Arc::new(
+        Clipboard::with_backend(backend.clone()).with_poll_interval(Duration::from_millis(10)),
+    );
+    let (write, mut read) = duplex(64 * 1024);
+    let protocol = Protocol::new(MAX_SIZE);
+    tokio::spawn({
+        let clipboard = clipboard.clone();
+        async move { clipboard.watch().await }
+    });
+    tokio::spawn(send_clipboard(
+        clipboard.clone(),
+        clipboard.peer(),
+        protocol.clone(),
+        write,
+        SessionOptions::default(),
+        None,
+    ));
+
+    backend.set_text("same");
+    let (_, _, sent) = protocol.read(&mut read).await.unwrap();
+    assert!(matches!(sent, ClipboardObject::Text(text) if text == "same"));
+    // Set again by the application, and copied again through clipshare
+    backend.set_text("same");
+    clipboard
+        .copy(Origin::Local, ClipboardObject::Text("same".into()))
+        .await
+        .unwrap();
+    let again = timeout(Duration::from_millis(200), protocol.read(&mut read)).await;
+    assert!(again.is_err(), "sent again: {again:?}");
+}
+
+#[tokio::test]
+async fn empty_text_is_not_sent() {
+    let clipboard = Arc::new(Clipboard::with_backend(MemoryClipboard::new()));
+    let (write, mut read) = duplex(64 * 1024);
+    let protocol = Protocol::new(MAX_SIZE);
+    tokio::spawn(send_clipboard(
+        clipboard.clone(),
+        clipboard.peer(),
+        protocol.clone(),
+        write,
+        SessionOptions::default(),
+        None,
+    ));
+
+    clipboard
+        .copy(Origin::Local, ClipboardObject::Text(String::new()))
+        .await
+        .unwrap();
+    let sent = timeout(Duration::from_millis(200), protocol.read(&mut read)).await;
+    assert!(sent.is_err(), "sent: {sent:?}");
+    // The next non-empty text is the first thing the peer gets
+    clipboard
+        .copy(Origin::Local, ClipboardObject::Text("after".into()))
+        .await
+        .unwrap();
+    let (_, _, sent) = protocol.read(&mut read).await.unwrap();
+    assert!(matches!(sent, ClipboardObject::Text(text) if text == "after"));
+}